@@ -18,7 +18,7 @@ pub async fn create_clients(db: &DatabaseConnection) -> Result<()> {
                 "https://pool.sjallabong.eu/auth/callback",
                 "http://localhost:8080/auth/callback",
             ],
-            vec!["openid", "profile", "pool"],
+            vec!["openid", "profile", "pool", "groups"],
             vec!["https://pool.sjallabong.eu", "http://localhost:8080"],
         ),
         (
@@ -28,7 +28,7 @@ pub async fn create_clients(db: &DatabaseConnection) -> Result<()> {
                 "https://sjallabong.eu/auth/callback",
                 "http://localhost:5173/auth/callback",
             ],
-            vec!["openid", "profile", "roles"],
+            vec!["openid", "profile", "roles", "groups"],
             vec!["https://sjallabong.eu", "http://localhost:5173"],
         ),
     ];
@@ -52,5 +52,10 @@ pub async fn create_clients(db: &DatabaseConnection) -> Result<()> {
         tracing::info!("Created client: {client_id}");
     }
 
+    // not just ours, a pairwise client without a sector would hand out subs that can change
+    for client in crate::client::Entity::find().all(db).await? {
+        client.check_sector().map_err(anyhow::Error::msg)?;
+    }
+
     Ok(())
 }
@@ -0,0 +1,93 @@
+use aes_gcm::{
+    Aes256Gcm, Key, Nonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+};
+use anyhow::{Result, anyhow};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use sha2::{Digest, Sha256};
+use std::sync::LazyLock;
+
+// the dev fallback is public, in production it would leave totp seeds and lookup keys unprotected
+fn secret() -> String {
+    match std::env::var("SECRET_ENCRYPTION_KEY") {
+        Ok(secret) => secret,
+        Err(_) if *crate::IS_PRODUCTION => panic!("SECRET_ENCRYPTION_KEY must be set in prod"),
+        Err(_) => "jkljkljkljkljkljkljkljkljkljkl".to_string(),
+    }
+}
+
+// read now, so a missing key stops the start rather than a login
+pub fn init() {
+    LazyLock::force(&CIPHER);
+    LazyLock::force(&LOOKUP_KEY);
+}
+
+// for secrets we have to read back (unlike passwords), e.g. totp seeds
+static CIPHER: LazyLock<Aes256Gcm> = LazyLock::new(|| {
+    let key = Sha256::digest(secret().as_bytes());
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+});
+
+// derived apart from the cipher's, so one never stands in for the other
+static LOOKUP_KEY: LazyLock<ring::hmac::Key> = LazyLock::new(|| {
+    let key = Sha256::digest(format!("lookup:{}", secret()).as_bytes());
+    ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &key)
+});
+
+const NONCE_LEN: usize = 12;
+
+// `context` is bound into the tag, so a ciphertext can't be moved to another row
+pub fn encrypt(plaintext: &[u8], context: &str) -> Result<String> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = CIPHER
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: context.as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow!("Encryption failed"))?;
+
+    let mut out = nonce.to_vec();
+    out.extend(ciphertext);
+    Ok(URL_SAFE_NO_PAD.encode(out))
+}
+
+pub fn decrypt(encoded: &str, context: &str) -> Result<Vec<u8>> {
+    let bytes = URL_SAFE_NO_PAD.decode(encoded)?;
+    if bytes.len() < NONCE_LEN {
+        return Err(anyhow!("Ciphertext too short"));
+    }
+    let (nonce, ciphertext) = bytes.split_at(NONCE_LEN);
+    CIPHER
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: context.as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow!("Decryption failed"))
+}
+
+// a keyed digest to find a hashed secret by, so checking one costs a single slow hash rather than one per
+// row. useless without the key, unlike a plain sha-256 of a short code
+pub fn lookup_key(value: &str, context: &str) -> String {
+    let tag = ring::hmac::sign(&LOOKUP_KEY, format!("{context}:{value}").as_bytes());
+    tag.as_ref().iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// hex encoded hmac-sha256 from someone we share `secret` with, compared in constant time
+pub fn verify_hmac(secret: &[u8], message: &[u8], signature: &str) -> bool {
+    // an odd length or a split character ends on a pair that doesn't parse
+    let Ok(tag) = (0..signature.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(signature.get(i..i + 2).unwrap_or("zz"), 16))
+        .collect::<Result<Vec<u8>, _>>()
+    else {
+        return false;
+    };
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret);
+    ring::hmac::verify(&key, message, &tag).is_ok()
+}
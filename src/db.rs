@@ -1,7 +1,9 @@
+use std::collections::HashMap;
 use std::time::Duration;
 
 use crate::IS_PRODUCTION;
 use anyhow::Result;
+use sea_orm::sea_query::{Index, Table};
 use sea_orm::*;
 
 pub async fn init_db() -> Result<DatabaseConnection> {
@@ -24,45 +26,274 @@ pub async fn init_db() -> Result<DatabaseConnection> {
     }
 
     let db = Database::connect(opt).await?;
+    setup(&db).await?;
+    Ok(db)
+}
+
+// brings the schema up to date and seeds the clients and roles
+pub async fn setup(db: &DatabaseConnection) -> Result<()> {
+    // access tokens used to be keyed by the whole jwt. they're short lived, so start that table over
+    if has_column(db, "access_tokens", "token").await {
+        db.execute_unprepared("DROP TABLE \"access_tokens\"").await?;
+        tracing::info!("Dropped legacy access_tokens table");
+    }
+
+    sync_table(db, crate::user::Entity).await?;
+    normalize_users(db).await?;
+    sync_table(db, crate::client::Entity).await?;
+    sync_table(db, crate::token::auth::Entity).await?;
+    sync_table(db, crate::token::access::Entity).await?;
+    sync_table(db, crate::token::refresh::Entity).await?;
+    sync_table(db, crate::token::verification::Entity).await?;
+    sync_table(db, crate::token::reset::Entity).await?;
+    sync_table(db, crate::token::mfa::Entity).await?;
+    sync_table(db, crate::token::webauthn::Entity).await?;
+    sync_table(db, crate::token::federation::Entity).await?;
+    sync_table(db, crate::subject::Entity).await?;
+    sync_table(db, crate::session::Entity).await?;
+    sync_table(db, crate::security_event::Entity).await?;
+    sync_table(db, crate::passkey::Entity).await?;
+    sync_table(db, crate::recovery_code::Entity).await?;
+    sync_table(db, crate::federated_identity::Entity).await?;
+    sync_table(db, crate::suspension::Entity).await?;
+    sync_table(db, crate::login_attempt::Entity).await?;
+    sync_table(db, crate::role::Entity).await?;
+    sync_table(db, crate::permission::Entity).await?;
+    sync_table(db, crate::user_role::Entity).await?;
+    sync_table(db, crate::group::Entity).await?;
+    sync_table(db, crate::group_member::Entity).await?;
+    sync_table(db, crate::group_request::Entity).await?;
+    sync_table(db, crate::membership::Entity).await?;
+    unique_membership_references(db).await?;
+    sync_table(db, crate::invite::Entity).await?;
+
+    crate::clients::create_clients(db).await?;
+    crate::roles::create_roles(db).await?;
+    migrate_role_flags(db).await?;
+    migrate_members(db).await?;
 
+    Ok(())
+}
+
+// hourly: accounts whose deletion grace period ran out, login history past retention, and memberships
+// that ran out or started
+pub async fn housekeeping(db: DatabaseConnection) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        match crate::user::Entity::purge_due(&db).await {
+            Ok(0) => {}
+            Ok(purged) => tracing::info!(purged, "Purged deleted accounts"),
+            Err(e) => tracing::error!("Purging deleted accounts failed: {e}"),
+        }
+        match crate::login_attempt::Entity::prune(&db).await {
+            Ok(0) => {}
+            Ok(pruned) => tracing::info!(pruned, "Pruned login history"),
+            Err(e) => tracing::error!("Pruning login history failed: {e}"),
+        }
+        match crate::membership::Entity::expire_due(&db).await {
+            Ok(0) => {}
+            Ok(changed) => tracing::info!(changed, "Synced member roles with memberships"),
+            Err(e) => tracing::error!("Expiring memberships failed: {e}"),
+        }
+    }
+}
+
+// creates the table if missing, and adds any columns that were added to the entity since.
+// new columns on existing tables need to be nullable or have a default
+async fn sync_table<E: EntityTrait>(db: &DatabaseConnection, entity: E) -> Result<()> {
     let backend = db.get_database_backend();
     let schema = Schema::new(backend);
-    let stmt = schema.create_table_from_entity(crate::user::Entity);
-    let sql = backend
-        .build(&stmt)
-        .to_string()
-        .replace("CREATE TABLE \"users\"", "CREATE TABLE IF NOT EXISTS \"users\"");
-    db.execute_unprepared(&sql).await?;
-
-    let stmt = schema.create_table_from_entity(crate::client::Entity);
-    let sql = backend
-        .build(&stmt)
-        .to_string()
-        .replace("CREATE TABLE \"clients\"", "CREATE TABLE IF NOT EXISTS \"clients\"");
-    db.execute_unprepared(&sql).await?;
-
-    let stmt = schema.create_table_from_entity(crate::token::auth::Entity);
-    let sql = backend.build(&stmt).to_string().replace(
-        "CREATE TABLE \"auth_codes\"",
-        "CREATE TABLE IF NOT EXISTS \"auth_codes\"",
-    );
-    db.execute_unprepared(&sql).await?;
-
-    let stmt = schema.create_table_from_entity(crate::token::access::Entity);
-    let sql = backend.build(&stmt).to_string().replace(
-        "CREATE TABLE \"access_tokens\"",
-        "CREATE TABLE IF NOT EXISTS \"access_tokens\"",
-    );
-    db.execute_unprepared(&sql).await?;
-
-    let stmt = schema.create_table_from_entity(crate::token::refresh::Entity);
-    let sql = backend.build(&stmt).to_string().replace(
-        "CREATE TABLE \"refresh_tokens\"",
-        "CREATE TABLE IF NOT EXISTS \"refresh_tokens\"",
-    );
-    db.execute_unprepared(&sql).await?;
-
-    crate::clients::create_clients(&db).await?;
 
-    Ok(db)
+    let mut stmt = schema.create_table_from_entity(entity);
+    db.execute(backend.build(stmt.if_not_exists())).await?;
+
+    for column in E::Column::iter() {
+        if has_column(db, entity.table_name(), column.as_str()).await {
+            continue;
+        }
+
+        let stmt = Table::alter()
+            .table(entity)
+            .add_column(schema.get_column_def::<E>(column))
+            .to_owned();
+        db.execute(backend.build(&stmt)).await?;
+        tracing::info!("Added column {}.{}", entity.table_name(), column.as_str());
+    }
+
+    for mut stmt in schema.create_index_from_entity(entity) {
+        db.execute(backend.build(stmt.if_not_exists())).await?;
+    }
+
+    Ok(())
+}
+
+// fills in the lookup keys for accounts from before they existed, oldest first. when two accounts
+// normalize to the same name the older keeps it, the other only matches exactly until it's renamed
+async fn normalize_users(db: &DatabaseConnection) -> Result<()> {
+    use crate::user::{Column, Entity, normalize};
+
+    let pending = Entity::find()
+        .filter(
+            Condition::any()
+                .add(Column::UsernameNormalized.is_null())
+                .add(Column::EmailNormalized.is_null()),
+        )
+        .count(db)
+        .await?;
+    if pending > 0 {
+        let users = Entity::find().order_by_asc(Column::CreatedAt).all(db).await?;
+        let mut usernames: HashMap<String, String> = HashMap::new();
+        let mut emails: HashMap<String, String> = HashMap::new();
+        for user in &users {
+            if let Some(key) = &user.username_normalized {
+                usernames.insert(key.clone(), user.id.clone());
+            }
+            if let Some(key) = &user.email_normalized {
+                emails.insert(key.clone(), user.id.clone());
+            }
+        }
+
+        for user in users {
+            let mut update: crate::user::ActiveModel = user.clone().into();
+            let mut changed = false;
+
+            if user.username_normalized.is_none() {
+                let key = normalize(&user.username);
+                match usernames.get(&key) {
+                    Some(other) => {
+                        tracing::warn!(user_id = %user.id, %other, username = %user.username, "Username collides")
+                    }
+                    None => {
+                        usernames.insert(key.clone(), user.id.clone());
+                        update.username_normalized = Set(Some(key));
+                        changed = true;
+                    }
+                }
+            }
+            if user.email_normalized.is_none() {
+                let key = normalize(&user.email);
+                match emails.get(&key) {
+                    Some(other) => tracing::warn!(user_id = %user.id, %other, email = %user.email, "Email collides"),
+                    None => {
+                        emails.insert(key.clone(), user.id.clone());
+                        update.email_normalized = Set(Some(key));
+                        changed = true;
+                    }
+                }
+            }
+
+            if changed {
+                update.update(db).await?;
+            }
+        }
+    }
+
+    // unique indexes rather than column constraints, sqlite can't add those to an existing table
+    let backend = db.get_database_backend();
+    for column in [Column::UsernameNormalized, Column::EmailNormalized] {
+        let stmt = Index::create()
+            .if_not_exists()
+            .name(format!("idx-users-{}-unique", column.as_str()))
+            .table(Entity)
+            .col(column)
+            .unique()
+            .to_owned();
+        db.execute(backend.build(&stmt)).await?;
+    }
+
+    Ok(())
+}
+
+// roles used to be is_admin, is_moderator and is_member on the user. they become global assignments of
+// the role by the same name, then the columns go so nothing reads them by mistake
+async fn migrate_role_flags(db: &DatabaseConnection) -> Result<()> {
+    const FLAGS: [(&str, &str); 3] = [
+        ("is_admin", "admin"),
+        ("is_moderator", "moderator"),
+        ("is_member", "member"),
+    ];
+
+    let backend = db.get_database_backend();
+    for (column, role) in FLAGS {
+        if !has_column(db, "users", column).await {
+            continue;
+        }
+
+        let rows = db
+            .query_all(Statement::from_string(
+                backend,
+                format!("SELECT \"id\" FROM \"users\" WHERE \"{column}\" = TRUE"),
+            ))
+            .await?;
+        let txn = db.begin().await?;
+        for row in &rows {
+            let user_id: String = row.try_get("", "id")?;
+            crate::user_role::Entity::assign(&user_id, role, None, None, &txn).await?;
+        }
+        txn.execute_unprepared(&format!("ALTER TABLE \"users\" DROP COLUMN \"{column}\""))
+            .await?;
+        txn.commit().await?;
+        tracing::info!(users = rows.len(), "Migrated users.{column} to the {role} role");
+    }
+
+    Ok(())
+}
+
+// a payment or promo code counts once, even when its webhook retries race. rows without a reference
+// don't clash, null never equals null
+async fn unique_membership_references(db: &DatabaseConnection) -> Result<()> {
+    use crate::membership::{Column, Entity};
+
+    let backend = db.get_database_backend();
+    let stmt = Index::create()
+        .if_not_exists()
+        .name("idx-memberships-source-reference-unique")
+        .table(Entity)
+        .col(Column::Source)
+        .col(Column::Reference)
+        .unique()
+        .to_owned();
+    db.execute(backend.build(&stmt)).await?;
+    Ok(())
+}
+
+// the member role now follows memberships, so members from before them get one that doesn't run out
+async fn migrate_members(db: &DatabaseConnection) -> Result<()> {
+    use crate::membership;
+
+    let members = crate::user_role::Entity::find()
+        .filter(crate::user_role::Column::Role.eq(membership::MEMBER_ROLE))
+        .filter(crate::user_role::Column::ClientId.is_null())
+        .all(db)
+        .await?;
+    for member in members {
+        let has_any = membership::Entity::find()
+            .filter(membership::Column::UserId.eq(&member.user_id))
+            .one(db)
+            .await?
+            .is_some();
+        if has_any {
+            continue;
+        }
+        membership::Entity::grant(
+            &member.user_id,
+            membership::DEFAULT_TIER,
+            None,
+            membership::MANUAL,
+            None,
+            None,
+            db,
+        )
+        .await?;
+        tracing::info!(user_id = %member.user_id, "Gave a membership to an existing member");
+    }
+
+    Ok(())
+}
+
+async fn has_column(db: &DatabaseConnection, table: &str, column: &str) -> bool {
+    // qualified, sqlite reads an unknown "column" as a string literal
+    let probe = format!("SELECT \"{table}\".\"{column}\" FROM \"{table}\" LIMIT 1");
+    db.execute_unprepared(&probe).await.is_ok()
 }
@@ -1,6 +1,21 @@
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
 use chrono::{DateTime, Utc};
 use sea_orm::*;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::LazyLock;
+
+// with the public dev fallback anyone knowing a user id could work out their pairwise subs
+static PAIRWISE_SALT: LazyLock<String> = LazyLock::new(|| match std::env::var("PAIRWISE_SALT") {
+    Ok(salt) => salt,
+    Err(_) if *crate::IS_PRODUCTION => panic!("PAIRWISE_SALT must be set in prod"),
+    Err(_) => "fdsafdsafdsafdsafdsafdsa".to_string(),
+});
+
+// read now, so a missing salt stops the start rather than a login
+pub fn init() {
+    LazyLock::force(&PAIRWISE_SALT);
+}
 
 #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "clients")]
@@ -11,6 +26,32 @@ pub struct Model {
     pub redirect_uris: String,
     pub authorized_origins: String,
     pub allowed_scopes: String,
+
+    // token lifetimes in seconds, None falls back to the global defaults in `lifetime`
+    pub access_token_lifetime: Option<i64>,
+    pub id_token_lifetime: Option<i64>,
+    pub refresh_token_idle_lifetime: Option<i64>,
+    pub refresh_token_absolute_lifetime: Option<i64>,
+    pub auth_code_lifetime: Option<i64>,
+
+    // "public" gets the user id as sub, "pairwise" a per sector hash of it
+    #[sea_orm(default_value = "public")]
+    pub subject_type: String,
+    // host the pairwise sub is derived from. may be left out when every redirect uri is on one host
+    pub sector_identifier: Option<String>,
+
+    // openid front-channel logout, framed on our logout page with iss and sid
+    pub frontchannel_logout_uri: Option<String>,
+
+    // confidential clients have a secret (argon2, like passwords) and must authenticate
+    pub client_secret_hash: Option<String>,
+    // whether revoking an access token also revokes the refresh token family it came from
+    #[sea_orm(default_value = true)]
+    pub revoke_refresh_with_access: bool,
+    // users with an unverified email get a verification link instead of a code
+    #[sea_orm(default_value = false)]
+    pub require_verified_email: bool,
+
     pub created_at: DateTime<Utc>,
 }
 
@@ -21,6 +62,9 @@ impl ActiveModelBehavior for ActiveModel {
     fn new() -> Self {
         Self {
             created_at: Set(chrono::Utc::now()),
+            subject_type: Set("public".to_string()),
+            revoke_refresh_with_access: Set(true),
+            require_verified_email: Set(false),
             ..ActiveModelTrait::default()
         }
     }
@@ -31,7 +75,110 @@ impl Model {
         serde_json::from_str(&self.allowed_scopes)
     }
 
+    pub fn lifetimes(&self) -> crate::lifetime::TokenLifetimes {
+        crate::lifetime::TokenLifetimes::for_client(self)
+    }
+
     pub fn get_redirect_uris(&self) -> Result<Vec<String>, serde_json::Error> {
         serde_json::from_str(&self.redirect_uris)
     }
+
+    pub fn is_pairwise(&self) -> bool {
+        self.subject_type == "pairwise"
+    }
+
+    // oidc core 8.1: redirect uris on several hosts need a sector_identifier, or the subs would change with
+    // whichever uri happens to come first
+    pub fn check_sector(&self) -> Result<(), String> {
+        if !self.is_pairwise() || self.sector_identifier.is_some() {
+            return Ok(());
+        }
+
+        let uris = self.get_redirect_uris().map_err(|e| e.to_string())?;
+        let mut hosts = uris.iter().map(|uri| {
+            url::Url::parse(uri)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+        });
+        let first = hosts.next().flatten();
+        if hosts.any(|host| host != first) {
+            return Err(format!(
+                "{} is pairwise with redirect uris on different hosts, it needs a sector_identifier",
+                self.client_id
+            ));
+        }
+        Ok(())
+    }
+
+    // clients on the same host share a sector, and so see the same pairwise subs. see `check_sector`
+    pub fn sector(&self) -> String {
+        if let Some(sector) = &self.sector_identifier {
+            return sector.clone();
+        }
+
+        self.get_redirect_uris()
+            .ok()
+            .and_then(|uris| uris.into_iter().next())
+            .and_then(|uri| url::Url::parse(&uri).ok())
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_else(|| self.client_id.clone())
+    }
+
+    // the `sub` this client knows the user by
+    pub fn subject_for(&self, user_id: &str) -> String {
+        if !self.is_pairwise() {
+            return user_id.to_string();
+        }
+
+        // oidc core 8.1
+        let mut hasher = Sha256::new();
+        hasher.update(self.sector().as_bytes());
+        hasher.update(user_id.as_bytes());
+        hasher.update(PAIRWISE_SALT.as_bytes());
+        URL_SAFE_NO_PAD.encode(hasher.finalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(redirect_uris: &[&str], sector_identifier: Option<&str>) -> Model {
+        Model {
+            client_id: "test".to_string(),
+            name: "Test".to_string(),
+            redirect_uris: serde_json::to_string(redirect_uris).unwrap(),
+            authorized_origins: "[]".to_string(),
+            allowed_scopes: "[]".to_string(),
+            access_token_lifetime: None,
+            id_token_lifetime: None,
+            refresh_token_idle_lifetime: None,
+            refresh_token_absolute_lifetime: None,
+            auth_code_lifetime: None,
+            subject_type: "pairwise".to_string(),
+            sector_identifier: sector_identifier.map(str::to_string),
+            frontchannel_logout_uri: None,
+            client_secret_hash: None,
+            revoke_refresh_with_access: true,
+            require_verified_email: false,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn one_host_is_its_own_sector() {
+        let client = client(&["https://a.example/callback", "https://a.example/other"], None);
+        assert!(client.check_sector().is_ok());
+        assert_eq!(client.sector(), "a.example");
+    }
+
+    #[test]
+    fn several_hosts_need_a_sector_identifier() {
+        let uris = ["https://a.example/callback", "https://b.example/callback"];
+        assert!(client(&uris, None).check_sector().is_err());
+
+        let client = client(&uris, Some("example"));
+        assert!(client.check_sector().is_ok());
+        assert_eq!(client.sector(), "example");
+    }
 }
@@ -0,0 +1,84 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// an account at an upstream provider that signs in as one of our users
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "federated_identities")]
+pub struct Model {
+    // provider id from the config
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub provider: String,
+    // their `sub`, stable unlike the email
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub subject: String,
+    #[sea_orm(indexed)]
+    pub user_id: String,
+    // as the provider last told us, for display
+    pub email: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_login_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            created_at: Set(Utc::now()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+impl Entity {
+    pub async fn for_user(user_id: &str, db: &impl ConnectionTrait) -> Result<Vec<Model>, DbErr> {
+        Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .order_by_asc(Column::CreatedAt)
+            .all(db)
+            .await
+    }
+
+    pub async fn link(
+        provider: &str,
+        subject: &str,
+        user_id: &str,
+        email: Option<&str>,
+        db: &impl ConnectionTrait,
+    ) -> Result<Model, DbErr> {
+        let model = ActiveModel {
+            provider: Set(provider.to_string()),
+            subject: Set(subject.to_string()),
+            user_id: Set(user_id.to_string()),
+            email: Set(email.map(str::to_string)),
+            last_login_at: Set(Some(Utc::now())),
+            ..Default::default()
+        };
+        model.insert(db).await
+    }
+
+    pub async fn used(identity: Model, email: Option<&str>, db: &impl ConnectionTrait) -> Result<Model, DbErr> {
+        let mut identity: ActiveModel = identity.into();
+        identity.email = Set(email.map(str::to_string));
+        identity.last_login_at = Set(Some(Utc::now()));
+        identity.update(db).await
+    }
+
+    // only the owner's, false if it wasn't theirs or is already gone
+    pub async fn unlink(
+        provider: &str,
+        subject: &str,
+        user_id: &str,
+        db: &impl ConnectionTrait,
+    ) -> Result<bool, DbErr> {
+        let deleted = Self::delete_many()
+            .filter(Column::Provider.eq(provider))
+            .filter(Column::Subject.eq(subject))
+            .filter(Column::UserId.eq(user_id))
+            .exec(db)
+            .await?;
+        Ok(deleted.rows_affected == 1)
+    }
+}
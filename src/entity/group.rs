@@ -0,0 +1,45 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// a pool league, a chat community. who's in it, and as what, is in `group_member`
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "groups")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            id: Set(uuid::Uuid::new_v4().to_string()),
+            created_at: Set(Utc::now()),
+            updated_at: Set(Utc::now()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+impl Entity {
+    // with everyone in it and everything pending
+    pub async fn remove(group_id: &str, db: &impl ConnectionTrait) -> Result<(), DbErr> {
+        crate::group_member::Entity::delete_many()
+            .filter(crate::group_member::Column::GroupId.eq(group_id))
+            .exec(db)
+            .await?;
+        crate::group_request::Entity::delete_many()
+            .filter(crate::group_request::Column::GroupId.eq(group_id))
+            .exec(db)
+            .await?;
+        Self::delete_by_id(group_id).exec(db).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// exactly one per group. can do anything to it, including delete it
+pub const OWNER: &str = "owner";
+// manages members, but not the owner or other admins
+pub const ADMIN: &str = "admin";
+pub const MEMBER: &str = "member";
+
+pub const ROLES: &[&str] = &[OWNER, ADMIN, MEMBER];
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "group_members")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    #[sea_orm(indexed)]
+    pub group_id: String,
+    #[sea_orm(indexed)]
+    pub user_id: String,
+    pub role: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            id: Set(uuid::Uuid::new_v4().to_string()),
+            created_at: Set(Utc::now()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+// one group a user is in, as it goes into tokens and userinfo under the groups scope
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Membership {
+    pub id: String,
+    pub name: String,
+    pub role: String,
+}
+
+impl Entity {
+    pub async fn find_member(group_id: &str, user_id: &str, db: &impl ConnectionTrait) -> Result<Option<Model>, DbErr> {
+        Self::find()
+            .filter(Column::GroupId.eq(group_id))
+            .filter(Column::UserId.eq(user_id))
+            .one(db)
+            .await
+    }
+
+    // oldest first, so the owner (who made it) leads
+    pub async fn for_group(group_id: &str, db: &impl ConnectionTrait) -> Result<Vec<Model>, DbErr> {
+        Self::find()
+            .filter(Column::GroupId.eq(group_id))
+            .order_by_asc(Column::CreatedAt)
+            .all(db)
+            .await
+    }
+
+    pub async fn memberships(user_id: &str, db: &impl ConnectionTrait) -> Result<Vec<Membership>, DbErr> {
+        let members = Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .order_by_asc(Column::CreatedAt)
+            .all(db)
+            .await?;
+        if members.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let groups = crate::group::Entity::find()
+            .filter(crate::group::Column::Id.is_in(members.iter().map(|m| m.group_id.clone())))
+            .all(db)
+            .await?;
+        Ok(members
+            .into_iter()
+            .filter_map(|member| {
+                let group = groups.iter().find(|g| g.id == member.group_id)?;
+                Some(Membership {
+                    id: group.id.clone(),
+                    name: group.name.clone(),
+                    role: member.role,
+                })
+            })
+            .collect())
+    }
+
+    pub async fn add(group_id: &str, user_id: &str, role: &str, db: &impl ConnectionTrait) -> Result<Model, DbErr> {
+        let model = ActiveModel {
+            group_id: Set(group_id.to_string()),
+            user_id: Set(user_id.to_string()),
+            role: Set(role.to_string()),
+            ..Default::default()
+        };
+        model.insert(db).await
+    }
+
+    pub async fn set_role(member: Model, role: &str, db: &impl ConnectionTrait) -> Result<Model, DbErr> {
+        let mut update: ActiveModel = member.into();
+        update.role = Set(role.to_string());
+        update.update(db).await
+    }
+
+    // takes the user out of every group, for when the account goes. the groups they owned pass to the
+    // longest standing admin, or member, and go away with them if nobody's left
+    pub async fn remove_user(user_id: &str, db: &impl ConnectionTrait) -> Result<(), DbErr> {
+        let owned = Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .filter(Column::Role.eq(OWNER))
+            .all(db)
+            .await?;
+        for owner in owned {
+            let heir = Self::for_group(&owner.group_id, db)
+                .await?
+                .into_iter()
+                .filter(|member| member.user_id != user_id)
+                .min_by_key(|member| (member.role != ADMIN, member.created_at));
+            match heir {
+                Some(heir) => {
+                    Self::set_role(heir, OWNER, db).await?;
+                }
+                None => crate::group::Entity::remove(&owner.group_id, db).await?,
+            }
+        }
+
+        Self::delete_many().filter(Column::UserId.eq(user_id)).exec(db).await?;
+        crate::group_request::Entity::delete_many()
+            .filter(crate::group_request::Column::UserId.eq(user_id))
+            .exec(db)
+            .await?;
+        Ok(())
+    }
+}
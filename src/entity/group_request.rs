@@ -0,0 +1,92 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// the group asked the user, the user has to accept
+pub const INVITE: &str = "invite";
+// the user asked the group, its owner or an admin has to approve
+pub const JOIN: &str = "join";
+
+// someone waiting to get into a group. gone once accepted or declined
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "group_requests")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    #[sea_orm(indexed)]
+    pub group_id: String,
+    #[sea_orm(indexed)]
+    #[serde(skip_serializing)]
+    pub user_id: String,
+    pub kind: String,
+    // whoever sent the invite, the user themselves for a join request
+    #[serde(skip_serializing)]
+    pub created_by: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            id: Set(uuid::Uuid::new_v4().to_string()),
+            created_at: Set(Utc::now()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+impl Entity {
+    // there's at most one per user and group, an invite and a join request meeting make a member
+    pub async fn pending(group_id: &str, user_id: &str, db: &impl ConnectionTrait) -> Result<Option<Model>, DbErr> {
+        Self::find()
+            .filter(Column::GroupId.eq(group_id))
+            .filter(Column::UserId.eq(user_id))
+            .one(db)
+            .await
+    }
+
+    pub async fn for_group(group_id: &str, db: &impl ConnectionTrait) -> Result<Vec<Model>, DbErr> {
+        Self::find()
+            .filter(Column::GroupId.eq(group_id))
+            .order_by_asc(Column::CreatedAt)
+            .all(db)
+            .await
+    }
+
+    pub async fn for_user(user_id: &str, db: &impl ConnectionTrait) -> Result<Vec<Model>, DbErr> {
+        Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .order_by_asc(Column::CreatedAt)
+            .all(db)
+            .await
+    }
+
+    pub async fn create(
+        group_id: &str,
+        user_id: &str,
+        kind: &str,
+        created_by: &str,
+        db: &impl ConnectionTrait,
+    ) -> Result<Model, DbErr> {
+        let model = ActiveModel {
+            group_id: Set(group_id.to_string()),
+            user_id: Set(user_id.to_string()),
+            kind: Set(kind.to_string()),
+            created_by: Set(created_by.to_string()),
+            ..Default::default()
+        };
+        model.insert(db).await
+    }
+
+    pub async fn resolve(group_id: &str, user_id: &str, db: &impl ConnectionTrait) -> Result<bool, DbErr> {
+        let result = Self::delete_many()
+            .filter(Column::GroupId.eq(group_id))
+            .filter(Column::UserId.eq(user_id))
+            .exec(db)
+            .await?;
+        Ok(result.rows_affected > 0)
+    }
+}
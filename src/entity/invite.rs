@@ -0,0 +1,103 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// a code that lets someone register while registration is invite only, see `handler::register::Mode`
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "invites")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub code: String,
+    #[sea_orm(indexed)]
+    #[serde(skip_serializing)]
+    pub created_by: String,
+    // None for any number
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub expires_at: Option<DateTime<Utc>>,
+    // global roles everyone registering with it gets, a json array
+    pub roles: Option<String>,
+    // only good when registering through this client, say the closed beta's game
+    pub client_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            code: Set(crate::util::generate_random_string(12)),
+            uses: Set(0),
+            created_at: Set(Utc::now()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+impl Model {
+    pub fn roles(&self) -> Vec<String> {
+        self.roles
+            .as_deref()
+            .and_then(|roles| serde_json::from_str(roles).ok())
+            .unwrap_or_default()
+    }
+
+    // why it can't be used to register through `client_id`, if it can't. shown on the form
+    pub fn problem(&self, client_id: &str) -> Option<&'static str> {
+        if self.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+            Some("This invite has expired")
+        } else if self.max_uses.is_some_and(|max_uses| self.uses >= max_uses) {
+            Some("This invite has been used up")
+        } else if self.client_id.as_deref().is_some_and(|only| only != client_id) {
+            Some("This invite is for another app")
+        } else {
+            None
+        }
+    }
+}
+
+impl Entity {
+    pub async fn for_creator(user_id: &str, db: &impl ConnectionTrait) -> Result<Vec<Model>, DbErr> {
+        Self::find()
+            .filter(Column::CreatedBy.eq(user_id))
+            .order_by_desc(Column::CreatedAt)
+            .all(db)
+            .await
+    }
+
+    // seats the user handed out lately: what's still open on live invites, and what was used on the rest
+    pub async fn seats_given(user_id: &str, since: DateTime<Utc>, db: &impl ConnectionTrait) -> Result<i64, DbErr> {
+        let invites = Self::find()
+            .filter(Column::CreatedBy.eq(user_id))
+            .filter(Column::CreatedAt.gt(since))
+            .all(db)
+            .await?;
+        Ok(invites
+            .iter()
+            .map(|invite| {
+                let open = invite.expires_at.is_none_or(|expires_at| expires_at > Utc::now());
+                match invite.max_uses {
+                    Some(max_uses) if open => i64::from(max_uses.max(invite.uses)),
+                    _ => i64::from(invite.uses),
+                }
+            })
+            .sum())
+    }
+
+    // takes one use, conditionally so two registrations can't share the last one. false if none was left
+    pub async fn redeem(code: &str, db: &impl ConnectionTrait) -> Result<bool, DbErr> {
+        let result = Self::update_many()
+            .col_expr(Column::Uses, sea_query::Expr::col(Column::Uses).add(1))
+            .filter(Column::Code.eq(code))
+            .filter(
+                Condition::any()
+                    .add(Column::MaxUses.is_null())
+                    .add(sea_query::Expr::col(Column::Uses).lt(sea_query::Expr::col(Column::MaxUses))),
+            )
+            .exec(db)
+            .await?;
+        Ok(result.rows_affected == 1)
+    }
+}
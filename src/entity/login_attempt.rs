@@ -0,0 +1,114 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// why an attempt failed
+pub const WRONG_PASSWORD: &str = "wrong_password";
+pub const WRONG_CODE: &str = "wrong_code";
+pub const WRONG_PASSKEY: &str = "wrong_passkey";
+pub const THROTTLED: &str = "throttled";
+pub const RESTRICTED: &str = "restricted";
+
+// every login, good or bad, for the user's "recent activity" and for admins. pruned after
+// `lifetime::login_history`
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "login_attempts")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    // None when the login didn't match anyone. kept out of api responses, pairwise clients get the sub
+    #[sea_orm(indexed)]
+    #[serde(skip_serializing)]
+    pub user_id: Option<String>,
+    // as typed, for the ones that didn't match
+    pub login: Option<String>,
+    pub success: bool,
+    pub failure: Option<String>,
+    // json array, what was proven so far, see `token::parse_amr`
+    pub amr: Option<String>,
+    pub client_id: Option<String>,
+    // indexed for `pow`, which looks at an ip's recent failures
+    #[sea_orm(indexed)]
+    pub ip: Option<String>,
+    pub country: Option<String>,
+    pub user_agent: Option<String>,
+    #[sea_orm(indexed)]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            id: Set(uuid::Uuid::new_v4().to_string()),
+            created_at: Set(Utc::now()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+impl Model {
+    pub fn get_amr(&self) -> Vec<String> {
+        self.amr
+            .as_deref()
+            .and_then(|amr| serde_json::from_str(amr).ok())
+            .unwrap_or_default()
+    }
+}
+
+impl Entity {
+    // newest first, and whether there's more after
+    pub async fn page(
+        user_id: &str,
+        page: u64,
+        per_page: u64,
+        db: &impl ConnectionTrait,
+    ) -> Result<(Vec<Model>, bool), DbErr> {
+        // pages come straight from the query string, one past anything the database can skip is just empty
+        let Some(offset) = page
+            .checked_mul(per_page)
+            .filter(|offset| i64::try_from(*offset).is_ok())
+        else {
+            return Ok((Vec::new(), false));
+        };
+        let mut attempts = Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .order_by_desc(Column::CreatedAt)
+            .offset(offset)
+            .limit(per_page + 1)
+            .all(db)
+            .await?;
+        let more = attempts.len() as u64 > per_page;
+        attempts.truncate(per_page as usize);
+        Ok((attempts, more))
+    }
+
+    pub async fn prune(db: &impl ConnectionTrait) -> Result<u64, DbErr> {
+        let result = Self::delete_many()
+            .filter(Column::CreatedAt.lt(Utc::now() - crate::lifetime::login_history()))
+            .exec(db)
+            .await?;
+        Ok(result.rows_affected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pages_past_the_end_are_empty() {
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        let backend = db.get_database_backend();
+        db.execute(backend.build(&Schema::new(backend).create_table_from_entity(Entity)))
+            .await
+            .unwrap();
+
+        for page in [1, u64::MAX / 20, u64::MAX] {
+            let (attempts, more) = Entity::page("user", page, 20, &db).await.unwrap();
+            assert!(attempts.is_empty() && !more);
+        }
+    }
+}
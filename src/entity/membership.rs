@@ -0,0 +1,240 @@
+use chrono::{DateTime, Duration, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// how it was granted
+pub const MANUAL: &str = "manual";
+pub const PROMO: &str = "promo";
+pub const PAYMENT: &str = "payment";
+
+pub const SOURCES: &[&str] = &[MANUAL, PROMO, PAYMENT];
+
+pub const DEFAULT_TIER: &str = "member";
+// the role members hold while a membership runs. it follows the memberships, see `sync_role`
+pub const MEMBER_ROLE: &str = "member";
+
+// one stretch of membership. extending adds another starting where the last ends, so each payment is its own row
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "memberships")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    #[sea_orm(indexed)]
+    #[serde(skip_serializing)]
+    pub user_id: String,
+    pub tier: String,
+    pub source: String,
+    // the promo code or the payment's id. a payment is only counted once, unique with the source (see
+    // `db::unique_membership_references`)
+    pub reference: Option<String>,
+    pub starts_at: DateTime<Utc>,
+    // None for one that doesn't run out
+    pub expires_at: Option<DateTime<Utc>>,
+    // None for the webhook and ones carried over from the old flag
+    #[serde(skip_serializing)]
+    pub granted_by: Option<String>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            id: Set(uuid::Uuid::new_v4().to_string()),
+            created_at: Set(Utc::now()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+// what the user is right now, as it goes into tokens
+#[derive(Clone, Debug, Serialize)]
+pub struct Standing {
+    pub tier: String,
+    // when the run of back to back stretches in this tier ends, None if never
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+// running now or starting later, and not revoked
+fn live() -> Condition {
+    Condition::all()
+        .add(
+            Condition::any()
+                .add(Column::ExpiresAt.is_null())
+                .add(Column::ExpiresAt.gt(Utc::now())),
+        )
+        .add(Column::RevokedAt.is_null())
+}
+
+// where a tier's stretches run out, None if one never does
+fn chain_end<'a>(memberships: impl Iterator<Item = &'a Model>) -> Option<Option<DateTime<Utc>>> {
+    memberships.fold(None, |end, membership| match (end, membership.expires_at) {
+        (Some(None), _) | (_, None) => Some(None),
+        (Some(Some(end)), Some(expires_at)) => Some(Some(end.max(expires_at))),
+        (None, Some(expires_at)) => Some(Some(expires_at)),
+    })
+}
+
+impl Entity {
+    // newest first
+    pub async fn for_user(user_id: &str, db: &impl ConnectionTrait) -> Result<Vec<Model>, DbErr> {
+        Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .order_by_desc(Column::StartsAt)
+            .all(db)
+            .await
+    }
+
+    async fn live_for(user_id: &str, db: &impl ConnectionTrait) -> Result<Vec<Model>, DbErr> {
+        Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .filter(live())
+            .order_by_asc(Column::StartsAt)
+            .all(db)
+            .await
+    }
+
+    // the latest started one sets the tier
+    pub async fn standing(user_id: &str, db: &impl ConnectionTrait) -> Result<Option<Standing>, DbErr> {
+        let live = Self::live_for(user_id, db).await?;
+        let Some(current) = live.iter().rfind(|membership| membership.starts_at <= Utc::now()) else {
+            return Ok(None);
+        };
+        let expires_at = chain_end(live.iter().filter(|membership| membership.tier == current.tier)).flatten();
+        Ok(Some(Standing {
+            tier: current.tier.clone(),
+            expires_at,
+        }))
+    }
+
+    async fn by_reference(source: &str, reference: &str, db: &impl ConnectionTrait) -> Result<Option<Model>, DbErr> {
+        Self::find()
+            .filter(Column::Source.eq(source))
+            .filter(Column::Reference.eq(reference))
+            .one(db)
+            .await
+    }
+
+    // grants `length` of `tier`, after whatever of it is already lined up. None is for good. a reference
+    // that was already used gets the membership it made back, so a retried webhook doesn't count twice,
+    // even two retries at once. None if that membership is someone else's
+    pub async fn grant(
+        user_id: &str,
+        tier: &str,
+        length: Option<Duration>,
+        source: &str,
+        reference: Option<&str>,
+        granted_by: Option<&str>,
+        db: &impl ConnectionTrait,
+    ) -> Result<Option<Model>, DbErr> {
+        let mine = |membership: Model| (membership.user_id == user_id).then_some(membership);
+        if let Some(reference) = reference
+            && let Some(existing) = Self::by_reference(source, reference, db).await?
+        {
+            return Ok(mine(existing));
+        }
+
+        let live = Self::live_for(user_id, db).await?;
+        // nothing to add to one that never runs out
+        if let Some(forever) = live.iter().find(|m| m.tier == tier && m.expires_at.is_none()) {
+            return Ok(Some(forever.clone()));
+        }
+        let now = Utc::now();
+        let starts_at = match (chain_end(live.iter().filter(|m| m.tier == tier)), length) {
+            (Some(Some(end)), Some(_)) => end.max(now),
+            _ => now,
+        };
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let model = ActiveModel {
+            id: Set(id.clone()),
+            user_id: Set(user_id.to_string()),
+            tier: Set(tier.to_string()),
+            source: Set(source.to_string()),
+            reference: Set(reference.map(str::to_string)),
+            starts_at: Set(starts_at),
+            expires_at: Set(length.map(|length| starts_at + length)),
+            granted_by: Set(granted_by.map(str::to_string)),
+            ..Default::default()
+        };
+        // a retry that got here first inserted the same reference, that one stands
+        let inserted = Self::insert(model)
+            .on_conflict(
+                sea_query::OnConflict::columns([Column::Source, Column::Reference])
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(db)
+            .await?;
+        if inserted == 0
+            && let Some(reference) = reference
+        {
+            let existing = Self::by_reference(source, reference, db)
+                .await?
+                .ok_or_else(|| DbErr::RecordNotFound(format!("Membership for {source} {reference}")))?;
+            return Ok(mine(existing));
+        }
+
+        let membership = Self::find_by_id(id)
+            .one(db)
+            .await?
+            .ok_or_else(|| DbErr::RecordNotFound("Inserted membership".to_string()))?;
+        Self::sync_role(user_id, db).await?;
+        Ok(Some(membership))
+    }
+
+    // ends the current and any lined up ones
+    pub async fn revoke(user_id: &str, db: &impl ConnectionTrait) -> Result<u64, DbErr> {
+        let result = Self::update_many()
+            .col_expr(Column::RevokedAt, sea_query::Expr::value(Some(Utc::now())))
+            .filter(Column::UserId.eq(user_id))
+            .filter(live())
+            .exec(db)
+            .await?;
+        Self::sync_role(user_id, db).await?;
+        Ok(result.rows_affected)
+    }
+
+    // gives or takes the member role to match. true if it changed
+    pub async fn sync_role(user_id: &str, db: &impl ConnectionTrait) -> Result<bool, DbErr> {
+        if Self::standing(user_id, db).await?.is_some() {
+            crate::user_role::Entity::assign(user_id, MEMBER_ROLE, None, None, db).await
+        } else {
+            crate::user_role::Entity::unassign(user_id, MEMBER_ROLE, None, db).await
+        }
+    }
+
+    // hourly: members whose time ran out lose the role, ones whose lined up stretch started get it
+    pub async fn expire_due(db: &DatabaseConnection) -> Result<u64, DbErr> {
+        let mut user_ids: Vec<String> = crate::user_role::Entity::find()
+            .filter(crate::user_role::Column::Role.eq(MEMBER_ROLE))
+            .filter(crate::user_role::Column::ClientId.is_null())
+            .all(db)
+            .await?
+            .into_iter()
+            .map(|assignment| assignment.user_id)
+            .collect();
+        user_ids.extend(
+            Self::find()
+                .filter(live())
+                .filter(Column::StartsAt.lte(Utc::now()))
+                .all(db)
+                .await?
+                .into_iter()
+                .map(|membership| membership.user_id),
+        );
+        user_ids.sort();
+        user_ids.dedup();
+
+        let mut changed = 0;
+        for user_id in &user_ids {
+            if Self::sync_role(user_id, db).await? {
+                changed += 1;
+            }
+        }
+        Ok(changed)
+    }
+}
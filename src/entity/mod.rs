@@ -1,3 +1,19 @@
 pub mod client;
+pub mod federated_identity;
+pub mod group;
+pub mod group_member;
+pub mod group_request;
+pub mod invite;
+pub mod login_attempt;
+pub mod membership;
+pub mod passkey;
+pub mod permission;
+pub mod recovery_code;
+pub mod role;
+pub mod security_event;
+pub mod session;
+pub mod subject;
+pub mod suspension;
 pub mod token;
 pub mod user;
+pub mod user_role;
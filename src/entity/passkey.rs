@@ -0,0 +1,126 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// webauthn credentials, passkeys and security keys alike
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "passkeys")]
+pub struct Model {
+    // credential id, base64url
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    #[sea_orm(indexed)]
+    pub user_id: String,
+    // uncompressed p-256 point, base64url
+    pub public_key: String,
+    pub sign_count: i64,
+    // json array of hints from the browser, handed back in allowCredentials
+    pub transports: String,
+    pub nickname: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            created_at: Set(Utc::now()),
+            transports: Set("[]".to_string()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+impl Model {
+    pub fn get_transports(&self) -> Result<Vec<String>, serde_json::Error> {
+        serde_json::from_str(&self.transports)
+    }
+}
+
+impl Entity {
+    pub async fn for_user(user_id: &str, db: &impl ConnectionTrait) -> Result<Vec<Model>, DbErr> {
+        Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .order_by_asc(Column::CreatedAt)
+            .all(db)
+            .await
+    }
+
+    pub async fn exists_for_user(user_id: &str, db: &impl ConnectionTrait) -> Result<bool, DbErr> {
+        Ok(Self::find().filter(Column::UserId.eq(user_id)).one(db).await?.is_some())
+    }
+
+    // a counter that doesn't move forward means the key may have been cloned. authenticators that
+    // don't keep one always send 0
+    pub async fn used(passkey: Model, sign_count: u32, db: &impl ConnectionTrait) -> Result<bool, DbErr> {
+        let sign_count = i64::from(sign_count);
+        if (sign_count != 0 || passkey.sign_count != 0) && sign_count <= passkey.sign_count {
+            tracing::warn!(id = %passkey.id, user_id = %passkey.user_id, "Passkey sign count went backwards");
+            return Ok(false);
+        }
+
+        let mut passkey: ActiveModel = passkey.into();
+        passkey.sign_count = Set(sign_count);
+        passkey.last_used_at = Set(Some(Utc::now()));
+        passkey.update(db).await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn stored_passkey(sign_count: i64) -> (DatabaseConnection, Model) {
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        let backend = db.get_database_backend();
+        db.execute(backend.build(&Schema::new(backend).create_table_from_entity(Entity)))
+            .await
+            .unwrap();
+        let passkey = ActiveModel {
+            id: Set("credential".to_string()),
+            user_id: Set("user".to_string()),
+            public_key: Set(String::new()),
+            sign_count: Set(sign_count),
+            nickname: Set("Passkey".to_string()),
+            ..Default::default()
+        }
+        .insert(&db)
+        .await
+        .unwrap();
+        (db, passkey)
+    }
+
+    #[tokio::test]
+    async fn counts_forward() {
+        let (db, passkey) = stored_passkey(5).await;
+        assert!(Entity::used(passkey, 6, &db).await.unwrap());
+
+        let stored = Entity::find_by_id("credential").one(&db).await.unwrap().unwrap();
+        assert_eq!(stored.sign_count, 6);
+        assert!(stored.last_used_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn refuses_a_counter_that_went_back() {
+        let (db, passkey) = stored_passkey(5).await;
+        assert!(!Entity::used(passkey.clone(), 5, &db).await.unwrap());
+        assert!(!Entity::used(passkey, 3, &db).await.unwrap());
+        // a key that stopped counting is as suspect as one that went back
+        let (db, passkey) = stored_passkey(5).await;
+        assert!(!Entity::used(passkey, 0, &db).await.unwrap());
+
+        let stored = Entity::find_by_id("credential").one(&db).await.unwrap().unwrap();
+        assert_eq!(stored.sign_count, 5);
+        assert!(stored.last_used_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn accepts_authenticators_without_a_counter() {
+        let (db, passkey) = stored_passkey(0).await;
+        assert!(Entity::used(passkey, 0, &db).await.unwrap());
+    }
+}
@@ -0,0 +1,77 @@
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// what we check for ourselves. clients can define their own on their roles, we just pass them on
+pub const USERS_UPDATE: &str = "users:update";
+pub const USERS_DEACTIVATE: &str = "users:deactivate";
+pub const USERS_ROLES: &str = "users:roles";
+pub const USERS_SUSPEND: &str = "users:suspend";
+// suspending someone who holds permissions themselves
+pub const USERS_SUSPEND_STAFF: &str = "users:suspend_staff";
+pub const USERS_ACTIVITY: &str = "users:activity";
+pub const LOCKOUTS_CLEAR: &str = "lockouts:clear";
+// any group, as if its owner
+pub const GROUPS_MANAGE: &str = "groups:manage";
+pub const MEMBERSHIPS_MANAGE: &str = "memberships:manage";
+// every invite, and ones with roles or no limits
+pub const INVITES_MANAGE: &str = "invites:manage";
+// client secrets
+pub const CLIENTS_MANAGE: &str = "clients:manage";
+
+pub const ALL: &[&str] = &[
+    USERS_UPDATE,
+    USERS_DEACTIVATE,
+    USERS_ROLES,
+    USERS_SUSPEND,
+    USERS_SUSPEND_STAFF,
+    USERS_ACTIVITY,
+    LOCKOUTS_CLEAR,
+    GROUPS_MANAGE,
+    MEMBERSHIPS_MANAGE,
+    INVITES_MANAGE,
+    CLIENTS_MANAGE,
+];
+
+// one permission a role carries
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "permissions")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    #[sea_orm(indexed)]
+    pub role: String,
+    pub permission: String,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            id: Set(uuid::Uuid::new_v4().to_string()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+impl Entity {
+    pub async fn add(role: &str, permission: &str, db: &impl ConnectionTrait) -> Result<bool, DbErr> {
+        let existing = Self::find()
+            .filter(Column::Role.eq(role))
+            .filter(Column::Permission.eq(permission))
+            .one(db)
+            .await?;
+        if existing.is_some() {
+            return Ok(false);
+        }
+
+        let model = ActiveModel {
+            role: Set(role.to_string()),
+            permission: Set(permission.to_string()),
+            ..Default::default()
+        };
+        model.insert(db).await?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,165 @@
+use crate::password::PasswordService;
+use chrono::{DateTime, Utc};
+use ring::rand::{SecureRandom, SystemRandom};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+pub const COUNT: usize = 10;
+const LENGTH: usize = 10;
+// no 0/o, 1/l/i, so they survive being written down
+const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+// single use fallback for a lost second factor, hashed like passwords
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "recovery_codes")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    #[sea_orm(indexed)]
+    pub user_id: String,
+    #[serde(skip_serializing)]
+    pub code_hash: String,
+    // `crypto::lookup_key` of the code, None on sets made before it
+    #[sea_orm(indexed)]
+    #[serde(skip_serializing)]
+    pub lookup: Option<String>,
+    pub used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            id: Set(uuid::Uuid::new_v4().to_string()),
+            created_at: Set(Utc::now()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+fn generate() -> String {
+    let rng = SystemRandom::new();
+    let mut code = String::with_capacity(LENGTH);
+    let mut byte = [0u8; 1];
+    while code.len() < LENGTH {
+        rng.fill(&mut byte).unwrap();
+        // rejection sampling keeps it unbiased
+        if (byte[0] as usize) < 256 - 256 % ALPHABET.len() {
+            code.push(ALPHABET[byte[0] as usize % ALPHABET.len()] as char);
+        }
+    }
+    code
+}
+
+// what gets hashed, so "ABCDE-FGHIJ" and "abcdefghij" are the same code
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+// how codes are shown, "abcde-fghij"
+fn format(code: &str) -> String {
+    let (a, b) = code.split_at(LENGTH / 2);
+    format!("{a}-{b}")
+}
+
+fn lookup(code: &str) -> String {
+    crate::crypto::lookup_key(code, "recovery-code")
+}
+
+// argon2 is slow on purpose, so off the async workers
+async fn verify(code: String, hash: String, password: &PasswordService) -> anyhow::Result<bool> {
+    let password = password.clone();
+    tokio::task::spawn_blocking(move || password.verify(&code, &hash)).await?
+}
+
+impl Entity {
+    // replaces any earlier set. the plaintext codes only exist in the return value
+    pub async fn regenerate(
+        user_id: &str,
+        password: &PasswordService,
+        db: &impl ConnectionTrait,
+    ) -> anyhow::Result<Vec<String>> {
+        let codes: Vec<String> = (0..COUNT).map(|_| generate()).collect();
+        let hashes = {
+            let (codes, password) = (codes.clone(), password.clone());
+            tokio::task::spawn_blocking(move || {
+                codes
+                    .iter()
+                    .map(|code| password.hash(code))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .await??
+        };
+        let models = codes.iter().zip(hashes).map(|(code, hash)| ActiveModel {
+            user_id: Set(user_id.to_string()),
+            code_hash: Set(hash),
+            lookup: Set(Some(lookup(code))),
+            ..Default::default()
+        });
+
+        Self::delete_many().filter(Column::UserId.eq(user_id)).exec(db).await?;
+        Self::insert_many(models).exec(db).await?;
+        Ok(codes.iter().map(|code| format(code)).collect())
+    }
+
+    // the first second factor comes with a set, later ones leave it alone
+    pub async fn issue_if_missing(
+        user_id: &str,
+        password: &PasswordService,
+        db: &impl ConnectionTrait,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        if Self::remaining(user_id, db).await? > 0 {
+            return Ok(None);
+        }
+        Ok(Some(Self::regenerate(user_id, password, db).await?))
+    }
+
+    pub async fn remaining(user_id: &str, db: &impl ConnectionTrait) -> Result<u64, DbErr> {
+        Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .filter(Column::UsedAt.is_null())
+            .count(db)
+            .await
+    }
+
+    // burns the matching code, conditionally so the same code can't get two logins. the lookup key picks
+    // the one code to check, only sets from before it cost a hash per code until they're replaced
+    pub async fn redeem(
+        user_id: &str,
+        code: &str,
+        password: &PasswordService,
+        db: &impl ConnectionTrait,
+    ) -> anyhow::Result<bool> {
+        let code = normalize(code);
+        if code.len() != LENGTH {
+            return Ok(false);
+        }
+
+        let unused = Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .filter(Column::UsedAt.is_null());
+        let candidates = match unused.clone().filter(Column::Lookup.eq(lookup(&code))).one(db).await? {
+            Some(model) => vec![model],
+            None => unused.filter(Column::Lookup.is_null()).all(db).await?,
+        };
+
+        for model in candidates {
+            if verify(code.clone(), model.code_hash, password).await? {
+                let result = Self::update_many()
+                    .col_expr(Column::UsedAt, sea_query::Expr::value(Utc::now()))
+                    .filter(Column::Id.eq(&model.id))
+                    .filter(Column::UsedAt.is_null())
+                    .exec(db)
+                    .await?;
+                return Ok(result.rows_affected == 1);
+            }
+        }
+        Ok(false)
+    }
+}
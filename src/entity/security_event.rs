@@ -0,0 +1,58 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+pub const PASSWORD_CHANGED: &str = "password_changed";
+pub const PASSWORD_RESET: &str = "password_reset";
+pub const EMAIL_CHANGED: &str = "email_changed";
+pub const TOTP_ENABLED: &str = "totp_enabled";
+pub const PASSKEY_ADDED: &str = "passkey_added";
+pub const PASSKEY_REMOVED: &str = "passkey_removed";
+pub const RECOVERY_CODES_GENERATED: &str = "recovery_codes_generated";
+pub const RECOVERY_CODE_USED: &str = "recovery_code_used";
+pub const FEDERATED_LINKED: &str = "federated_linked";
+pub const FEDERATED_UNLINKED: &str = "federated_unlinked";
+pub const DELETION_REQUESTED: &str = "deletion_requested";
+pub const DELETION_CANCELLED: &str = "deletion_cancelled";
+pub const SESSION_ENDED: &str = "session_ended";
+pub const GRANT_REVOKED: &str = "grant_revoked";
+pub const SIGNED_OUT_EVERYWHERE: &str = "signed_out_everywhere";
+
+// audit trail of changes to a user's credentials
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "security_events")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    #[sea_orm(indexed)]
+    pub user_id: String,
+    pub kind: String,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            id: Set(uuid::Uuid::new_v4().to_string()),
+            created_at: Set(Utc::now()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+impl Entity {
+    pub async fn record(user_id: &str, kind: &str, ip: Option<String>, db: &impl ConnectionTrait) -> Result<(), DbErr> {
+        let model = ActiveModel {
+            user_id: Set(user_id.to_string()),
+            kind: Set(kind.to_string()),
+            ip: Set(ip),
+            ..Default::default()
+        };
+        model.insert(db).await?;
+        Ok(())
+    }
+}
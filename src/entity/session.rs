@@ -0,0 +1,144 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// where a session was started from
+pub struct Device {
+    pub ip: String,
+    pub country: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+// the browser's sso session with us, shared by every client it signs in to
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "sessions")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub sid: String,
+    #[sea_orm(indexed)]
+    pub user_id: String,
+    // opaque "op browser state" for session management, also kept in a js readable cookie
+    pub browser_state: String,
+    // json array of clients that got a code in this session, for front-channel logout
+    pub client_ids: String,
+    // how the user signed in, see `token::parse_amr`
+    pub amr: Option<String>,
+    // the browser it was started from, for the account's sessions page
+    pub ip: Option<String>,
+    pub country: Option<String>,
+    pub user_agent: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            created_at: Set(Utc::now()),
+            last_seen_at: Set(Utc::now()),
+            expires_at: Set(Utc::now() + crate::lifetime::session()),
+            client_ids: Set("[]".to_string()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+impl Model {
+    pub fn get_client_ids(&self) -> Result<Vec<String>, serde_json::Error> {
+        serde_json::from_str(&self.client_ids)
+    }
+
+    pub fn get_amr(&self) -> Vec<String> {
+        crate::token::parse_amr(self.amr.as_deref())
+    }
+
+    pub fn is_multi_factor(&self) -> bool {
+        crate::token::is_multi_factor(&self.get_amr())
+    }
+
+    pub fn public_id(&self) -> String {
+        public_id(&self.sid)
+    }
+
+    // signed in just now, not merely still signed in
+    pub fn is_fresh(&self) -> bool {
+        Utc::now() - self.created_at < crate::lifetime::reauthentication()
+    }
+}
+
+crate::impl_verify!(Sid);
+
+// the sid doubles as the cookie, this is what the user, the api and the clients (id tokens' `sid`,
+// front-channel logout) get to see instead
+pub fn public_id(sid: &str) -> String {
+    let digest = Sha256::digest(sid.as_bytes());
+    digest[..16].iter().map(|b| format!("{b:02x}")).collect()
+}
+
+impl Entity {
+    pub async fn create(
+        user_id: &str,
+        amr: &[&str],
+        device: Device,
+        db: &impl ConnectionTrait,
+    ) -> Result<Model, DbErr> {
+        let model = ActiveModel {
+            sid: Set(uuid::Uuid::new_v4().to_string()),
+            user_id: Set(user_id.to_string()),
+            browser_state: Set(crate::util::generate_random_string(32)),
+            amr: Set(Some(
+                serde_json::to_string(amr).map_err(|e| DbErr::Custom(e.to_string()))?,
+            )),
+            ip: Set(Some(device.ip)),
+            country: Set(device.country),
+            user_agent: Set(device.user_agent),
+            ..Default::default()
+        };
+        model.insert(db).await
+    }
+
+    pub async fn add_client(session: Model, client_id: &str, db: &impl ConnectionTrait) -> Result<Model, DbErr> {
+        let mut client_ids = session.get_client_ids().unwrap_or_default();
+        if !client_ids.iter().any(|id| id == client_id) {
+            client_ids.push(client_id.to_string());
+        }
+
+        let mut session: ActiveModel = session.into();
+        session.client_ids = Set(serde_json::to_string(&client_ids).map_err(|e| DbErr::Custom(e.to_string()))?);
+        session.last_seen_at = Set(Utc::now());
+        session.update(db).await
+    }
+
+    // live ones, most recently used first
+    pub async fn for_user(user_id: &str, db: &impl ConnectionTrait) -> Result<Vec<Model>, DbErr> {
+        Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .filter(Column::ExpiresAt.gt(Utc::now()))
+            .order_by_desc(Column::LastSeenAt)
+            .all(db)
+            .await
+    }
+
+    pub async fn end(sid: &str, db: &impl ConnectionTrait) -> Result<Option<Model>, DbErr> {
+        let session = Self::find_by_id(sid).one(db).await?;
+        if session.is_some() {
+            Self::delete_by_id(sid).exec(db).await?;
+        }
+        Ok(session)
+    }
+
+    // every session the user has, bar `keep_sid`
+    pub async fn end_all(user_id: &str, keep_sid: Option<&str>, db: &impl ConnectionTrait) -> Result<u64, DbErr> {
+        let mut query = Self::delete_many().filter(Column::UserId.eq(user_id));
+        if let Some(sid) = keep_sid {
+            query = query.filter(Column::Sid.ne(sid));
+        }
+        let result = query.exec(db).await?;
+        Ok(result.rows_affected)
+    }
+}
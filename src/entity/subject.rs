@@ -0,0 +1,65 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// pairwise subs are one-way hashes, so we keep the ones we've handed out to map them back
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "pairwise_subjects")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub sub: String,
+    pub sector: String,
+    pub user_id: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            created_at: Set(Utc::now()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+impl Entity {
+    pub async fn remember(
+        client: &crate::client::Model,
+        user_id: &str,
+        db: &impl ConnectionTrait,
+    ) -> Result<(), DbErr> {
+        let sub = client.subject_for(user_id);
+        if Self::find_by_id(&sub).one(db).await?.is_some() {
+            return Ok(());
+        }
+
+        let model = ActiveModel {
+            sub: Set(sub),
+            sector: Set(client.sector()),
+            user_id: Set(user_id.to_string()),
+            ..Default::default()
+        };
+        model.insert(db).await?;
+        Ok(())
+    }
+
+    // user id behind a sub the client gave us. public clients already use the user id
+    pub async fn user_id(
+        client: &crate::client::Model,
+        sub: &str,
+        db: &impl ConnectionTrait,
+    ) -> Result<Option<String>, DbErr> {
+        if !client.is_pairwise() {
+            return Ok(Some(sub.to_string()));
+        }
+
+        Ok(Self::find_by_id(sub)
+            .filter(Column::Sector.eq(client.sector()))
+            .one(db)
+            .await?
+            .map(|subject| subject.user_id))
+    }
+}
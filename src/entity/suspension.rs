@@ -0,0 +1,110 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// a moderator locking an account for a while. kept after it ends, as the account's history
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "suspensions")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    #[sea_orm(indexed)]
+    pub user_id: String,
+    // shown to the user
+    pub reason: String,
+    pub moderator_id: String,
+    pub starts_at: DateTime<Utc>,
+    // None until lifted by hand
+    pub ends_at: Option<DateTime<Utc>>,
+    pub lifted_at: Option<DateTime<Utc>>,
+    pub lifted_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            id: Set(uuid::Uuid::new_v4().to_string()),
+            created_at: Set(Utc::now()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+impl Model {
+    pub fn message(&self) -> String {
+        match self.ends_at {
+            Some(ends_at) => format!(
+                "This account is suspended until {}: {}",
+                ends_at.format("%Y-%m-%d %H:%M UTC"),
+                self.reason
+            ),
+            None => format!("This account is suspended: {}", self.reason),
+        }
+    }
+}
+
+// in force right now
+fn active() -> Condition {
+    let now = Utc::now();
+    Condition::all()
+        .add(Column::StartsAt.lte(now))
+        .add(
+            Condition::any()
+                .add(Column::EndsAt.is_null())
+                .add(Column::EndsAt.gt(now)),
+        )
+        .add(Column::LiftedAt.is_null())
+}
+
+impl Entity {
+    // the one ending last, if several overlap
+    pub async fn active_for(user_id: &str, db: &impl ConnectionTrait) -> Result<Option<Model>, DbErr> {
+        let mut suspensions = Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .filter(active())
+            .all(db)
+            .await?;
+        suspensions.sort_by_key(|suspension| suspension.ends_at.unwrap_or(DateTime::<Utc>::MAX_UTC));
+        Ok(suspensions.pop())
+    }
+
+    pub async fn create(
+        user_id: &str,
+        reason: &str,
+        moderator_id: &str,
+        starts_at: DateTime<Utc>,
+        ends_at: Option<DateTime<Utc>>,
+        db: &impl ConnectionTrait,
+    ) -> Result<Model, DbErr> {
+        let model = ActiveModel {
+            user_id: Set(user_id.to_string()),
+            reason: Set(reason.to_string()),
+            moderator_id: Set(moderator_id.to_string()),
+            starts_at: Set(starts_at),
+            ends_at: Set(ends_at),
+            ..Default::default()
+        };
+        model.insert(db).await
+    }
+
+    // ends every current and upcoming one early
+    pub async fn lift(user_id: &str, moderator_id: &str, db: &impl ConnectionTrait) -> Result<u64, DbErr> {
+        let result = Self::update_many()
+            .col_expr(Column::LiftedAt, sea_query::Expr::value(Some(Utc::now())))
+            .col_expr(Column::LiftedBy, sea_query::Expr::value(Some(moderator_id.to_string())))
+            .filter(Column::UserId.eq(user_id))
+            .filter(Column::LiftedAt.is_null())
+            .filter(
+                Condition::any()
+                    .add(Column::EndsAt.is_null())
+                    .add(Column::EndsAt.gt(Utc::now())),
+            )
+            .exec(db)
+            .await?;
+        Ok(result.rows_affected)
+    }
+}
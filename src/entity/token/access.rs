@@ -3,16 +3,19 @@ use jsonwebtoken::EncodingKey;
 use sea_orm::*;
 use serde::{Deserialize, Serialize};
 
-const EXPIRATION_MIN: i64 = 60 * 24;
-
 #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "access_tokens")]
 pub struct Model {
+    // the jwt itself is verified statelessly, only its id is kept for revocation
     #[sea_orm(primary_key, auto_increment = false)]
-    pub token: String,
+    pub jti: String,
     pub client_id: String,
+    #[sea_orm(indexed)]
     pub user_id: String,
     pub scopes: String,
+    // refresh token family this was issued in, see `refresh::Family`
+    #[sea_orm(indexed)]
+    pub family_id: Option<String>,
     pub expires_at: DateTime<Utc>,
     pub created_at: DateTime<Utc>,
 }
@@ -24,58 +27,81 @@ impl ActiveModelBehavior for ActiveModel {
     fn new() -> Self {
         Self {
             created_at: Set(Utc::now()),
-            expires_at: Set(Utc::now() + chrono::Duration::minutes(EXPIRATION_MIN)),
             ..ActiveModelTrait::default()
         }
     }
 }
 
-crate::impl_verify!(Token);
+crate::impl_verify!(Jti);
 
 impl Entity {
     pub async fn create(
-        client_id: &str,
+        client: &crate::client::Model,
         user: &crate::user::Model,
         scopes: &str,
+        family: &super::refresh::Family,
         db: &impl ConnectionTrait,
         encoding_key: &EncodingKey,
-    ) -> Result<String, DbErr> {
+    ) -> Result<(String, String), DbErr> {
+        // (access_token, jti)
+        // same instant for the jwt exp and the row, so they can't drift apart
+        let expires_at = Utc::now() + client.lifetimes().access;
+        let jti = uuid::Uuid::new_v4().to_string();
+        let extra = super::extra_claims(&user.id, &client.client_id, scopes, db).await?;
         let access_token = crate::jwt::create_jwt(
             user,
-            client_id,
-            crate::jwt::TokenType::AccessToken,
+            client,
+            crate::jwt::TokenType::AccessToken {
+                jti: jti.clone(),
+                amr: super::parse_amr(family.amr.as_deref()),
+            },
             scopes,
+            &extra,
+            expires_at,
             encoding_key,
         )
         .map_err(|e| DbErr::Custom(e.to_string()))?;
 
         let model = ActiveModel {
-            token: Set(access_token.clone()),
-            client_id: Set(client_id.to_string()),
+            jti: Set(jti.clone()),
+            client_id: Set(client.client_id.clone()),
             user_id: Set(user.id.to_string()),
             scopes: Set(scopes.to_string()),
+            family_id: Set(Some(family.id.clone())),
+            expires_at: Set(expires_at),
             ..Default::default()
         };
         model.insert(db).await?;
-        Ok(access_token)
+
+        if client.is_pairwise() {
+            crate::subject::Entity::remember(client, &user.id, db).await?;
+        }
+
+        Ok((access_token, jti))
     }
 
-    pub async fn revoke(token: &str, client_id: &str, db: &DatabaseConnection) -> Result<bool, DbErr> {
-        let Some(access_token) = Self::find_by_id(token).one(db).await? else {
+    pub async fn revoke(jti: &str, client: &crate::client::Model, db: &DatabaseConnection) -> Result<bool, DbErr> {
+        let Some(access_token) = Self::find_by_id(jti).one(db).await? else {
             return Ok(false);
         };
 
-        if access_token.client_id != client_id {
+        if access_token.client_id != client.client_id {
             return Ok(false);
         }
 
         let txn = db.begin().await?;
 
-        Self::delete_by_id(token).exec(&txn).await?;
+        Self::delete_by_id(jti).exec(&txn).await?;
 
-        // remove associated refresh token
-        if let Some(refresh) = crate::token::refresh::Entity::find()
-            .filter(crate::token::refresh::Column::AccessToken.eq(token))
+        if !client.revoke_refresh_with_access {
+            txn.commit().await?;
+            return Ok(true);
+        }
+
+        if let Some(family_id) = &access_token.family_id {
+            crate::token::refresh::Entity::revoke_family(family_id, &txn).await?;
+        } else if let Some(refresh) = crate::token::refresh::Entity::find()
+            .filter(crate::token::refresh::Column::AccessJti.eq(jti))
             .one(&txn)
             .await?
         {
@@ -87,4 +113,14 @@ impl Entity {
         txn.commit().await?;
         Ok(true)
     }
+
+    // per user rather than per token, see `refresh::Entity::revoke_client`
+    pub async fn revoke_client(user_id: &str, client_id: &str, db: &impl ConnectionTrait) -> Result<u64, DbErr> {
+        let result = Self::delete_many()
+            .filter(Column::UserId.eq(user_id))
+            .filter(Column::ClientId.eq(client_id))
+            .exec(db)
+            .await?;
+        Ok(result.rows_affected)
+    }
 }
@@ -3,8 +3,6 @@ use jsonwebtoken::EncodingKey;
 use sea_orm::*;
 use serde::{Deserialize, Serialize};
 
-const EXPIRATION_MIN: i64 = 10;
-
 #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "auth_codes")]
 pub struct Model {
@@ -19,6 +17,10 @@ pub struct Model {
 
     pub code_challenge: String,
     pub code_challenge_method: String,
+
+    // sso session the code was issued in, and how the user signed in to it
+    pub sid: Option<String>,
+    pub amr: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -28,7 +30,6 @@ impl ActiveModelBehavior for ActiveModel {
     fn new() -> Self {
         Self {
             created_at: Set(Utc::now()),
-            expires_at: Set(Utc::now() + chrono::Duration::minutes(EXPIRATION_MIN)),
             ..ActiveModelTrait::default()
         }
     }
@@ -39,13 +40,13 @@ crate::impl_verify!(Code);
 impl Entity {
     pub async fn exchange_for_tokens(
         code: &str,
-        client_id: &str,
+        client: &crate::client::Model,
         redirect_uri: &str,
         code_verifier: &str,
         db: &DatabaseConnection,
         encoding_key: &EncodingKey,
-    ) -> Result<(String, String, String, crate::user::Model), DbErr> {
-        // (access_token, refresh_token, scopes, user_id)
+    ) -> Result<super::IssuedTokens, DbErr> {
+        let client_id = client.client_id.as_str();
         let txn = db.begin().await?;
 
         // validation
@@ -66,14 +67,16 @@ impl Entity {
             .await?
             .ok_or(DbErr::RecordNotFound("User not found".to_string()))?;
 
-        let access_token =
-            crate::token::access::Entity::create(client_id, &user, &auth_code.scopes, &txn, encoding_key).await?;
+        let family = crate::token::refresh::Family::new(client, auth_code.sid.clone(), auth_code.amr.clone());
+        let (access_token, access_jti) =
+            crate::token::access::Entity::create(client, &user, &auth_code.scopes, &family, &txn, encoding_key).await?;
 
         let refresh_token = crate::token::refresh::Entity::create(
-            &access_token,
-            client_id,
+            &access_jti,
+            client,
             &auth_code.user_id,
             &auth_code.scopes,
+            &family,
             &txn,
         )
         .await?;
@@ -87,6 +90,13 @@ impl Entity {
             .ok_or(DbErr::RecordNotFound(String::new()))?;
 
         txn.commit().await?;
-        Ok((access_token, refresh_token, auth_code.scopes, user))
+        Ok(super::IssuedTokens {
+            access_token,
+            refresh_token,
+            scopes: auth_code.scopes,
+            user,
+            sid: auth_code.sid,
+            amr: super::parse_amr(auth_code.amr.as_deref()),
+        })
     }
 }
@@ -0,0 +1,65 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// a trip to an upstream provider, looked up again by `state` when it comes back
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "federation_states")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub state: String,
+    pub provider: String,
+    pub nonce: String,
+    // our pkce verifier towards the provider
+    pub code_verifier: String,
+    // the client's authorization request to resume, json `OAuthParams`. empty when linking
+    pub oauth: String,
+    // set when a signed in user is adding this provider to their account rather than logging in
+    pub link_user_id: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            created_at: Set(Utc::now()),
+            expires_at: Set(Utc::now() + crate::lifetime::federation()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+crate::impl_verify!(State);
+
+impl Entity {
+    pub async fn create(
+        provider: &str,
+        oauth: &str,
+        link_user_id: Option<&str>,
+        db: &impl ConnectionTrait,
+    ) -> Result<Model, DbErr> {
+        let model = ActiveModel {
+            state: Set(crate::util::generate_random_string(43)),
+            provider: Set(provider.to_string()),
+            nonce: Set(crate::util::generate_random_string(32)),
+            code_verifier: Set(crate::util::generate_random_string(64)),
+            oauth: Set(oauth.to_string()),
+            link_user_id: Set(link_user_id.map(str::to_string)),
+            ..Default::default()
+        };
+        model.insert(db).await
+    }
+
+    // single use
+    pub async fn consume(state: &str, db: &impl ConnectionTrait) -> Result<Option<Model>, DbErr> {
+        let Some(model) = Self::verify(state, db).await? else {
+            return Ok(None);
+        };
+        let deleted = Self::delete_by_id(state).exec(db).await?;
+        Ok((deleted.rows_affected == 1).then_some(model))
+    }
+}
@@ -0,0 +1,75 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// a first factor that checked out, waiting on the second
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "mfa_challenges")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub token: String,
+    pub user_id: String,
+    // amr of the step already passed, None from before federated login meant "pwd"
+    pub first_factor: Option<String>,
+    pub attempts: i32,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            created_at: Set(Utc::now()),
+            expires_at: Set(Utc::now() + crate::lifetime::mfa_challenge()),
+            attempts: Set(0),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+// a million codes, a handful of guesses, then back to the password
+const MAX_ATTEMPTS: i32 = 5;
+
+impl Model {
+    pub fn first_factor(&self) -> &str {
+        self.first_factor.as_deref().unwrap_or("pwd")
+    }
+}
+
+crate::impl_verify!(Token);
+
+impl Entity {
+    pub async fn create(user_id: &str, first_factor: &str, db: &impl ConnectionTrait) -> Result<Model, DbErr> {
+        let model = ActiveModel {
+            token: Set(crate::util::generate_random_string(32)),
+            user_id: Set(user_id.to_string()),
+            first_factor: Set(Some(first_factor.to_string())),
+            ..Default::default()
+        };
+        model.insert(db).await
+    }
+
+    // false once the challenge is used up. conditional, so guesses posted side by side all count
+    pub async fn fail(token: &str, db: &impl ConnectionTrait) -> Result<bool, DbErr> {
+        let result = Self::update_many()
+            .col_expr(Column::Attempts, sea_query::Expr::col(Column::Attempts).add(1))
+            .filter(Column::Token.eq(token))
+            .filter(Column::Attempts.lt(MAX_ATTEMPTS - 1))
+            .exec(db)
+            .await?;
+        if result.rows_affected == 1 {
+            return Ok(true);
+        }
+
+        Self::delete_by_id(token).exec(db).await?;
+        Ok(false)
+    }
+
+    pub async fn consume(token: &str, db: &impl ConnectionTrait) -> Result<bool, DbErr> {
+        let deleted = Self::delete_by_id(token).exec(db).await?;
+        Ok(deleted.rows_affected == 1)
+    }
+}
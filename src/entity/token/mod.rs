@@ -1,6 +1,99 @@
 pub mod access;
 pub mod auth;
+pub mod federation;
+pub mod mfa;
 pub mod refresh;
+pub mod reset;
+pub mod verification;
+pub mod webauthn;
+
+use sea_orm::*;
+
+// what a code exchange or refresh hands back to the token endpoint
+pub struct IssuedTokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub scopes: String,
+    pub user: crate::user::Model,
+    pub sid: Option<String>,
+    pub amr: Vec<String>,
+}
+
+// whether the user proved more than one thing, or one thing that counts as two (a verified passkey)
+pub fn is_multi_factor(amr: &[String]) -> bool {
+    amr.iter().any(|m| matches!(m.as_str(), "otp" | "hwk" | "mfa"))
+}
+
+// rfc 8176 methods, stored as a json array. rows from before we tracked it were password only
+pub fn parse_amr(amr: Option<&str>) -> Vec<String> {
+    amr.and_then(|amr| serde_json::from_str(amr).ok())
+        .unwrap_or_else(|| vec!["pwd".to_string()])
+}
+
+// what goes into a client's tokens beyond the user row, see `jwt::ExtraClaims`
+pub async fn extra_claims(
+    user_id: &str,
+    client_id: &str,
+    scopes: &str,
+    db: &impl ConnectionTrait,
+) -> Result<crate::jwt::ExtraClaims, DbErr> {
+    let has = |scope: &str| scopes.split_whitespace().any(|s| s == scope);
+    let mut extra = crate::jwt::ExtraClaims::default();
+    if has("roles") {
+        extra.grants = Some(crate::user_role::Entity::grants(user_id, Some(client_id), db).await?);
+    }
+    if has("groups") {
+        extra.groups = Some(crate::group_member::Entity::memberships(user_id, db).await?);
+    }
+    extra.membership = crate::membership::Entity::standing(user_id, db).await?;
+    Ok(extra)
+}
+
+// every grant a user holds, for when their credentials change. grants issued in `keep_sid`
+// survive, so the browser making the change stays signed in
+pub async fn revoke_all_for_user(
+    user_id: &str,
+    keep_sid: Option<&str>,
+    db: &impl ConnectionTrait,
+) -> Result<(), DbErr> {
+    let mut codes = Condition::all().add(auth::Column::UserId.eq(user_id));
+    let mut refresh_tokens = Condition::all().add(refresh::Column::UserId.eq(user_id));
+    let mut access_tokens = Condition::all().add(access::Column::UserId.eq(user_id));
+
+    if let Some(sid) = keep_sid {
+        let kept_families: Vec<String> = refresh::Entity::find()
+            .filter(refresh::Column::UserId.eq(user_id))
+            .filter(refresh::Column::Sid.eq(sid))
+            .all(db)
+            .await?
+            .into_iter()
+            .filter_map(|token| token.family_id)
+            .collect();
+
+        codes = codes.add(
+            Condition::any()
+                .add(auth::Column::Sid.is_null())
+                .add(auth::Column::Sid.ne(sid)),
+        );
+        refresh_tokens = refresh_tokens.add(
+            Condition::any()
+                .add(refresh::Column::Sid.is_null())
+                .add(refresh::Column::Sid.ne(sid)),
+        );
+        if !kept_families.is_empty() {
+            access_tokens = access_tokens.add(
+                Condition::any()
+                    .add(access::Column::FamilyId.is_null())
+                    .add(access::Column::FamilyId.is_not_in(kept_families)),
+            );
+        }
+    }
+
+    auth::Entity::delete_many().filter(codes).exec(db).await?;
+    refresh::Entity::delete_many().filter(refresh_tokens).exec(db).await?;
+    access::Entity::delete_many().filter(access_tokens).exec(db).await?;
+    Ok(())
+}
 
 #[macro_export]
 macro_rules! impl_verify {
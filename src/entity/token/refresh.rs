@@ -3,20 +3,27 @@ use jsonwebtoken::EncodingKey;
 use sea_orm::*;
 use serde::{Deserialize, Serialize};
 
-const EXPIRATION_DAYS: i64 = 30;
-
-// TODO: ROTATION, BLACKLISTING
-
 #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "refresh_tokens")]
 pub struct Model {
     #[sea_orm(primary_key, auto_increment = false)]
     pub token: String,
-    pub access_token: String,
+    // jti of the access token issued alongside
+    #[sea_orm(column_name = "access_token")]
+    pub access_jti: String,
     pub client_id: String,
+    #[sea_orm(indexed)]
     pub user_id: String,
     pub scopes: String,
     pub expires_at: DateTime<Utc>,
+    // carried over on rotation, caps expires_at
+    pub absolute_expires_at: Option<DateTime<Utc>>,
+    // sso session the family started in, and how the user signed in to it
+    pub sid: Option<String>,
+    pub amr: Option<String>,
+    // every token rotated out of the same code exchange shares this, access tokens included
+    #[sea_orm(indexed)]
+    pub family_id: Option<String>,
     pub created_at: DateTime<Utc>,
 }
 
@@ -27,30 +34,78 @@ impl ActiveModelBehavior for ActiveModel {
     fn new() -> Self {
         Self {
             created_at: Set(Utc::now()),
-            expires_at: Set(Utc::now() + chrono::Duration::days(EXPIRATION_DAYS)),
             ..ActiveModelTrait::default()
         }
     }
 }
 
+// what a refresh token passes on to the one replacing it
+pub struct Family {
+    pub id: String,
+    pub absolute_expires_at: DateTime<Utc>,
+    pub sid: Option<String>,
+    pub amr: Option<String>,
+}
+
+impl Family {
+    // a code exchange starts a new family, its absolute lifetime starts now
+    pub fn new(client: &crate::client::Model, sid: Option<String>, amr: Option<String>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            absolute_expires_at: Utc::now() + client.lifetimes().refresh_absolute,
+            sid,
+            amr,
+        }
+    }
+}
+
+impl Model {
+    // what the sessions page and api call a grant, the token itself stays secret
+    pub fn grant_id(&self) -> String {
+        self.family_id.clone().unwrap_or_else(|| self.access_jti.clone())
+    }
+
+    pub fn family(&self, client: &crate::client::Model) -> Family {
+        // rows from before families existed start one, and count from their own creation
+        Family {
+            id: self
+                .family_id
+                .clone()
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+            absolute_expires_at: self
+                .absolute_expires_at
+                .unwrap_or(self.created_at + client.lifetimes().refresh_absolute),
+            sid: self.sid.clone(),
+            amr: self.amr.clone(),
+        }
+    }
+}
+
 crate::impl_verify!(Token);
 
 impl Entity {
     pub async fn create(
-        access_token: &str,
-        client_id: &str,
+        access_jti: &str,
+        client: &crate::client::Model,
         user_id: &str,
         scopes: &str,
+        family: &Family,
         db: &impl ConnectionTrait,
     ) -> Result<String, DbErr> {
         let refresh_token = crate::util::generate_random_string(64);
+        let expires_at = (Utc::now() + client.lifetimes().refresh_idle).min(family.absolute_expires_at);
 
         let model = ActiveModel {
             token: Set(refresh_token.clone()),
-            access_token: Set(access_token.to_string()),
-            client_id: Set(client_id.to_string()),
+            access_jti: Set(access_jti.to_string()),
+            client_id: Set(client.client_id.clone()),
             user_id: Set(user_id.to_string()),
             scopes: Set(scopes.to_string()),
+            expires_at: Set(expires_at),
+            absolute_expires_at: Set(Some(family.absolute_expires_at)),
+            sid: Set(family.sid.clone()),
+            amr: Set(family.amr.clone()),
+            family_id: Set(Some(family.id.clone())),
             ..Default::default()
         };
         model.insert(db).await?;
@@ -59,10 +114,11 @@ impl Entity {
 
     pub async fn refresh_tokens(
         refresh_token: &str,
-        client_id: &str,
+        client: &crate::client::Model,
         db: &DatabaseConnection,
         encoding_key: &EncodingKey,
-    ) -> Result<(String, String, String, crate::user::Model), DbErr> {
+    ) -> Result<super::IssuedTokens, DbErr> {
+        let client_id = client.client_id.as_str();
         let txn = db.begin().await?;
 
         let refresh_record = Self::verify(refresh_token, &txn)
@@ -74,35 +130,43 @@ impl Entity {
         }
 
         // delete old
-        crate::token::access::Entity::delete_by_id(&refresh_record.access_token)
+        crate::token::access::Entity::delete_by_id(&refresh_record.access_jti)
             .exec(&txn)
             .await?;
-        Self::delete_by_id(refresh_token).exec(&txn).await?;
+        // only one of two refreshes racing with the same token gets to rotate it
+        if Self::delete_by_id(refresh_token).exec(&txn).await?.rows_affected != 1 {
+            return Err(DbErr::RecordNotFound(String::new()));
+        }
 
         let user = crate::user::Entity::find_by_id(&refresh_record.user_id)
             .one(&txn)
             .await?
             .ok_or(DbErr::RecordNotFound("User not found".to_string()))?;
 
-        let access_token =
-            crate::token::access::Entity::create(client_id, &user, &refresh_record.scopes, &txn, encoding_key).await?;
+        let family = refresh_record.family(client);
+        let (access_token, access_jti) =
+            crate::token::access::Entity::create(client, &user, &refresh_record.scopes, &family, &txn, encoding_key)
+                .await?;
 
         let refresh_token = Self::create(
-            &access_token,
-            client_id,
+            &access_jti,
+            client,
             &refresh_record.user_id,
             &refresh_record.scopes,
+            &family,
             &txn,
         )
         .await?;
 
-        let user = crate::user::Entity::find_by_id(refresh_record.user_id)
-            .one(&txn)
-            .await?
-            .ok_or(DbErr::RecordNotFound(String::new()))?;
-
         txn.commit().await?;
-        Ok((access_token, refresh_token, refresh_record.scopes, user))
+        Ok(super::IssuedTokens {
+            access_token,
+            refresh_token,
+            scopes: refresh_record.scopes,
+            user,
+            sid: refresh_record.sid,
+            amr: super::parse_amr(refresh_record.amr.as_deref()),
+        })
     }
 
     pub async fn revoke(token: &str, client_id: &str, db: &DatabaseConnection) -> Result<bool, DbErr> {
@@ -115,14 +179,93 @@ impl Entity {
         }
 
         let txn = db.begin().await?;
+        Self::revoke_grant_of(&refresh_token, &txn).await?;
+        txn.commit().await?;
+        Ok(true)
+    }
 
-        // delete both access and refresh tokens
-        Self::delete_by_id(token).exec(&txn).await?;
-        crate::token::access::Entity::delete_by_id(&refresh_token.access_token)
-            .exec(&txn)
-            .await?;
+    // the grants a user has given, newest first. one row per grant, rotation replaces the token
+    pub async fn for_user(user_id: &str, db: &impl ConnectionTrait) -> Result<Vec<Model>, DbErr> {
+        Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .filter(Column::ExpiresAt.gt(Utc::now()))
+            .order_by_desc(Column::CreatedAt)
+            .all(db)
+            .await
+    }
 
-        txn.commit().await?;
+    // like `revoke`, but by the grant's id and only among the user's own
+    pub async fn revoke_grant(user_id: &str, grant_id: &str, db: &impl ConnectionTrait) -> Result<bool, DbErr> {
+        let Some(refresh_token) = Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .filter(
+                Condition::any()
+                    .add(Column::FamilyId.eq(grant_id))
+                    .add(Column::AccessJti.eq(grant_id)),
+            )
+            .one(db)
+            .await?
+        else {
+            return Ok(false);
+        };
+
+        Self::revoke_grant_of(&refresh_token, db).await?;
         Ok(true)
     }
+
+    // every grant the user gave a client, access tokens and unredeemed codes included
+    pub async fn revoke_client(user_id: &str, client_id: &str, db: &impl ConnectionTrait) -> Result<u64, DbErr> {
+        let result = Self::delete_many()
+            .filter(Column::UserId.eq(user_id))
+            .filter(Column::ClientId.eq(client_id))
+            .exec(db)
+            .await?;
+        crate::token::access::Entity::revoke_client(user_id, client_id, db).await?;
+        crate::token::auth::Entity::delete_many()
+            .filter(crate::token::auth::Column::UserId.eq(user_id))
+            .filter(crate::token::auth::Column::ClientId.eq(client_id))
+            .exec(db)
+            .await?;
+        Ok(result.rows_affected)
+    }
+
+    // the grants that came out of one sso session, for when it's ended from elsewhere
+    pub async fn revoke_session(sid: &str, db: &impl ConnectionTrait) -> Result<(), DbErr> {
+        let tokens = Self::find().filter(Column::Sid.eq(sid)).all(db).await?;
+        for token in &tokens {
+            Self::revoke_grant_of(token, db).await?;
+        }
+        crate::token::auth::Entity::delete_many()
+            .filter(crate::token::auth::Column::Sid.eq(sid))
+            .exec(db)
+            .await?;
+        Ok(())
+    }
+
+    // its family, or for rows from before families just it and the access token issued with it
+    async fn revoke_grant_of(refresh_token: &Model, db: &impl ConnectionTrait) -> Result<(), DbErr> {
+        match &refresh_token.family_id {
+            Some(family_id) => Self::revoke_family(family_id, db).await,
+            None => {
+                Self::delete_by_id(&refresh_token.token).exec(db).await?;
+                crate::token::access::Entity::delete_by_id(&refresh_token.access_jti)
+                    .exec(db)
+                    .await?;
+                Ok(())
+            }
+        }
+    }
+
+    // rfc 7009 2.1, the whole grant goes: every refresh and access token derived from the same code
+    pub async fn revoke_family(family_id: &str, db: &impl ConnectionTrait) -> Result<(), DbErr> {
+        Self::delete_many()
+            .filter(Column::FamilyId.eq(family_id))
+            .exec(db)
+            .await?;
+        crate::token::access::Entity::delete_many()
+            .filter(crate::token::access::Column::FamilyId.eq(family_id))
+            .exec(db)
+            .await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,65 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// password reset links. only a hash is stored, the token itself only exists in the email
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "password_resets")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub token_hash: String,
+    #[sea_orm(indexed)]
+    pub user_id: String,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            created_at: Set(Utc::now()),
+            expires_at: Set(Utc::now() + crate::lifetime::password_reset()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+fn hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+impl Entity {
+    // replaces any earlier link, returns the raw token to mail out
+    pub async fn create(user_id: &str, db: &impl ConnectionTrait) -> Result<String, DbErr> {
+        Self::delete_many().filter(Column::UserId.eq(user_id)).exec(db).await?;
+
+        let token = crate::util::generate_random_string(43);
+        let model = ActiveModel {
+            token_hash: Set(hash(&token)),
+            user_id: Set(user_id.to_string()),
+            ..Default::default()
+        };
+        model.insert(db).await?;
+        Ok(token)
+    }
+
+    pub async fn verify(token: &str, db: &impl ConnectionTrait) -> Result<Option<Model>, DbErr> {
+        Self::find_by_id(hash(token))
+            .filter(Column::ExpiresAt.gt(Utc::now()))
+            .one(db)
+            .await
+    }
+
+    // single use, whoever deletes the row gets to reset
+    pub async fn consume(token: &str, db: &impl ConnectionTrait) -> Result<Option<Model>, DbErr> {
+        let Some(reset) = Self::verify(token, db).await? else {
+            return Ok(None);
+        };
+        let deleted = Self::delete_by_id(&reset.token_hash).exec(db).await?;
+        Ok((deleted.rows_affected == 1).then_some(reset))
+    }
+}
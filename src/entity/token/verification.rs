@@ -0,0 +1,287 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// outstanding email verification links, the link itself is a signed jwt carrying this jti
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "email_verifications")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub jti: String,
+    #[sea_orm(indexed)]
+    pub user_id: String,
+    pub email: String,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            created_at: Set(Utc::now()),
+            expires_at: Set(Utc::now() + crate::lifetime::email_verification()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+crate::impl_verify!(Jti);
+
+impl Entity {
+    // replaces any earlier link, only the latest one works
+    pub async fn create(user_id: &str, email: &str, db: &impl ConnectionTrait) -> Result<Model, DbErr> {
+        Self::delete_many().filter(Column::UserId.eq(user_id)).exec(db).await?;
+
+        let model = ActiveModel {
+            jti: Set(uuid::Uuid::new_v4().to_string()),
+            user_id: Set(user_id.to_string()),
+            email: Set(email.to_string()),
+            ..Default::default()
+        };
+        model.insert(db).await
+    }
+
+    pub async fn pending(user_id: &str, email: &str, db: &impl ConnectionTrait) -> Result<Option<Model>, DbErr> {
+        Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .filter(Column::Email.eq(email))
+            .filter(Column::ExpiresAt.gt(Utc::now()))
+            .one(db)
+            .await
+    }
+
+    // single use, a second click finds nothing
+    pub async fn consume(jti: &str, db: &impl ConnectionTrait) -> Result<Option<Model>, DbErr> {
+        let Some(verification) = Self::verify(jti, db).await? else {
+            return Ok(None);
+        };
+        let deleted = Self::delete_by_id(jti).exec(db).await?;
+        Ok((deleted.rows_affected == 1).then_some(verification))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::verify_email::{self, VerifyParams};
+    use crate::mail::{Email, MemoryMailer};
+    use axum::{
+        Form,
+        extract::{Query, State},
+        http::{HeaderMap, StatusCode, header},
+    };
+    use std::sync::Arc;
+
+    struct Setup {
+        app_state: crate::AppState,
+        mailer: Arc<MemoryMailer>,
+        user: crate::user::Model,
+    }
+
+    async fn setup() -> Setup {
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        crate::db::setup(&db).await.unwrap();
+
+        let key = openssl::rsa::Rsa::generate(2048).unwrap();
+        let public_key = String::from_utf8(key.public_key_to_pem().unwrap()).unwrap();
+        let mailer = Arc::new(MemoryMailer::default());
+        let app_state = crate::AppState {
+            db,
+            password: crate::password::PasswordService::default(),
+            jwk: crate::jwt::jwk_from_pem(&key.private_key_to_pem().unwrap(), &public_key),
+            mailer: Some(mailer.clone()),
+        };
+
+        let user = crate::user::ActiveModel {
+            email: Set("ada@example.com".to_string()),
+            username: Set("ada".to_string()),
+            password_hash: Set(String::new()),
+            country: Set(Some("NO".to_string())),
+            ..Default::default()
+        }
+        .insert(&app_state.db)
+        .await
+        .unwrap();
+
+        Setup {
+            app_state,
+            mailer,
+            user,
+        }
+    }
+
+    // the mail goes out on a spawned task
+    async fn mailed(mailer: &MemoryMailer) -> Email {
+        for _ in 0..100 {
+            if let Some(email) = mailer.sent().pop() {
+                return email;
+            }
+            tokio::task::yield_now().await;
+        }
+        panic!("nothing was mailed");
+    }
+
+    fn link_token(email: &Email) -> String {
+        let (_, rest) = email.body.split_once("/verify-email?token=").unwrap();
+        let token = rest.split_whitespace().next().unwrap();
+        urlencoding::decode(token).unwrap().into_owned()
+    }
+
+    async fn confirm(token: &str, app_state: &crate::AppState) -> bool {
+        let params = VerifyParams {
+            token: token.to_string(),
+        };
+        verify_email::post(State(app_state.clone()), Form(params)).await.is_ok()
+    }
+
+    async fn is_verified(user_id: &str, db: &DatabaseConnection) -> bool {
+        let user = crate::user::Entity::find_by_id(user_id).one(db).await.unwrap().unwrap();
+        user.is_verified
+    }
+
+    #[tokio::test]
+    async fn only_the_latest_link_works() {
+        let Setup { app_state, user, .. } = setup().await;
+        let db = &app_state.db;
+
+        let first = Entity::create(&user.id, &user.email, db).await.unwrap();
+        let second = Entity::create(&user.id, &user.email, db).await.unwrap();
+        assert!(Entity::verify(&first.jti, db).await.unwrap().is_none());
+        assert!(Entity::verify(&second.jti, db).await.unwrap().is_some());
+
+        let pending = Entity::pending(&user.id, &user.email, db).await.unwrap().unwrap();
+        assert_eq!(pending.jti, second.jti);
+        assert!(
+            Entity::pending(&user.id, "other@example.com", db)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn links_work_once() {
+        let Setup {
+            app_state,
+            mailer,
+            user,
+        } = setup().await;
+
+        verify_email::send(&user, &app_state).await.unwrap();
+        let email = mailed(&mailer).await;
+        assert_eq!(email.to, user.email);
+        let token = link_token(&email);
+
+        // opening it only asks
+        let params = VerifyParams { token: token.clone() };
+        assert!(verify_email::get(Query(params), State(app_state.clone())).await.is_ok());
+        assert!(!is_verified(&user.id, &app_state.db).await);
+
+        assert!(confirm(&token, &app_state).await);
+        assert!(is_verified(&user.id, &app_state.db).await);
+
+        assert!(!confirm(&token, &app_state).await);
+    }
+
+    #[tokio::test]
+    async fn expired_links_are_refused() {
+        let Setup { app_state, user, .. } = setup().await;
+        let db = &app_state.db;
+
+        let verification = ActiveModel {
+            jti: Set("expired".to_string()),
+            user_id: Set(user.id.clone()),
+            email: Set(user.email.clone()),
+            expires_at: Set(Utc::now() - chrono::Duration::hours(1)),
+            ..Default::default()
+        }
+        .insert(db)
+        .await
+        .unwrap();
+        assert!(Entity::verify(&verification.jti, db).await.unwrap().is_none());
+        assert!(Entity::consume(&verification.jti, db).await.unwrap().is_none());
+
+        let token = crate::jwt::create_email_verification(
+            &user.id,
+            &user.email,
+            &verification.jti,
+            verification.expires_at,
+            &app_state.jwk.encoding_key,
+        )
+        .unwrap();
+        assert!(!confirm(&token, &app_state).await);
+        assert!(!is_verified(&user.id, db).await);
+    }
+
+    #[tokio::test]
+    async fn links_are_for_the_address_they_were_sent_to() {
+        let Setup {
+            app_state,
+            mailer,
+            user,
+        } = setup().await;
+
+        verify_email::send(&user, &app_state).await.unwrap();
+        let token = link_token(&mailed(&mailer).await);
+
+        let mut update: crate::user::ActiveModel = user.clone().into();
+        update.email = Set("grace@example.com".to_string());
+        update.update(&app_state.db).await.unwrap();
+
+        assert!(!confirm(&token, &app_state).await);
+        assert!(!is_verified(&user.id, &app_state.db).await);
+    }
+
+    #[tokio::test]
+    async fn clients_can_require_a_verified_email() {
+        let Setup {
+            app_state,
+            mailer,
+            user,
+        } = setup().await;
+
+        let client = crate::client::Entity::find_by_id("sjallabong-main")
+            .one(&app_state.db)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut update: crate::client::ActiveModel = client.into();
+        update.require_verified_email = Set(true);
+        let client = update.update(&app_state.db).await.unwrap();
+
+        let oauth = crate::handler::auth::OAuthParams {
+            client_id: client.client_id.clone(),
+            redirect_uri: "https://sjallabong.eu/auth/callback".to_string(),
+            state: "state".to_string(),
+            scope: "openid".to_string(),
+            code_challenge: "challenge".to_string(),
+            code_challenge_method: "S256".to_string(),
+            prompt: None,
+        };
+        let sign_in = async |user: &crate::user::Model| {
+            let ip = "127.0.0.1".to_string();
+            crate::handler::auth::sign_in(user, &["pwd"], &oauth, &client, ip, &HeaderMap::new(), &app_state)
+                .await
+                .unwrap()
+        };
+
+        // no code, a link instead
+        let response = sign_in(&user).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let token = link_token(&mailed(&mailer).await);
+
+        assert!(confirm(&token, &app_state).await);
+        let user = crate::user::Entity::find_by_id(&user.id)
+            .one(&app_state.db)
+            .await
+            .unwrap()
+            .unwrap();
+        let response = sign_in(&user).await;
+        assert!(response.status().is_redirection());
+        let location = response.headers()[header::LOCATION].to_str().unwrap();
+        assert!(location.contains("code="));
+    }
+}
@@ -0,0 +1,59 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+pub const REGISTER: &str = "register";
+// passwordless, we don't know who it is until the assertion comes back
+pub const LOGIN: &str = "login";
+// a security key after the password, for a known user
+pub const SECOND_FACTOR: &str = "second_factor";
+
+// webauthn ceremony challenges, each good for one create() or get()
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "webauthn_challenges")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub challenge: String,
+    pub purpose: String,
+    pub user_id: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            created_at: Set(Utc::now()),
+            expires_at: Set(Utc::now() + crate::lifetime::mfa_challenge()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+crate::impl_verify!(Challenge);
+
+impl Entity {
+    pub async fn create(purpose: &str, user_id: Option<&str>, db: &impl ConnectionTrait) -> Result<Model, DbErr> {
+        let model = ActiveModel {
+            challenge: Set(crate::util::generate_random_string(43)),
+            purpose: Set(purpose.to_string()),
+            user_id: Set(user_id.map(str::to_string)),
+            ..Default::default()
+        };
+        model.insert(db).await
+    }
+
+    pub async fn consume(challenge: &str, purpose: &str, db: &impl ConnectionTrait) -> Result<Option<Model>, DbErr> {
+        let Some(model) = Self::verify(challenge, db).await? else {
+            return Ok(None);
+        };
+        if model.purpose != purpose {
+            return Ok(None);
+        }
+        let deleted = Self::delete_by_id(challenge).exec(db).await?;
+        Ok((deleted.rows_affected == 1).then_some(model))
+    }
+}
@@ -1,6 +1,7 @@
 use chrono::{DateTime, Utc};
 use sea_orm::*;
 use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
 
 #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "users")]
@@ -13,26 +14,49 @@ pub struct Model {
     pub email: String,
     #[sea_orm(unique)]
     pub username: String,
+    // lookup and uniqueness keys, see `normalize`. only None for accounts that collided when these were
+    // added, until they're renamed
+    #[serde(skip_serializing)]
+    pub username_normalized: Option<String>,
+    #[serde(skip_serializing)]
+    pub email_normalized: Option<String>,
+    #[serde(skip_serializing)]
     pub password_hash: String,
+    // false for accounts made through an upstream provider, whose hash is random, until a reset sets one
+    #[sea_orm(default_value = true)]
+    pub has_password: bool,
     pub country: Option<String>,
     pub avatar_url: Option<String>,
     pub bio: Option<String>,
 
-    // admin editable
-    pub is_moderator: bool,
-    pub is_admin: bool,
-
+    // admin editable. roles are in `user_role`
     pub is_active: bool,
-    pub is_member: bool,
     pub is_verified: bool,
+
+    // totp seed, encrypted with `crypto`. pending until the first code checks out
+    #[serde(skip_serializing)]
+    pub totp_secret: Option<String>,
+    #[serde(skip_serializing)]
+    pub totp_pending_secret: Option<String>,
+    pub totp_enabled_at: Option<DateTime<Utc>>,
+    // last accepted time step, so a code can't be replayed
+    #[serde(skip_serializing)]
+    pub totp_last_step: Option<i64>,
+
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub last_login_at: Option<DateTime<Utc>>,
+
+    // set while a deletion request waits out its grace period, the account is disabled until then
+    pub delete_after: Option<DateTime<Utc>>,
+    // when the personal data was purged. the row stays so the id, and every client's sub, isn't reused
+    pub deleted_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {}
 
+#[async_trait::async_trait]
 impl ActiveModelBehavior for ActiveModel {
     fn new() -> Self {
         Self {
@@ -42,18 +66,288 @@ impl ActiveModelBehavior for ActiveModel {
             last_login_at: Set(None),
 
             // defaults
-            is_moderator: Set(false),
-            is_admin: Set(false),
             is_active: Set(true),
-            is_member: Set(false),
             is_verified: Set(false),
+            has_password: Set(true),
 
             ..ActiveModelTrait::default()
         }
     }
+
+    // keeps the lookup keys in step with whatever was typed
+    async fn before_save<C: ConnectionTrait>(mut self, _db: &C, _insert: bool) -> Result<Self, DbErr> {
+        if let ActiveValue::Set(username) = &self.username {
+            self.username_normalized = Set(Some(normalize(username)));
+        }
+        if let ActiveValue::Set(email) = &self.email {
+            self.email_normalized = Set(Some(normalize(email)));
+        }
+        Ok(self)
+    }
+}
+
+// "Bob", "bob " and "ｂｏｂ" are the same account. nfkc, then case folded, then nfkc again since
+// lowercasing can undo it
+pub fn normalize(identifier: &str) -> String {
+    identifier
+        .trim()
+        .nfkc()
+        .collect::<String>()
+        .to_lowercase()
+        .nfkc()
+        .collect()
+}
+
+// aad for the encrypted totp seeds, ties them to the user
+pub fn totp_context(user_id: &str) -> String {
+    format!("totp:{user_id}")
+}
+
+impl Model {
+    pub fn has_totp(&self) -> bool {
+        self.totp_enabled_at.is_some() && self.totp_secret.is_some()
+    }
 }
 
 impl Entity {
+    // the login form takes either
+    pub async fn find_by_login(login: &str, db: &impl ConnectionTrait) -> Result<Option<Model>, DbErr> {
+        let key = normalize(login);
+        if let Some(user) = Self::find().filter(Column::UsernameNormalized.eq(&key)).one(db).await? {
+            return Ok(Some(user));
+        }
+        if let Some(user) = Self::find().filter(Column::EmailNormalized.eq(&key)).one(db).await? {
+            return Ok(Some(user));
+        }
+        // accounts that collided only answer to their exact name
+        Self::find()
+            .filter(
+                Condition::any()
+                    .add(Column::Username.eq(login.trim()))
+                    .add(Column::Email.eq(login.trim())),
+            )
+            .one(db)
+            .await
+    }
+
+    pub async fn find_by_email(email: &str, db: &impl ConnectionTrait) -> Result<Option<Model>, DbErr> {
+        Self::find()
+            .filter(
+                Condition::any()
+                    .add(Column::EmailNormalized.eq(normalize(email)))
+                    .add(Column::Email.eq(email.trim())),
+            )
+            .one(db)
+            .await
+    }
+
+    // whether someone else already has it, in any casing
+    // someone's email counts too, `find_by_login` would pick the username over it
+    pub async fn username_taken(
+        username: &str,
+        except_id: Option<&str>,
+        db: &impl ConnectionTrait,
+    ) -> Result<bool, DbErr> {
+        let mut query = Self::find().filter(
+            Condition::any()
+                .add(Column::UsernameNormalized.eq(normalize(username)))
+                .add(Column::Username.eq(username))
+                .add(Column::EmailNormalized.eq(normalize(username))),
+        );
+        if let Some(id) = except_id {
+            query = query.filter(Column::Id.ne(id));
+        }
+        Ok(query.one(db).await?.is_some())
+    }
+
+    pub async fn email_taken(email: &str, except_id: Option<&str>, db: &impl ConnectionTrait) -> Result<bool, DbErr> {
+        let mut query = Self::find().filter(
+            Condition::any()
+                .add(Column::EmailNormalized.eq(normalize(email)))
+                .add(Column::Email.eq(email)),
+        );
+        if let Some(id) = except_id {
+            query = query.filter(Column::Id.ne(id));
+        }
+        Ok(query.one(db).await?.is_some())
+    }
+
+    // staff can't get tokens on a password alone. anyone holding a permission of ours counts
+    pub async fn requires_mfa(user: &Model, db: &impl ConnectionTrait) -> Result<bool, DbErr> {
+        let grants = crate::user_role::Entity::grants(&user.id, None, db).await?;
+        Ok(!grants.permissions.is_empty())
+    }
+
+    // totp or a passkey, either means login takes a second step
+    pub async fn has_second_factor(user: &Model, db: &impl ConnectionTrait) -> Result<bool, DbErr> {
+        Ok(user.has_totp() || crate::passkey::Entity::exists_for_user(&user.id, db).await?)
+    }
+
+    // why the account can't be used right now, if it can't. shown to the user as is
+    pub async fn restriction(user: &Model, db: &impl ConnectionTrait) -> Result<Option<String>, DbErr> {
+        if !user.is_active {
+            return Ok(Some("This account has been deactivated".to_string()));
+        }
+        Ok(crate::suspension::Entity::active_for(&user.id, db)
+            .await?
+            .map(|suspension| suspension.message()))
+    }
+
+    // ways to get in without anyone's help: the password, any passkey, each linked provider. removing
+    // the last one would strand the account
+    pub async fn sign_in_methods(user: &Model, db: &impl ConnectionTrait) -> Result<u64, DbErr> {
+        let passkeys = crate::passkey::Entity::exists_for_user(&user.id, db).await?;
+        let identities = crate::federated_identity::Entity::find()
+            .filter(crate::federated_identity::Column::UserId.eq(&user.id))
+            .count(db)
+            .await?;
+        Ok(u64::from(user.has_password) + u64::from(passkeys) + identities)
+    }
+
+    // checks a code against the active seed and burns its time step
+    pub async fn check_totp(user: &Model, code: &str, db: &impl ConnectionTrait) -> anyhow::Result<bool> {
+        let Some(secret) = &user.totp_secret else {
+            return Ok(false);
+        };
+        let secret = crate::crypto::decrypt(secret, &totp_context(&user.id))?;
+        let Some(step) = crate::totp::verify(secret, &user.username, code, user.totp_last_step)? else {
+            return Ok(false);
+        };
+
+        // conditional, so two requests racing with the same code can't both get in
+        let result = Self::update_many()
+            .col_expr(Column::TotpLastStep, sea_query::Expr::value(step))
+            .filter(Column::Id.eq(&user.id))
+            .filter(
+                Condition::any()
+                    .add(Column::TotpLastStep.is_null())
+                    .add(Column::TotpLastStep.lt(step)),
+            )
+            .exec(db)
+            .await?;
+        Ok(result.rows_affected == 1)
+    }
+
+    // wipes an account whose grace period ran out: everything tied to it goes, the row keeps only its id
+    pub async fn purge(user_id: &str, db: &impl ConnectionTrait) -> Result<(), DbErr> {
+        use crate::token;
+
+        token::revoke_all_for_user(user_id, None, db).await?;
+        token::verification::Entity::delete_many()
+            .filter(token::verification::Column::UserId.eq(user_id))
+            .exec(db)
+            .await?;
+        token::reset::Entity::delete_many()
+            .filter(token::reset::Column::UserId.eq(user_id))
+            .exec(db)
+            .await?;
+        token::mfa::Entity::delete_many()
+            .filter(token::mfa::Column::UserId.eq(user_id))
+            .exec(db)
+            .await?;
+        token::webauthn::Entity::delete_many()
+            .filter(token::webauthn::Column::UserId.eq(user_id))
+            .exec(db)
+            .await?;
+        token::federation::Entity::delete_many()
+            .filter(token::federation::Column::LinkUserId.eq(user_id))
+            .exec(db)
+            .await?;
+        crate::session::Entity::end_all(user_id, None, db).await?;
+        crate::passkey::Entity::delete_many()
+            .filter(crate::passkey::Column::UserId.eq(user_id))
+            .exec(db)
+            .await?;
+        crate::recovery_code::Entity::delete_many()
+            .filter(crate::recovery_code::Column::UserId.eq(user_id))
+            .exec(db)
+            .await?;
+        crate::federated_identity::Entity::delete_many()
+            .filter(crate::federated_identity::Column::UserId.eq(user_id))
+            .exec(db)
+            .await?;
+        crate::subject::Entity::delete_many()
+            .filter(crate::subject::Column::UserId.eq(user_id))
+            .exec(db)
+            .await?;
+        crate::login_attempt::Entity::delete_many()
+            .filter(crate::login_attempt::Column::UserId.eq(user_id))
+            .exec(db)
+            .await?;
+        crate::suspension::Entity::delete_many()
+            .filter(crate::suspension::Column::UserId.eq(user_id))
+            .exec(db)
+            .await?;
+        crate::security_event::Entity::delete_many()
+            .filter(crate::security_event::Column::UserId.eq(user_id))
+            .exec(db)
+            .await?;
+        crate::user_role::Entity::delete_many()
+            .filter(crate::user_role::Column::UserId.eq(user_id))
+            .exec(db)
+            .await?;
+        crate::group_member::Entity::remove_user(user_id, db).await?;
+        crate::membership::Entity::delete_many()
+            .filter(crate::membership::Column::UserId.eq(user_id))
+            .exec(db)
+            .await?;
+        crate::invite::Entity::delete_many()
+            .filter(crate::invite::Column::CreatedBy.eq(user_id))
+            .exec(db)
+            .await?;
+
+        let placeholder = format!("deleted-{user_id}");
+        let user = ActiveModel {
+            id: Unchanged(user_id.to_string()),
+            email: Set(format!("{placeholder}@invalid")),
+            username: Set(placeholder),
+            password_hash: Set(String::new()),
+            has_password: Set(false),
+            country: Set(None),
+            avatar_url: Set(None),
+            bio: Set(None),
+            is_active: Set(false),
+            is_verified: Set(false),
+            totp_secret: Set(None),
+            totp_pending_secret: Set(None),
+            totp_enabled_at: Set(None),
+            totp_last_step: Set(None),
+            last_login_at: Set(None),
+            delete_after: Set(None),
+            deleted_at: Set(Some(Utc::now())),
+            updated_at: Set(Utc::now()),
+            ..Default::default()
+        };
+        user.update(db).await?;
+        Ok(())
+    }
+
+    // every account due, one transaction each so a failure leaves the rest to the next run
+    pub async fn purge_due(db: &DatabaseConnection) -> Result<u64, DbErr> {
+        let due = Self::find()
+            .filter(Column::DeleteAfter.lte(Utc::now()))
+            .filter(Column::DeletedAt.is_null())
+            .all(db)
+            .await?;
+
+        for user in &due {
+            let txn = db.begin().await?;
+            Self::purge(&user.id, &txn).await?;
+            txn.commit().await?;
+            tracing::info!(user_id = %user.id, "Account purged");
+        }
+        Ok(due.len() as u64)
+    }
+
+    pub async fn logged_in(user_id: &str, db: &impl ConnectionTrait) -> Result<(), DbErr> {
+        Self::update_many()
+            .col_expr(Column::LastLoginAt, sea_query::Expr::value(Some(Utc::now())))
+            .filter(Column::Id.eq(user_id))
+            .exec(db)
+            .await?;
+        Ok(())
+    }
+
     pub async fn update_country(user_id: &str, country: &str, db: &DatabaseConnection) -> Result<(), DbErr> {
         let mut user: ActiveModel = Self::find_by_id(user_id).one(db).await?.unwrap().into();
         user.country = Set(Some(country.to_string()));
@@ -0,0 +1,142 @@
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// a role given to a user, everywhere or only in one client's tokens
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "user_roles")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: String,
+    #[sea_orm(indexed)]
+    #[serde(skip_serializing)]
+    pub user_id: String,
+    pub role: String,
+    // None for everywhere. only global ones count for our own permission checks
+    pub client_id: Option<String>,
+    // None for ones carried over from the old flags
+    #[serde(skip_serializing)]
+    pub granted_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {
+    fn new() -> Self {
+        Self {
+            id: Set(uuid::Uuid::new_v4().to_string()),
+            created_at: Set(Utc::now()),
+            ..ActiveModelTrait::default()
+        }
+    }
+}
+
+// what a user holds in one place, sorted and without duplicates
+#[derive(Clone, Debug, Default)]
+pub struct Grants {
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+}
+
+impl Grants {
+    pub fn can(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|p| p == permission)
+    }
+}
+
+fn scope(client_id: Option<&str>) -> Condition {
+    match client_id {
+        Some(client_id) => Condition::all().add(Column::ClientId.eq(client_id)),
+        None => Condition::all().add(Column::ClientId.is_null()),
+    }
+}
+
+impl Entity {
+    pub async fn for_user(user_id: &str, db: &impl ConnectionTrait) -> Result<Vec<Model>, DbErr> {
+        Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .order_by_asc(Column::CreatedAt)
+            .all(db)
+            .await
+    }
+
+    // the global ones, plus those scoped to `client_id` if given
+    pub async fn grants(user_id: &str, client_id: Option<&str>, db: &impl ConnectionTrait) -> Result<Grants, DbErr> {
+        let mut condition = Condition::any().add(Column::ClientId.is_null());
+        if let Some(client_id) = client_id {
+            condition = condition.add(Column::ClientId.eq(client_id));
+        }
+
+        let mut roles: Vec<String> = Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .filter(condition)
+            .all(db)
+            .await?
+            .into_iter()
+            .map(|assignment| assignment.role)
+            .collect();
+        roles.sort();
+        roles.dedup();
+        if roles.is_empty() {
+            return Ok(Grants::default());
+        }
+
+        let mut permissions: Vec<String> = crate::permission::Entity::find()
+            .filter(crate::permission::Column::Role.is_in(roles.clone()))
+            .all(db)
+            .await?
+            .into_iter()
+            .map(|permission| permission.permission)
+            .collect();
+        permissions.sort();
+        permissions.dedup();
+
+        Ok(Grants { roles, permissions })
+    }
+
+    // false if they already had it
+    pub async fn assign(
+        user_id: &str,
+        role: &str,
+        client_id: Option<&str>,
+        granted_by: Option<&str>,
+        db: &impl ConnectionTrait,
+    ) -> Result<bool, DbErr> {
+        let existing = Self::find()
+            .filter(Column::UserId.eq(user_id))
+            .filter(Column::Role.eq(role))
+            .filter(scope(client_id))
+            .one(db)
+            .await?;
+        if existing.is_some() {
+            return Ok(false);
+        }
+
+        let model = ActiveModel {
+            user_id: Set(user_id.to_string()),
+            role: Set(role.to_string()),
+            client_id: Set(client_id.map(str::to_string)),
+            granted_by: Set(granted_by.map(str::to_string)),
+            ..Default::default()
+        };
+        model.insert(db).await?;
+        Ok(true)
+    }
+
+    pub async fn unassign(
+        user_id: &str,
+        role: &str,
+        client_id: Option<&str>,
+        db: &impl ConnectionTrait,
+    ) -> Result<bool, DbErr> {
+        let result = Self::delete_many()
+            .filter(Column::UserId.eq(user_id))
+            .filter(Column::Role.eq(role))
+            .filter(scope(client_id))
+            .exec(db)
+            .await?;
+        Ok(result.rows_affected > 0)
+    }
+}
@@ -0,0 +1,582 @@
+use anyhow::{Context, Result, bail, ensure};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, jwk::JwkSet};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::sync::LazyLock;
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+// upstream identity providers, "continue with x". we're the oauth client here, with pkce, state and
+// nonce kept server side in `token::federation`
+
+// which claim holds what, for providers that aren't plain oidc
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ClaimMapping {
+    pub subject: String,
+    pub email: String,
+    pub email_verified: String,
+    pub username: String,
+}
+
+impl Default for ClaimMapping {
+    fn default() -> Self {
+        Self {
+            subject: "sub".to_string(),
+            email: "email".to_string(),
+            email_verified: "email_verified".to_string(),
+            username: "preferred_username".to_string(),
+        }
+    }
+}
+
+fn default_scopes() -> Vec<String> {
+    vec!["openid".to_string(), "email".to_string(), "profile".to_string()]
+}
+
+#[derive(Deserialize)]
+pub struct Provider {
+    pub id: String,
+    pub name: String,
+    // discovered from here, unless the authorization and token endpoints are given
+    #[serde(default)]
+    issuer: Option<String>,
+    #[serde(default)]
+    authorization_endpoint: Option<String>,
+    #[serde(default)]
+    token_endpoint: Option<String>,
+    #[serde(default)]
+    userinfo_endpoint: Option<String>,
+    #[serde(default)]
+    jwks_uri: Option<String>,
+    client_id: String,
+    #[serde(default)]
+    client_secret: Option<String>,
+    // name of an env var with the secret, to keep it out of the file
+    #[serde(default)]
+    client_secret_env: Option<String>,
+    #[serde(default = "default_scopes")]
+    scopes: Vec<String>,
+    #[serde(default)]
+    claims: ClaimMapping,
+    #[serde(skip)]
+    endpoints: OnceCell<Endpoints>,
+}
+
+struct Endpoints {
+    issuer: Option<String>,
+    authorization: String,
+    token: String,
+    userinfo: Option<String>,
+    jwks_uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+    jwks_uri: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: Option<String>,
+}
+
+// who the provider says signed in
+#[derive(Debug)]
+pub struct Identity {
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub username: Option<String>,
+}
+
+static HTTP: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Failed to build http client")
+});
+
+static PROVIDERS: LazyLock<Vec<Provider>> = LazyLock::new(|| {
+    let path = std::env::var("PROVIDERS_FILE").unwrap_or_else(|_| "providers.json".to_string());
+    let Ok(json) = std::fs::read_to_string(&path) else {
+        return Vec::new();
+    };
+    serde_json::from_str(&json).unwrap_or_else(|e| panic!("Invalid {path}: {e}"))
+});
+
+pub fn providers() -> &'static [Provider] {
+    &PROVIDERS
+}
+
+pub fn provider(id: &str) -> Option<&'static Provider> {
+    PROVIDERS.iter().find(|provider| provider.id == id)
+}
+
+// one callback for every provider, the state says which
+pub fn redirect_uri() -> String {
+    format!("{}/authorize/federated/callback", crate::jwt::issuer())
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(request: reqwest::RequestBuilder) -> Result<T> {
+    let response = request.send().await?;
+    let status = response.status();
+    let body = response.text().await?;
+    ensure!(status.is_success(), "{status}: {body}");
+    Ok(serde_json::from_str(&body)?)
+}
+
+// "true", true, or absent
+fn truthy(value: Option<&Value>) -> bool {
+    match value {
+        Some(Value::Bool(b)) => *b,
+        Some(Value::String(s)) => s == "true",
+        _ => false,
+    }
+}
+
+fn string_claim(claims: &Map<String, Value>, name: &str) -> Option<String> {
+    match claims.get(name)? {
+        Value::String(s) if !s.is_empty() => Some(s.clone()),
+        // discord and github ids are numbers
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+impl Provider {
+    fn client_secret(&self) -> Option<String> {
+        match &self.client_secret_env {
+            Some(var) => std::env::var(var).ok(),
+            None => self.client_secret.clone(),
+        }
+    }
+
+    async fn endpoints(&self) -> Result<&Endpoints> {
+        self.endpoints
+            .get_or_try_init(|| async {
+                if let (Some(authorization), Some(token)) = (&self.authorization_endpoint, &self.token_endpoint) {
+                    return Ok(Endpoints {
+                        issuer: self.issuer.clone(),
+                        authorization: authorization.clone(),
+                        token: token.clone(),
+                        userinfo: self.userinfo_endpoint.clone(),
+                        jwks_uri: self.jwks_uri.clone(),
+                    });
+                }
+
+                let issuer = self
+                    .issuer
+                    .as_deref()
+                    .context("Provider needs an issuer or endpoints")?;
+                let url = format!("{}/.well-known/openid-configuration", issuer.trim_end_matches('/'));
+                let discovery: Discovery = get_json(HTTP.get(&url)).await.context("Discovery failed")?;
+                ensure!(
+                    discovery.issuer.trim_end_matches('/') == issuer.trim_end_matches('/'),
+                    "Discovery issuer mismatch: {}",
+                    discovery.issuer
+                );
+
+                Ok(Endpoints {
+                    issuer: Some(discovery.issuer),
+                    authorization: discovery.authorization_endpoint,
+                    token: discovery.token_endpoint,
+                    userinfo: self.userinfo_endpoint.clone().or(discovery.userinfo_endpoint),
+                    jwks_uri: self.jwks_uri.clone().or(discovery.jwks_uri),
+                })
+            })
+            .await
+    }
+
+    pub async fn authorization_url(&self, state: &crate::token::federation::Model) -> Result<String> {
+        let endpoints = self.endpoints().await?;
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(state.code_verifier.as_bytes()));
+
+        let mut url = url::Url::parse(&endpoints.authorization).context("Invalid authorization endpoint")?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &redirect_uri())
+            .append_pair("scope", &self.scopes.join(" "))
+            .append_pair("state", &state.state)
+            .append_pair("nonce", &state.nonce)
+            .append_pair("code_challenge", &code_challenge)
+            .append_pair("code_challenge_method", "S256");
+        Ok(url.into())
+    }
+
+    // trades the code for tokens, and the tokens for who it is
+    pub async fn identity(&self, code: &str, state: &crate::token::federation::Model) -> Result<Identity> {
+        let endpoints = self.endpoints().await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code".to_string()),
+            ("code", code.to_string()),
+            ("redirect_uri", redirect_uri()),
+            ("client_id", self.client_id.clone()),
+            ("code_verifier", state.code_verifier.clone()),
+        ];
+        if let Some(secret) = self.client_secret() {
+            form.push(("client_secret", secret));
+        }
+        let tokens: TokenResponse = get_json(
+            HTTP.post(&endpoints.token)
+                .header(reqwest::header::ACCEPT, "application/json")
+                .form(&form),
+        )
+        .await
+        .context("Token exchange failed")?;
+
+        let mut claims = match &tokens.id_token {
+            Some(id_token) => self.verify_id_token(endpoints, id_token, &state.nonce).await?,
+            None => Map::new(),
+        };
+
+        if let Some(userinfo) = &endpoints.userinfo {
+            let info: Map<String, Value> = get_json(HTTP.get(userinfo).bearer_auth(&tokens.access_token))
+                .await
+                .context("Userinfo failed")?;
+            // oidc core 5.3.2, userinfo has to be about the same person as the id token
+            if let Some(sub) = claims.get("sub")
+                && info.get("sub").is_some_and(|s| s != sub)
+            {
+                bail!("Userinfo sub doesn't match the id token");
+            }
+            claims.extend(info);
+        }
+
+        let subject = string_claim(&claims, &self.claims.subject).context("Provider didn't say who signed in")?;
+        Ok(Identity {
+            subject,
+            email: string_claim(&claims, &self.claims.email),
+            email_verified: truthy(claims.get(&self.claims.email_verified)),
+            username: string_claim(&claims, &self.claims.username),
+        })
+    }
+
+    async fn verify_id_token(&self, endpoints: &Endpoints, id_token: &str, nonce: &str) -> Result<Map<String, Value>> {
+        let jwks_uri = endpoints
+            .jwks_uri
+            .as_deref()
+            .context("No jwks_uri to check the id token with")?;
+        let jwks: JwkSet = get_json(HTTP.get(jwks_uri)).await.context("Fetching jwks failed")?;
+
+        let header = jsonwebtoken::decode_header(id_token)?;
+        // keys are public, an hmac "signature" with one proves nothing
+        ensure!(
+            !matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512),
+            "Symmetric id token"
+        );
+        let jwk = match &header.kid {
+            Some(kid) => jwks.find(kid),
+            None => jwks.keys.first(),
+        }
+        .context("Unknown id token key")?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.client_id]);
+        if let Some(issuer) = &endpoints.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        let claims =
+            jsonwebtoken::decode::<Map<String, Value>>(id_token, &DecodingKey::from_jwk(jwk)?, &validation)?.claims;
+
+        ensure!(
+            claims.get("nonce").and_then(Value::as_str) == Some(nonce),
+            "Nonce mismatch"
+        );
+        Ok(claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        Form, Json, Router,
+        extract::{Query, State},
+        http::{HeaderMap, StatusCode, header},
+        response::{IntoResponse, Redirect, Response},
+        routing::{get, post},
+    };
+    use chrono::Utc;
+    use jsonwebtoken::{EncodingKey, Header};
+    use openssl::{pkey::Private, rsa::Rsa};
+    use serde_json::json;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    const CLIENT_ID: &str = "auth-test";
+    const SUBJECT: &str = "mock-user-1";
+    const ACCESS_TOKEN: &str = "mock-access-token";
+
+    #[derive(Clone, Copy, Default)]
+    struct Misbehaviour {
+        // discovery names another issuer
+        wrong_issuer: bool,
+        // id tokens signed by a key that isn't in the jwks
+        foreign_key: bool,
+        // id tokens "signed" with hs256
+        symmetric: bool,
+    }
+
+    // a local oidc provider: discovery, authorize, token, userinfo and jwks
+    struct Mock {
+        issuer: String,
+        misbehaviour: Misbehaviour,
+        key: Rsa<Private>,
+        // what /authorize was asked for, by code: the pkce challenge and the nonce
+        codes: Mutex<HashMap<String, (String, String)>>,
+    }
+
+    async fn discovery(State(mock): State<Arc<Mock>>) -> Json<Value> {
+        let issuer = if mock.misbehaviour.wrong_issuer {
+            "https://elsewhere.example"
+        } else {
+            &mock.issuer
+        };
+        Json(json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", mock.issuer),
+            "token_endpoint": format!("{}/token", mock.issuer),
+            "userinfo_endpoint": format!("{}/userinfo", mock.issuer),
+            "jwks_uri": format!("{}/jwks", mock.issuer),
+        }))
+    }
+
+    // approves straight away and sends the browser back with a code
+    async fn authorize(State(mock): State<Arc<Mock>>, Query(query): Query<HashMap<String, String>>) -> Response {
+        if query.get("response_type").map(String::as_str) != Some("code")
+            || query.get("client_id").map(String::as_str) != Some(CLIENT_ID)
+            || query.get("code_challenge_method").map(String::as_str) != Some("S256")
+        {
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+        let code = crate::util::generate_random_string(16);
+        mock.codes
+            .lock()
+            .unwrap()
+            .insert(code.clone(), (query["code_challenge"].clone(), query["nonce"].clone()));
+
+        let mut url = url::Url::parse(&query["redirect_uri"]).unwrap();
+        url.query_pairs_mut()
+            .append_pair("code", &code)
+            .append_pair("state", &query["state"]);
+        Redirect::to(url.as_str()).into_response()
+    }
+
+    async fn token(State(mock): State<Arc<Mock>>, Form(form): Form<HashMap<String, String>>) -> Response {
+        let Some((challenge, nonce)) = mock.codes.lock().unwrap().remove(&form["code"]) else {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "invalid_grant" }))).into_response();
+        };
+        let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+        if URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) != challenge
+            || form.get("client_id").map(String::as_str) != Some(CLIENT_ID)
+            || form.get("redirect_uri") != Some(&redirect_uri())
+        {
+            return (StatusCode::BAD_REQUEST, Json(json!({ "error": "invalid_grant" }))).into_response();
+        }
+
+        let now = Utc::now().timestamp();
+        let claims = json!({
+            "iss": mock.issuer,
+            "aud": CLIENT_ID,
+            "sub": SUBJECT,
+            "iat": now,
+            "exp": now + 300,
+            "nonce": nonce,
+            "email": "ada@example.com",
+            "email_verified": true,
+        });
+        let id_token = if mock.misbehaviour.symmetric {
+            jsonwebtoken::encode(
+                &Header::new(Algorithm::HS256),
+                &claims,
+                &EncodingKey::from_secret(b"anyone can make these"),
+            )
+        } else {
+            let key = if mock.misbehaviour.foreign_key {
+                Rsa::generate(2048).unwrap()
+            } else {
+                mock.key.clone()
+            };
+            let mut header = Header::new(Algorithm::RS256);
+            header.kid = Some("mock".to_string());
+            jsonwebtoken::encode(
+                &header,
+                &claims,
+                &EncodingKey::from_rsa_pem(&key.private_key_to_pem().unwrap()).unwrap(),
+            )
+        }
+        .unwrap();
+
+        Json(json!({ "access_token": ACCESS_TOKEN, "token_type": "Bearer", "id_token": id_token })).into_response()
+    }
+
+    async fn userinfo(headers: HeaderMap) -> Response {
+        let bearer = format!("Bearer {ACCESS_TOKEN}");
+        if headers.get(header::AUTHORIZATION).and_then(|h| h.to_str().ok()) != Some(bearer.as_str()) {
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+        Json(json!({ "sub": SUBJECT, "preferred_username": "ada" })).into_response()
+    }
+
+    async fn jwks(State(mock): State<Arc<Mock>>) -> Json<Value> {
+        Json(json!({ "keys": [{
+            "kty": "RSA",
+            "kid": "mock",
+            "alg": "RS256",
+            "use": "sig",
+            "n": URL_SAFE_NO_PAD.encode(mock.key.n().to_vec()),
+            "e": URL_SAFE_NO_PAD.encode(mock.key.e().to_vec()),
+        }]}))
+    }
+
+    // starts a mock on a free port, and a provider entry pointing at it as providers.json would
+    async fn mock_provider(misbehaviour: Misbehaviour) -> Provider {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let mock = Arc::new(Mock {
+            issuer: issuer.clone(),
+            misbehaviour,
+            key: Rsa::generate(2048).unwrap(),
+            codes: Mutex::new(HashMap::new()),
+        });
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/authorize", get(authorize))
+            .route("/token", post(token))
+            .route("/userinfo", get(userinfo))
+            .route("/jwks", get(jwks))
+            .with_state(mock);
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        serde_json::from_value(json!({ "id": "mock", "name": "Mock", "issuer": issuer, "client_id": CLIENT_ID }))
+            .unwrap()
+    }
+
+    fn federation_state() -> crate::token::federation::Model {
+        crate::token::federation::Model {
+            state: crate::util::generate_random_string(43),
+            provider: "mock".to_string(),
+            nonce: crate::util::generate_random_string(32),
+            code_verifier: crate::util::generate_random_string(64),
+            oauth: String::new(),
+            link_user_id: None,
+            expires_at: Utc::now() + chrono::Duration::minutes(10),
+            created_at: Utc::now(),
+        }
+    }
+
+    // the browser's trip: our authorization url, the provider approving, and the callback's code
+    async fn sign_in_at(provider: &Provider, state: &crate::token::federation::Model) -> String {
+        let url = url::Url::parse(&provider.authorization_url(state).await.unwrap()).unwrap();
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(query["state"], state.state);
+        assert_eq!(query["nonce"], state.nonce);
+        assert_eq!(query["redirect_uri"], redirect_uri());
+        assert_eq!(
+            query["code_challenge"],
+            URL_SAFE_NO_PAD.encode(Sha256::digest(state.code_verifier.as_bytes()))
+        );
+
+        let browser = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let response = browser.get(url).send().await.unwrap();
+        let location = url::Url::parse(response.headers()[reqwest::header::LOCATION].to_str().unwrap()).unwrap();
+        let callback: HashMap<String, String> = location.query_pairs().into_owned().collect();
+        assert_eq!(callback["state"], state.state);
+        callback["code"].clone()
+    }
+
+    #[tokio::test]
+    async fn signs_in_through_a_mock_provider() {
+        let provider = mock_provider(Misbehaviour::default()).await;
+        let state = federation_state();
+        let code = sign_in_at(&provider, &state).await;
+
+        let identity = provider.identity(&code, &state).await.unwrap();
+        assert_eq!(identity.subject, SUBJECT);
+        assert_eq!(identity.email.as_deref(), Some("ada@example.com"));
+        assert!(identity.email_verified);
+        // from userinfo
+        assert_eq!(identity.username.as_deref(), Some("ada"));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_discovery_issuer_mismatch() {
+        let provider = mock_provider(Misbehaviour {
+            wrong_issuer: true,
+            ..Default::default()
+        })
+        .await;
+
+        let err = provider.authorization_url(&federation_state()).await.unwrap_err();
+        assert!(format!("{err:#}").contains("Discovery issuer mismatch"));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_wrong_code_verifier() {
+        let provider = mock_provider(Misbehaviour::default()).await;
+        let state = federation_state();
+        let code = sign_in_at(&provider, &state).await;
+
+        let other = crate::token::federation::Model {
+            code_verifier: crate::util::generate_random_string(64),
+            ..state
+        };
+        let err = provider.identity(&code, &other).await.unwrap_err();
+        assert!(format!("{err:#}").contains("Token exchange failed"));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_nonce_mismatch() {
+        let provider = mock_provider(Misbehaviour::default()).await;
+        let state = federation_state();
+        let code = sign_in_at(&provider, &state).await;
+
+        let other = crate::token::federation::Model {
+            nonce: crate::util::generate_random_string(32),
+            ..state
+        };
+        let err = provider.identity(&code, &other).await.unwrap_err();
+        assert!(format!("{err:#}").contains("Nonce mismatch"));
+    }
+
+    #[tokio::test]
+    async fn rejects_an_id_token_signed_by_another_key() {
+        let provider = mock_provider(Misbehaviour {
+            foreign_key: true,
+            ..Default::default()
+        })
+        .await;
+        let state = federation_state();
+        let code = sign_in_at(&provider, &state).await;
+
+        let err = provider.identity(&code, &state).await.unwrap_err();
+        assert!(format!("{err:#}").contains("InvalidSignature"));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_symmetric_id_token() {
+        let provider = mock_provider(Misbehaviour {
+            symmetric: true,
+            ..Default::default()
+        })
+        .await;
+        let state = federation_state();
+        let code = sign_in_at(&provider, &state).await;
+
+        let err = provider.identity(&code, &state).await.unwrap_err();
+        assert!(format!("{err:#}").contains("Symmetric id token"));
+    }
+}
@@ -0,0 +1,334 @@
+use super::auth::OAuthParams;
+use crate::AppState;
+use crate::error::{AppError, FormResponse, HtmlError, OptionExt};
+use crate::handler::session::cleared_cookies;
+use crate::mail::Email;
+use crate::templates::{AccountDataTemplate, NoticeTemplate, RestoreTemplate};
+use askama::Template;
+use axum::{
+    Form, Json,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, header},
+    response::{AppendHeaders, Html, IntoResponse, Redirect, Response},
+};
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+// gdpr article 15 and 17, a copy of what we hold and a way to have it erased
+
+async fn data_page(
+    session: &crate::session::Model,
+    user: &crate::user::Model,
+    error: Option<&str>,
+) -> Result<Html<String>, AppError> {
+    let template = AccountDataTemplate {
+        username: user.username.clone(),
+        grace_days: crate::lifetime::account_deletion().num_days(),
+        fresh: session.is_fresh(),
+        has_password: user.has_password,
+        error: error.map(str::to_string),
+        csrf_token: crate::util::generate_csrf_token().await,
+    };
+    Ok(Html(template.render()?))
+}
+
+pub async fn data_get(headers: HeaderMap, State(app_state): State<AppState>) -> Result<Html<String>, HtmlError> {
+    let (session, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+    Ok(data_page(&session, &user, None).await?)
+}
+
+#[derive(Serialize)]
+struct Export {
+    exported_at: DateTime<Utc>,
+    user: crate::user::Model,
+    roles: Vec<crate::user_role::Model>,
+    groups: Vec<crate::group_member::Membership>,
+    group_requests: Vec<crate::group_request::Model>,
+    memberships: Vec<crate::membership::Model>,
+    invites: Vec<crate::invite::Model>,
+    linked_identities: Vec<crate::federated_identity::Model>,
+    passkeys: Vec<crate::passkey::Model>,
+    consents: Vec<Consent>,
+    active_tokens: Vec<ActiveToken>,
+    sessions: Vec<SessionExport>,
+    login_history: Vec<crate::login_attempt::Model>,
+    security_events: Vec<crate::security_event::Model>,
+    suspensions: Vec<crate::suspension::Model>,
+}
+
+// there's no consent screen, a client holding a refresh token is one the user let in
+#[derive(Serialize)]
+struct Consent {
+    client_id: String,
+    client_name: Option<String>,
+    scopes: String,
+    granted_at: DateTime<Utc>,
+}
+
+// the secrets themselves stay out, the file could end up anywhere
+#[derive(Serialize)]
+struct ActiveToken {
+    kind: &'static str,
+    client_id: String,
+    scopes: String,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct SessionExport {
+    amr: Vec<String>,
+    client_ids: Vec<String>,
+    created_at: DateTime<Utc>,
+    last_seen_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+}
+
+pub async fn export(headers: HeaderMap, State(app_state): State<AppState>) -> Result<Response, HtmlError> {
+    let (_, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+    let db = &app_state.db;
+    let now = Utc::now();
+
+    let refresh_tokens = crate::token::refresh::Entity::find()
+        .filter(crate::token::refresh::Column::UserId.eq(&user.id))
+        .filter(crate::token::refresh::Column::ExpiresAt.gt(now))
+        .order_by_asc(crate::token::refresh::Column::CreatedAt)
+        .all(db)
+        .await?;
+    let access_tokens = crate::token::access::Entity::find()
+        .filter(crate::token::access::Column::UserId.eq(&user.id))
+        .filter(crate::token::access::Column::ExpiresAt.gt(now))
+        .order_by_asc(crate::token::access::Column::CreatedAt)
+        .all(db)
+        .await?;
+
+    let mut consents: Vec<Consent> = Vec::new();
+    for token in &refresh_tokens {
+        if consents.iter().any(|consent| consent.client_id == token.client_id) {
+            continue;
+        }
+        let client = crate::client::Entity::find_by_id(&token.client_id).one(db).await?;
+        consents.push(Consent {
+            client_id: token.client_id.clone(),
+            client_name: client.map(|client| client.name),
+            scopes: token.scopes.clone(),
+            granted_at: token.created_at,
+        });
+    }
+
+    let mut active_tokens = Vec::new();
+    for token in &access_tokens {
+        active_tokens.push(ActiveToken {
+            kind: "access_token",
+            client_id: token.client_id.clone(),
+            scopes: token.scopes.clone(),
+            created_at: token.created_at,
+            expires_at: token.expires_at,
+        });
+    }
+    for token in &refresh_tokens {
+        active_tokens.push(ActiveToken {
+            kind: "refresh_token",
+            client_id: token.client_id.clone(),
+            scopes: token.scopes.clone(),
+            created_at: token.created_at,
+            expires_at: token.expires_at,
+        });
+    }
+
+    let sessions = crate::session::Entity::find()
+        .filter(crate::session::Column::UserId.eq(&user.id))
+        .order_by_asc(crate::session::Column::CreatedAt)
+        .all(db)
+        .await?
+        .into_iter()
+        .map(|session| SessionExport {
+            amr: session.get_amr(),
+            client_ids: session.get_client_ids().unwrap_or_default(),
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+            expires_at: session.expires_at,
+        })
+        .collect();
+
+    let export = Export {
+        exported_at: now,
+        roles: crate::user_role::Entity::for_user(&user.id, db).await?,
+        groups: crate::group_member::Entity::memberships(&user.id, db).await?,
+        group_requests: crate::group_request::Entity::for_user(&user.id, db).await?,
+        memberships: crate::membership::Entity::for_user(&user.id, db).await?,
+        invites: crate::invite::Entity::for_creator(&user.id, db).await?,
+        linked_identities: crate::federated_identity::Entity::for_user(&user.id, db).await?,
+        passkeys: crate::passkey::Entity::for_user(&user.id, db).await?,
+        consents,
+        active_tokens,
+        sessions,
+        login_history: crate::login_attempt::Entity::find()
+            .filter(crate::login_attempt::Column::UserId.eq(&user.id))
+            .order_by_desc(crate::login_attempt::Column::CreatedAt)
+            .all(db)
+            .await?,
+        security_events: crate::security_event::Entity::find()
+            .filter(crate::security_event::Column::UserId.eq(&user.id))
+            .order_by_asc(crate::security_event::Column::CreatedAt)
+            .all(db)
+            .await?,
+        suspensions: crate::suspension::Entity::find()
+            .filter(crate::suspension::Column::UserId.eq(&user.id))
+            .order_by_asc(crate::suspension::Column::CreatedAt)
+            .all(db)
+            .await?,
+        user,
+    };
+
+    tracing::info!(user_id = %export.user.id, "Data exported");
+    let disposition = format!("attachment; filename=\"sjallabong-{}.json\"", export.user.username);
+    Ok((
+        AppendHeaders([(header::CONTENT_DISPOSITION, disposition)]),
+        Json(export),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct DeleteForm {
+    #[serde(default)]
+    password: Option<String>,
+    csrf_token: String,
+}
+
+// disables the account now, the data goes once the grace period is over, see `user::Entity::purge`
+pub async fn delete(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Form(form): Form<DeleteForm>,
+) -> Result<FormResponse<Response>, HtmlError> {
+    let (session, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+
+    let error = if !crate::util::validate_csrf_token(&form.csrf_token).await {
+        Some("Invalid request, try again")
+    } else {
+        crate::handler::session::reauthenticate(&session, &user, form.password.as_deref(), &ip, &app_state).await?
+    };
+    if let Some(error) = error {
+        return Ok(FormResponse::ValidationErrors(
+            data_page(&session, &user, Some(error)).await?,
+        ));
+    }
+
+    let delete_after = Utc::now() + crate::lifetime::account_deletion();
+    let txn = app_state.db.begin().await?;
+    let mut update: crate::user::ActiveModel = user.clone().into();
+    update.delete_after = Set(Some(delete_after));
+    update.updated_at = Set(Utc::now());
+    update.update(&txn).await?;
+    crate::token::revoke_all_for_user(&user.id, None, &txn).await?;
+    crate::session::Entity::end_all(&user.id, None, &txn).await?;
+    crate::security_event::Entity::record(&user.id, crate::security_event::DELETION_REQUESTED, Some(ip), &txn).await?;
+    txn.commit().await?;
+
+    tracing::info!(user_id = %user.id, %delete_after, "Account deletion requested");
+    let date = delete_after.format("%Y-%m-%d");
+    match app_state.mailer.clone() {
+        Some(mailer) => crate::mail::send_in_background(
+            mailer,
+            Email {
+                to: user.email.clone(),
+                subject: "Your account will be deleted".to_string(),
+                body: format!(
+                    "Hi {},\n\nYour sjallabong account will be deleted on {date}. Until then, signing in lets you keep it. If you didn't ask for this, sign in and change your password.\n",
+                    user.username
+                ),
+            },
+        ),
+        None => tracing::warn!(user_id = %user.id, "Can't send deletion email, no mailer"),
+    }
+
+    let template = NoticeTemplate {
+        title: "Account deleted".to_string(),
+        message: format!("You've been signed out everywhere. Sign in before {date} if you change your mind"),
+    };
+    Ok(FormResponse::Success(
+        (AppendHeaders(cleared_cookies()), Html(template.render()?)).into_response(),
+    ))
+}
+
+// shown instead of a code to an account waiting to be deleted, the login itself still checked out
+pub async fn restore_page(
+    user: &crate::user::Model,
+    oauth: &OAuthParams,
+    session: &crate::session::Model,
+) -> Result<Response, AppError> {
+    let template = RestoreTemplate {
+        username: user.username.clone(),
+        delete_after: user
+            .delete_after
+            .map(|at| at.format("%Y-%m-%d").to_string())
+            .unwrap_or_default(),
+        csrf_token: crate::util::generate_csrf_token().await,
+        client_id: oauth.client_id.clone(),
+        redirect_uri: oauth.redirect_uri.clone(),
+        state: oauth.state.clone(),
+        scope: oauth.scope.clone(),
+        code_challenge: oauth.code_challenge.clone(),
+        code_challenge_method: oauth.code_challenge_method.clone(),
+    };
+    Ok((
+        AppendHeaders(crate::handler::session::cookies(session)),
+        Html(template.render()?),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct RestoreForm {
+    csrf_token: String,
+    #[serde(flatten)]
+    oauth: OAuthParams,
+}
+
+// calls the deletion off, then carries on with the authorization request through the session
+pub async fn restore(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Form(form): Form<RestoreForm>,
+) -> Result<Redirect, HtmlError> {
+    // `signed_in` turns these accounts away
+    let session = crate::handler::session::current(&headers, &app_state.db)
+        .await?
+        .or_unauthorized("Sign in first")?;
+    if !crate::util::validate_csrf_token(&form.csrf_token).await {
+        return Err(AppError::bad_request("Invalid request, try again").into());
+    }
+
+    let txn = app_state.db.begin().await?;
+    let restored = crate::user::Entity::update_many()
+        .col_expr(
+            crate::user::Column::DeleteAfter,
+            sea_query::Expr::value(Option::<DateTime<Utc>>::None),
+        )
+        .filter(crate::user::Column::Id.eq(&session.user_id))
+        .filter(crate::user::Column::DeleteAfter.is_not_null())
+        .filter(crate::user::Column::DeletedAt.is_null())
+        .exec(&txn)
+        .await?;
+    if restored.rows_affected == 1 {
+        let ip = crate::handler::geoloc::client_ip(&headers, addr);
+        crate::security_event::Entity::record(
+            &session.user_id,
+            crate::security_event::DELETION_CANCELLED,
+            Some(ip),
+            &txn,
+        )
+        .await?;
+        tracing::info!(user_id = %session.user_id, "Account deletion cancelled");
+    }
+    txn.commit().await?;
+
+    Ok(Redirect::to(&format!("/authorize?{}", form.oauth.query())))
+}
@@ -0,0 +1,149 @@
+use crate::AppState;
+use crate::error::{AppError, HtmlError, OptionExt};
+use crate::templates::{ActivityTemplate, ActivityView};
+use askama::Template;
+use axum::{
+    Extension, Json,
+    extract::{Query, State},
+    http::{HeaderMap, header},
+    response::Html,
+};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+const PER_PAGE: u64 = 20;
+const MAX_PER_PAGE: u64 = 100;
+
+pub struct Attempt<'a> {
+    pub user_id: Option<&'a str>,
+    pub login: Option<&'a str>,
+    pub client_id: Option<&'a str>,
+    pub amr: &'a [&'a str],
+    // one of `login_attempt`'s reasons, None on success
+    pub failure: Option<&'static str>,
+}
+
+// trimmed, it's stored with every attempt and session
+pub fn user_agent(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::USER_AGENT)
+        .and_then(|ua| ua.to_str().ok())
+        .map(|ua| ua.chars().take(256).collect())
+}
+
+// one row of login history, see `login_attempt`
+pub async fn record(
+    attempt: Attempt<'_>,
+    ip: &str,
+    headers: &HeaderMap,
+    db: &DatabaseConnection,
+) -> Result<(), AppError> {
+    let user_agent = user_agent(headers);
+
+    crate::login_attempt::ActiveModel {
+        user_id: Set(attempt.user_id.map(str::to_string)),
+        login: Set(attempt.login.map(|login| login.chars().take(256).collect())),
+        success: Set(attempt.failure.is_none()),
+        failure: Set(attempt.failure.map(str::to_string)),
+        amr: Set((!attempt.amr.is_empty())
+            .then(|| serde_json::to_string(attempt.amr))
+            .transpose()?),
+        client_id: Set(attempt.client_id.map(str::to_string)),
+        ip: Set(Some(ip.to_string())),
+        country: Set(crate::handler::geoloc::get_country_from_ip(ip).await),
+        user_agent: Set(user_agent),
+        ..Default::default()
+    }
+    .insert(db)
+    .await?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct PageParams {
+    #[serde(default)]
+    page: u64,
+}
+
+pub async fn account_get(
+    headers: HeaderMap,
+    Query(params): Query<PageParams>,
+    State(app_state): State<AppState>,
+) -> Result<Html<String>, HtmlError> {
+    let (_, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+    let (attempts, more) = crate::login_attempt::Entity::page(&user.id, params.page, PER_PAGE, &app_state.db).await?;
+
+    let mut entries = Vec::new();
+    for attempt in attempts {
+        let client = match &attempt.client_id {
+            Some(client_id) => crate::client::Entity::find_by_id(client_id)
+                .one(&app_state.db)
+                .await?
+                .map(|client| client.name),
+            None => None,
+        };
+        entries.push(ActivityView {
+            at: attempt.created_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+            methods: attempt.get_amr().join(" + "),
+            success: attempt.success,
+            failure: attempt.failure.map(|failure| failure.replace('_', " ")),
+            ip: attempt.ip.unwrap_or_default(),
+            country: attempt.country,
+            client,
+            user_agent: attempt.user_agent,
+        });
+    }
+
+    let template = ActivityTemplate {
+        username: user.username,
+        entries,
+        page: params.page,
+        more,
+    };
+    Ok(Html(template.render()?))
+}
+
+#[derive(Deserialize)]
+pub struct ActivityQuery {
+    // someone else's, needs users:activity. the sub the calling client knows
+    user_id: Option<String>,
+    #[serde(default)]
+    page: u64,
+    per_page: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ActivityResponse {
+    pub attempts: Vec<crate::login_attempt::Model>,
+    pub page: u64,
+    pub more: bool,
+}
+
+pub async fn get(
+    Extension(auth_user): Extension<crate::middleware::user::AuthenticatedUser>,
+    Query(query): Query<ActivityQuery>,
+    State(app_state): State<AppState>,
+) -> Result<Json<ActivityResponse>, AppError> {
+    let user_id = match &query.user_id {
+        Some(sub) if *sub != auth_user.sub => {
+            if !auth_user.can(crate::permission::USERS_ACTIVITY) {
+                return Err(AppError::forbidden(
+                    "Insufficient permissions to view this user's activity",
+                ));
+            }
+            let client = crate::util::get_client(&auth_user.access_token.client_id, &app_state.db).await?;
+            crate::subject::Entity::user_id(&client, sub, &app_state.db)
+                .await?
+                .or_not_found(format!("User not found: {sub}"))?
+        }
+        _ => auth_user.user.id.clone(),
+    };
+
+    let per_page = query.per_page.unwrap_or(PER_PAGE).clamp(1, MAX_PER_PAGE);
+    let (attempts, more) = crate::login_attempt::Entity::page(&user_id, query.page, per_page, &app_state.db).await?;
+    Ok(Json(ActivityResponse {
+        attempts,
+        page: query.page,
+        more,
+    }))
+}
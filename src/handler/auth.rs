@@ -1,7 +1,8 @@
 use crate::AppState;
+use crate::error::FormResponse;
 use crate::error::{AppError, HtmlError, OptionExt};
+use crate::templates::{LoginTemplate, NoticeTemplate};
 use crate::util::generate_random_string;
-use crate::{error::FormResponse, templates::LoginTemplate};
 use anyhow::Context;
 use askama::Template;
 use axum::extract::ConnectInfo;
@@ -9,15 +10,15 @@ use axum::http::HeaderMap;
 use axum::{
     Form,
     extract::{Query, State},
-    response::{Html, Redirect},
+    response::{AppendHeaders, Html, IntoResponse, Redirect, Response},
 };
 use sea_orm::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use tracing::info;
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OAuthParams {
     pub client_id: String,
     pub redirect_uri: String,
@@ -25,6 +26,23 @@ pub struct OAuthParams {
     pub scope: String,
     pub code_challenge: String,
     pub code_challenge_method: String,
+    // only read on the way in, "login" skips the sso session and "none" never shows the form
+    #[serde(default)]
+    pub prompt: Option<String>,
+}
+
+impl OAuthParams {
+    // to send the browser back to /authorize with the same request
+    pub fn query(&self) -> String {
+        url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_uri)
+            .append_pair("state", &self.state)
+            .append_pair("scope", &self.scope)
+            .append_pair("code_challenge", &self.code_challenge)
+            .append_pair("code_challenge_method", &self.code_challenge_method)
+            .finish()
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -33,6 +51,8 @@ pub struct LoginForm {
     password: String,
     csrf_token: String,
     #[serde(flatten)]
+    pow: crate::pow::Solution,
+    #[serde(flatten)]
     pub oauth: OAuthParams,
 }
 
@@ -50,12 +70,12 @@ fn validate_login_format(form: &LoginForm) -> HashMap<String, String> {
     errors
 }
 
-async fn authenticate_user(form: &LoginForm, app_state: &AppState) -> Result<crate::user::Model, AppError> {
-    let user = crate::user::Entity::find()
-        .filter(crate::user::Column::Username.eq(&form.login))
-        .one(&app_state.db)
-        .await?
-        .or_unauthorized("Invalid username or password")?;
+async fn authenticate_user(
+    user: Option<crate::user::Model>,
+    form: &LoginForm,
+    app_state: &AppState,
+) -> Result<crate::user::Model, AppError> {
+    let user = user.or_unauthorized("Invalid username or password")?;
 
     if app_state.password.verify(&form.password, &user.password_hash)? {
         Ok(user)
@@ -64,10 +84,158 @@ async fn authenticate_user(form: &LoginForm, app_state: &AppState) -> Result<cra
     }
 }
 
+async fn login_page(
+    oauth: &OAuthParams,
+    login: &str,
+    errors: HashMap<String, String>,
+    ip: &str,
+    db: &DatabaseConnection,
+) -> Result<Html<String>, AppError> {
+    let template = LoginTemplate {
+        client_id: oauth.client_id.clone(),
+        redirect_uri: oauth.redirect_uri.clone(),
+        state: oauth.state.clone(),
+        scope: oauth.scope.clone(),
+        errors,
+        login: login.to_string(),
+        code_challenge: oauth.code_challenge.clone(),
+        code_challenge_method: oauth.code_challenge_method.clone(),
+        csrf_token: crate::util::generate_csrf_token().await,
+        providers: crate::federation::providers(),
+        can_register: crate::handler::register::mode() != crate::handler::register::Mode::Closed,
+        pow: crate::pow::issue(crate::pow::LOGIN, ip, db).await?,
+    };
+    Ok(Html(template.render()?))
+}
+
+// sends the browser back to the client with an oauth error instead of a code
+fn error_redirect(oauth: &OAuthParams, error: &str) -> Result<Response, AppError> {
+    let mut redirect_url = url::Url::parse(&oauth.redirect_uri).context("Invalid redirect URI")?;
+    redirect_url
+        .query_pairs_mut()
+        .append_pair("error", error)
+        .append_pair("state", &oauth.state);
+    Ok(Redirect::to(redirect_url.as_ref()).into_response())
+}
+
+// hands the client a code for a user signed in to the given sso session
+async fn issue_code(
+    oauth: &OAuthParams,
+    client: &crate::client::Model,
+    user: &crate::user::Model,
+    session: crate::session::Model,
+    app_state: &AppState,
+) -> Result<Response, AppError> {
+    let db = &app_state.db;
+
+    // every way in ends up here, passkeys and providers included
+    if let Some(restriction) = crate::user::Entity::restriction(user, db).await? {
+        info!(user_id = %user.id, "Authorization refused, account restricted");
+        crate::session::Entity::end(&session.sid, db).await?;
+        if oauth.prompt.as_deref() == Some("none") {
+            return error_redirect(oauth, "access_denied");
+        }
+        let mut errors = HashMap::new();
+        errors.insert("general".to_string(), restriction);
+        return Ok((
+            AppendHeaders(crate::handler::session::cleared_cookies()),
+            login_page(
+                oauth,
+                &user.username,
+                errors,
+                session.ip.as_deref().unwrap_or_default(),
+                db,
+            )
+            .await?,
+        )
+            .into_response());
+    }
+
+    // signing in is how a deletion gets called off, so they're asked before anything else
+    if user.delete_after.is_some() {
+        if oauth.prompt.as_deref() == Some("none") {
+            return error_redirect(oauth, "login_required");
+        }
+        return crate::handler::account::restore_page(user, oauth, &session).await;
+    }
+
+    if crate::user::Entity::requires_mfa(user, db).await? && !session.is_multi_factor() {
+        if oauth.prompt.as_deref() == Some("none") {
+            return error_redirect(oauth, "interaction_required");
+        }
+        let template = NoticeTemplate {
+            title: "Two-factor required".to_string(),
+            message: "Staff accounts need two-factor authentication. Set it up at /account/totp or /account/passkeys, then sign in again"
+                .to_string(),
+        };
+        return Ok((
+            AppendHeaders(crate::handler::session::cookies(&session)),
+            Html(template.render()?),
+        )
+            .into_response());
+    }
+
+    // the session still counts, so once verified the user gets straight through
+    if client.require_verified_email && !user.is_verified {
+        if oauth.prompt.as_deref() == Some("none") {
+            return error_redirect(oauth, "interaction_required");
+        }
+        crate::handler::verify_email::send(user, app_state).await?;
+        let template = NoticeTemplate {
+            title: "Verify your email".to_string(),
+            message: format!(
+                "{} needs a verified email. We sent a link to {}, open it and then sign in again",
+                client.name, user.email
+            ),
+        };
+        return Ok((
+            AppendHeaders(crate::handler::session::cookies(&session)),
+            Html(template.render()?),
+        )
+            .into_response());
+    }
+
+    let code = generate_random_string(32);
+    let auth_code = crate::token::auth::ActiveModel {
+        code: Set(code.clone()),
+        client_id: Set(client.client_id.clone()),
+        user_id: Set(user.id.clone()),
+        redirect_uri: Set(oauth.redirect_uri.clone()),
+        scopes: Set(oauth.scope.clone()),
+        code_challenge: Set(oauth.code_challenge.clone()),
+        code_challenge_method: Set(oauth.code_challenge_method.clone()),
+        expires_at: Set(chrono::Utc::now() + client.lifetimes().code),
+        sid: Set(Some(session.sid.clone())),
+        amr: Set(session.amr.clone()),
+        ..Default::default()
+    };
+
+    auth_code.insert(db).await.context("Failed to create auth code")?;
+
+    let session = crate::session::Entity::add_client(session, &client.client_id, db).await?;
+    let session_state =
+        crate::handler::session::session_state(&client.client_id, &oauth.redirect_uri, &session.browser_state)?;
+
+    let mut redirect_url = url::Url::parse(&oauth.redirect_uri).context("Invalid redirect URI")?;
+    redirect_url
+        .query_pairs_mut()
+        .append_pair("code", &code)
+        .append_pair("state", &oauth.state)
+        .append_pair("session_state", &session_state);
+
+    Ok((
+        AppendHeaders(crate::handler::session::cookies(&session)),
+        Redirect::to(redirect_url.as_ref()),
+    )
+        .into_response())
+}
+
 pub async fn get(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Query(oauth): Query<OAuthParams>,
     State(app_state): State<AppState>,
-) -> Result<Html<String>, HtmlError> {
+    headers: HeaderMap,
+) -> Result<Response, HtmlError> {
     info!(
         client_id = %oauth.client_id,
         scopes = ?oauth.scope,
@@ -103,19 +271,30 @@ pub async fn get(
         }
     }
 
-    let template = LoginTemplate {
-        client_id: oauth.client_id,
-        redirect_uri: oauth.redirect_uri,
-        state: oauth.state,
-        scope: oauth.scope,
-        errors: HashMap::new(),
-        login: String::new(),
-        code_challenge: oauth.code_challenge,
-        code_challenge_method: oauth.code_challenge_method,
-        csrf_token: crate::util::generate_csrf_token().await,
+    // sso, skip the form if this browser is already signed in
+    let session = match oauth.prompt.as_deref() {
+        Some("login") => None,
+        _ => crate::handler::session::current(&headers, &app_state.db).await?,
     };
+    if let Some(session) = session
+        && let Some(user) = crate::user::Entity::find_by_id(&session.user_id)
+            .one(&app_state.db)
+            .await?
+        // a session from before 2fa was turned on isn't good enough anymore
+        && (session.is_multi_factor() || !crate::user::Entity::has_second_factor(&user, &app_state.db).await?)
+    {
+        info!(client_id = %oauth.client_id, "Authorized from existing session");
+        return Ok(issue_code(&oauth, &client, &user, session, &app_state).await?);
+    }
 
-    Ok(Html(template.render()?))
+    if oauth.prompt.as_deref() == Some("none") {
+        return Ok(error_redirect(&oauth, "login_required")?);
+    }
+
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+    Ok(login_page(&oauth, "", HashMap::new(), &ip, &app_state.db)
+        .await?
+        .into_response())
 }
 
 pub async fn post(
@@ -123,22 +302,14 @@ pub async fn post(
     headers: HeaderMap,
     State(app_state): State<AppState>,
     Form(form): Form<LoginForm>,
-) -> Result<FormResponse<Redirect>, HtmlError> {
+) -> Result<FormResponse<Response>, HtmlError> {
     let oauth = &form.oauth;
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
     let render_error =
-        async |errors: HashMap<String, String>, form: &LoginForm| -> Result<FormResponse<Redirect>, HtmlError> {
-            let template = LoginTemplate {
-                client_id: oauth.client_id.clone(),
-                redirect_uri: oauth.redirect_uri.clone(),
-                state: oauth.state.clone(),
-                scope: oauth.scope.clone(),
-                errors,
-                login: form.login.clone(),
-                code_challenge: oauth.code_challenge.clone(),
-                code_challenge_method: oauth.code_challenge_method.clone(),
-                csrf_token: crate::util::generate_csrf_token().await,
-            };
-            Ok(FormResponse::ValidationErrors(Html(template.render()?)))
+        async |errors: HashMap<String, String>, form: &LoginForm| -> Result<FormResponse<Response>, HtmlError> {
+            Ok(FormResponse::ValidationErrors(
+                login_page(oauth, &form.login, errors, &ip, &app_state.db).await?,
+            ))
         };
 
     let format_errors = validate_login_format(&form);
@@ -152,56 +323,123 @@ pub async fn post(
         return render_error(errors, &form).await;
     }
 
-    let user = match authenticate_user(&form, &app_state).await {
+    if let Err(error) = crate::pow::verify(crate::pow::LOGIN, &form.pow, &ip).await {
+        let mut errors = HashMap::new();
+        errors.insert("general".to_string(), error.to_string());
+        return render_error(errors, &form).await;
+    }
+
+    // checked before the password, so a held back guess learns nothing
+    let attempt = |user_id, amr, failure| crate::handler::activity::Attempt {
+        user_id,
+        login: Some(&form.login),
+        client_id: Some(&form.oauth.client_id),
+        amr,
+        failure: Some(failure),
+    };
+    let user = crate::user::Entity::find_by_login(&form.login, &app_state.db).await?;
+    let account = crate::throttle::account(user.as_ref(), &form.login);
+    let user_id = user.as_ref().map(|user| user.id.clone());
+    if let Some(wait) = crate::throttle::check(&account, &ip).await {
+        info!(login = %form.login, %ip, wait, "Login throttled");
+        crate::handler::activity::record(
+            attempt(None, &[], crate::login_attempt::THROTTLED),
+            &ip,
+            &headers,
+            &app_state.db,
+        )
+        .await?;
+        let mut errors = HashMap::new();
+        errors.insert("general".to_string(), "Too many attempts, try again later".to_string());
+        return render_error(errors, &form).await;
+    }
+
+    let user = match authenticate_user(user, &form, &app_state).await {
         Ok(user) => user,
         Err(AppError::Unauthorized(msg)) => {
+            crate::throttle::failed(&account, &ip).await;
+            let attempt = attempt(user_id.as_deref(), &["pwd"], crate::login_attempt::WRONG_PASSWORD);
+            crate::handler::activity::record(attempt, &ip, &headers, &app_state.db).await?;
             let mut errors = HashMap::new();
             errors.insert("general".to_string(), msg);
             return render_error(errors, &form).await;
         }
         Err(e) => return Err(e.into()),
     };
+    // only said once the password checked out, so it doesn't give away who's suspended
+    if let Some(restriction) = crate::user::Entity::restriction(&user, &app_state.db).await? {
+        info!(user_id = %user.id, "Login refused, account restricted");
+        let attempt = attempt(Some(&user.id), &["pwd"], crate::login_attempt::RESTRICTED);
+        crate::handler::activity::record(attempt, &ip, &headers, &app_state.db).await?;
+        let mut errors = HashMap::new();
+        errors.insert("general".to_string(), restriction);
+        return render_error(errors, &form).await;
+    }
 
-    let code = generate_random_string(32);
-    let auth_code = crate::token::auth::ActiveModel {
-        code: Set(code.clone()),
-        client_id: Set(form.oauth.client_id.clone()),
-        user_id: Set(user.id.clone()),
-        redirect_uri: Set(form.oauth.redirect_uri.clone()),
-        scopes: Set(form.oauth.scope.clone()),
-        code_challenge: Set(form.oauth.code_challenge.clone()),
-        code_challenge_method: Set(form.oauth.code_challenge_method.clone()),
-        ..Default::default()
-    };
+    let client = crate::util::get_client(&form.oauth.client_id, &app_state.db).await?;
+    crate::util::validate_redirect_uri(&client, &form.oauth.redirect_uri)?;
 
-    auth_code
-        .insert(&app_state.db)
-        .await
-        .context("Failed to create auth code")?;
+    if crate::user::Entity::has_second_factor(&user, &app_state.db).await? {
+        let challenge = crate::token::mfa::Entity::create(&user.id, "pwd", &app_state.db).await?;
+        let page =
+            crate::handler::totp::challenge_page(&form.oauth, &challenge.token, &user, HashMap::new(), &app_state.db)
+                .await?;
+        return Ok(FormResponse::Success(page.into_response()));
+    }
 
-    let mut redirect_url = url::Url::parse(&form.oauth.redirect_uri).context("Invalid redirect URI")?;
+    let response = sign_in(&user, &["pwd"], &form.oauth, &client, ip, &headers, &app_state).await?;
 
-    let client = crate::client::Entity::find_by_id(&form.oauth.client_id)
-        .one(&app_state.db)
-        .await?
-        .or_bad_request(format!("Invalid client_id: {}", form.oauth.client_id))?;
-    crate::util::validate_redirect_uri(&client, &form.oauth.redirect_uri)?;
+    Ok(FormResponse::Success(response))
+}
 
-    redirect_url.query_pairs_mut().append_pair("code", &code);
-    redirect_url.query_pairs_mut().append_pair("state", &form.oauth.state);
+// the last step of every login form, once all factors are in
+pub async fn sign_in(
+    user: &crate::user::Model,
+    amr: &[&str],
+    oauth: &OAuthParams,
+    client: &crate::client::Model,
+    ip: String,
+    headers: &HeaderMap,
+    app_state: &AppState,
+) -> Result<Response, AppError> {
+    // a restricted account is turned away in `issue_code`, it's only counted here
+    let restricted = crate::user::Entity::restriction(user, &app_state.db).await?.is_some();
+    let attempt = crate::handler::activity::Attempt {
+        user_id: Some(&user.id),
+        login: None,
+        client_id: Some(&client.client_id),
+        amr,
+        failure: restricted.then_some(crate::login_attempt::RESTRICTED),
+    };
+    crate::handler::activity::record(attempt, &ip, headers, &app_state.db).await?;
+    if !restricted {
+        crate::user::Entity::logged_in(&user.id, &app_state.db).await?;
+        crate::throttle::signed_in(user).await;
+    }
+
+    // fresh login, fresh session. drop whatever this browser had before
+    if let Some(sid) = crate::util::get_cookie(headers, crate::handler::session::SESSION_COOKIE) {
+        crate::session::Entity::end(&sid, &app_state.db).await?;
+    }
+    let device = crate::session::Device {
+        ip: ip.clone(),
+        country: crate::handler::geoloc::get_country_from_ip(&ip).await,
+        user_agent: crate::handler::activity::user_agent(headers),
+    };
+    let session = crate::session::Entity::create(&user.id, amr, device, &app_state.db).await?;
+    let response = issue_code(oauth, client, user, session, app_state).await?;
 
     // if a user doesn't have a country, quick check. should only realistically happen on register, and
     // NEVER if they already have a country!!!!! + it can be changed by them later :)
     if user.country.is_none() {
-        let user_ip = crate::handler::geoloc::get_forwarded_ip(&headers).unwrap_or_else(|| addr.ip().to_string());
         let user_id = user.id.clone();
         let db = app_state.db.clone();
         tokio::spawn(async move {
-            if let Some(country) = crate::handler::geoloc::get_country_from_ip(&user_ip).await {
+            if let Some(country) = crate::handler::geoloc::get_country_from_ip(&ip).await {
                 let _ = crate::user::Entity::update_country(&user_id, &country, &db).await;
             }
         });
     }
 
-    Ok(FormResponse::Success(Redirect::to(redirect_url.as_ref())))
+    Ok(response)
 }
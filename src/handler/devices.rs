@@ -0,0 +1,351 @@
+use crate::AppState;
+use crate::error::{AppError, HtmlError};
+use crate::handler::session::cleared_cookies;
+use crate::templates::{AppView, GrantView, NoticeTemplate, SessionView, SessionsTemplate};
+use askama::Template;
+use axum::{
+    Extension, Form, Json,
+    extract::{ConnectInfo, State},
+    http::HeaderMap,
+    response::{AppendHeaders, Html, IntoResponse, Redirect, Response},
+};
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+// where the user is signed in: browsers holding an sso session, and clients holding a refresh token
+
+#[derive(Serialize)]
+pub struct SessionInfo {
+    // not the sid, see `session::Model::public_id`
+    pub id: String,
+    // the browser (or for the api, the session the calling token came from)
+    pub current: bool,
+    pub ip: Option<String>,
+    pub country: Option<String>,
+    pub user_agent: Option<String>,
+    pub amr: Vec<String>,
+    pub client_ids: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct GrantInfo {
+    // see `refresh::Model::grant_id`
+    pub id: String,
+    pub scopes: String,
+    // the session it was granted in, its device is the one the user signed in on. None once that ended
+    pub session_id: Option<String>,
+    pub ip: Option<String>,
+    pub country: Option<String>,
+    pub user_agent: Option<String>,
+    // refresh tokens rotate, so the live one was issued on the last refresh
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct AppInfo {
+    pub client_id: String,
+    pub client_name: Option<String>,
+    pub grants: Vec<GrantInfo>,
+}
+
+#[derive(Serialize)]
+pub struct Overview {
+    pub sessions: Vec<SessionInfo>,
+    pub apps: Vec<AppInfo>,
+}
+
+async fn overview(user_id: &str, current_sid: Option<&str>, db: &DatabaseConnection) -> Result<Overview, DbErr> {
+    let sessions = crate::session::Entity::for_user(user_id, db).await?;
+    let by_sid: HashMap<&str, &crate::session::Model> =
+        sessions.iter().map(|session| (session.sid.as_str(), session)).collect();
+
+    let mut apps: Vec<AppInfo> = Vec::new();
+    for token in crate::token::refresh::Entity::for_user(user_id, db).await? {
+        let session = token.sid.as_deref().and_then(|sid| by_sid.get(sid));
+        let grant = GrantInfo {
+            id: token.grant_id(),
+            scopes: token.scopes.clone(),
+            session_id: session.map(|session| session.public_id()),
+            ip: session.and_then(|session| session.ip.clone()),
+            country: session.and_then(|session| session.country.clone()),
+            user_agent: session.and_then(|session| session.user_agent.clone()),
+            last_used_at: token.created_at,
+            expires_at: token.expires_at,
+        };
+        match apps.iter_mut().find(|app| app.client_id == token.client_id) {
+            Some(app) => app.grants.push(grant),
+            None => {
+                let client = crate::client::Entity::find_by_id(&token.client_id).one(db).await?;
+                apps.push(AppInfo {
+                    client_id: token.client_id,
+                    client_name: client.map(|client| client.name),
+                    grants: vec![grant],
+                });
+            }
+        }
+    }
+
+    let sessions = sessions
+        .iter()
+        .map(|session| SessionInfo {
+            id: session.public_id(),
+            current: current_sid == Some(session.sid.as_str()),
+            ip: session.ip.clone(),
+            country: session.country.clone(),
+            user_agent: session.user_agent.clone(),
+            amr: session.get_amr(),
+            client_ids: session.get_client_ids().unwrap_or_default(),
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+            expires_at: session.expires_at,
+        })
+        .collect();
+
+    Ok(Overview { sessions, apps })
+}
+
+enum Target {
+    // a browser, and whatever clients got through it
+    Session(String),
+    Grant(String),
+    // every grant to one client
+    Client(String),
+    Everywhere,
+}
+
+impl Target {
+    fn from(
+        session_id: Option<String>,
+        grant_id: Option<String>,
+        client_id: Option<String>,
+        everywhere: bool,
+    ) -> Option<Self> {
+        match (session_id, grant_id, client_id, everywhere) {
+            (Some(id), None, None, false) => Some(Target::Session(id)),
+            (None, Some(id), None, false) => Some(Target::Grant(id)),
+            (None, None, Some(client_id), false) => Some(Target::Client(client_id)),
+            (None, None, None, true) => Some(Target::Everywhere),
+            _ => None,
+        }
+    }
+}
+
+// whether there was anything to revoke
+async fn revoke(user_id: &str, target: &Target, ip: String, db: &DatabaseConnection) -> Result<bool, DbErr> {
+    let txn = db.begin().await?;
+    let kind = match target {
+        Target::Session(id) => {
+            let sessions = crate::session::Entity::for_user(user_id, &txn).await?;
+            let Some(session) = sessions.iter().find(|session| session.public_id() == *id) else {
+                return Ok(false);
+            };
+            crate::token::refresh::Entity::revoke_session(&session.sid, &txn).await?;
+            crate::session::Entity::end(&session.sid, &txn).await?;
+            crate::security_event::SESSION_ENDED
+        }
+        Target::Grant(id) => {
+            if !crate::token::refresh::Entity::revoke_grant(user_id, id, &txn).await? {
+                return Ok(false);
+            }
+            crate::security_event::GRANT_REVOKED
+        }
+        Target::Client(client_id) => {
+            if crate::token::refresh::Entity::revoke_client(user_id, client_id, &txn).await? == 0 {
+                return Ok(false);
+            }
+            crate::security_event::GRANT_REVOKED
+        }
+        Target::Everywhere => {
+            crate::token::revoke_all_for_user(user_id, None, &txn).await?;
+            crate::session::Entity::end_all(user_id, None, &txn).await?;
+            crate::security_event::SIGNED_OUT_EVERYWHERE
+        }
+    };
+    crate::security_event::Entity::record(user_id, kind, Some(ip), &txn).await?;
+    txn.commit().await?;
+    tracing::info!(user_id, kind, "Revoked");
+    Ok(true)
+}
+
+fn device(user_agent: Option<&str>) -> String {
+    user_agent.unwrap_or("Unknown device").to_string()
+}
+
+fn location(ip: Option<&str>, country: Option<&str>) -> String {
+    match (ip, country) {
+        (Some(ip), Some(country)) => format!("{ip} ({country})"),
+        (Some(ip), None) => ip.to_string(),
+        _ => String::new(),
+    }
+}
+
+pub async fn account_get(headers: HeaderMap, State(app_state): State<AppState>) -> Result<Html<String>, HtmlError> {
+    let (session, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+    let overview = overview(&user.id, Some(&session.sid), &app_state.db).await?;
+
+    let mut names: HashMap<String, String> = HashMap::new();
+    let mut apps = Vec::new();
+    for app in overview.apps {
+        let name = app.client_name.unwrap_or_else(|| app.client_id.clone());
+        names.insert(app.client_id.clone(), name.clone());
+        let mut grants = Vec::new();
+        for grant in app.grants {
+            grants.push(GrantView {
+                device: device(grant.user_agent.as_deref()),
+                location: location(grant.ip.as_deref(), grant.country.as_deref()),
+                last_used_at: grant.last_used_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+                id: grant.id,
+                csrf_token: crate::util::generate_csrf_token().await,
+            });
+        }
+        apps.push(AppView {
+            client_id: app.client_id,
+            name,
+            grants,
+            csrf_token: crate::util::generate_csrf_token().await,
+        });
+    }
+
+    let mut sessions = Vec::new();
+    for session in overview.sessions {
+        // clients that never got a refresh token aren't among the apps
+        for client_id in &session.client_ids {
+            if !names.contains_key(client_id) {
+                let client = crate::client::Entity::find_by_id(client_id).one(&app_state.db).await?;
+                let name = client.map_or_else(|| client_id.clone(), |client| client.name);
+                names.insert(client_id.clone(), name);
+            }
+        }
+        let clients = session
+            .client_ids
+            .iter()
+            .map(|client_id| names.get(client_id).unwrap_or(client_id).as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        sessions.push(SessionView {
+            device: device(session.user_agent.as_deref()),
+            location: location(session.ip.as_deref(), session.country.as_deref()),
+            signed_in_at: session.created_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+            last_seen_at: session.last_seen_at.format("%Y-%m-%d %H:%M UTC").to_string(),
+            clients,
+            current: session.current,
+            id: session.id,
+            csrf_token: crate::util::generate_csrf_token().await,
+        });
+    }
+
+    let template = SessionsTemplate {
+        username: user.username,
+        sessions,
+        apps,
+        csrf_token: crate::util::generate_csrf_token().await,
+    };
+    Ok(Html(template.render()?))
+}
+
+#[derive(Deserialize)]
+pub struct RevokeForm {
+    session_id: Option<String>,
+    grant_id: Option<String>,
+    client_id: Option<String>,
+    // the "sign out everywhere" button
+    everywhere: Option<String>,
+    csrf_token: String,
+}
+
+pub async fn account_revoke(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Form(form): Form<RevokeForm>,
+) -> Result<Response, HtmlError> {
+    let (session, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+    if !crate::util::validate_csrf_token(&form.csrf_token).await {
+        return Err(AppError::bad_request("Invalid request, try again").into());
+    }
+    let target = Target::from(
+        form.session_id,
+        form.grant_id,
+        form.client_id,
+        form.everywhere.is_some(),
+    )
+    .ok_or_else(|| AppError::bad_request("Invalid request, try again"))?;
+
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+    revoke(&user.id, &target, ip, &app_state.db).await?;
+
+    let signed_out = match &target {
+        Target::Everywhere => true,
+        Target::Session(id) => *id == session.public_id(),
+        _ => false,
+    };
+    if !signed_out {
+        return Ok(Redirect::to("/account/sessions").into_response());
+    }
+    let template = NoticeTemplate {
+        title: "Signed out".to_string(),
+        message: "You've been signed out, along with every app you were signed in to".to_string(),
+    };
+    Ok((AppendHeaders(cleared_cookies()), Html(template.render()?)).into_response())
+}
+
+// the session the calling token was issued in, if it came through a browser
+async fn token_sid(
+    access_token: &crate::token::access::Model,
+    db: &DatabaseConnection,
+) -> Result<Option<String>, DbErr> {
+    let Some(family_id) = &access_token.family_id else {
+        return Ok(None);
+    };
+    let refresh_token = crate::token::refresh::Entity::find()
+        .filter(crate::token::refresh::Column::FamilyId.eq(family_id))
+        .one(db)
+        .await?;
+    Ok(refresh_token.and_then(|token| token.sid))
+}
+
+pub async fn get(
+    Extension(auth_user): Extension<crate::middleware::user::AuthenticatedUser>,
+    State(app_state): State<AppState>,
+) -> Result<Json<Overview>, AppError> {
+    let sid = token_sid(&auth_user.access_token, &app_state.db).await?;
+    Ok(Json(overview(&auth_user.user.id, sid.as_deref(), &app_state.db).await?))
+}
+
+#[derive(Deserialize)]
+pub struct RevokeRequest {
+    session_id: Option<String>,
+    grant_id: Option<String>,
+    client_id: Option<String>,
+    #[serde(default)]
+    everywhere: bool,
+}
+
+#[derive(Serialize)]
+pub struct RevokeResponse {
+    pub success: bool,
+}
+
+pub async fn delete(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(auth_user): Extension<crate::middleware::user::AuthenticatedUser>,
+    State(app_state): State<AppState>,
+    Json(req): Json<RevokeRequest>,
+) -> Result<Json<RevokeResponse>, AppError> {
+    let target = Target::from(req.session_id, req.grant_id, req.client_id, req.everywhere)
+        .ok_or_else(|| AppError::bad_request("Give exactly one of session_id, grant_id, client_id or everywhere"))?;
+
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+    if !revoke(&auth_user.user.id, &target, ip, &app_state.db).await? {
+        return Err(AppError::not_found("Nothing to revoke"));
+    }
+    Ok(Json(RevokeResponse { success: true }))
+}
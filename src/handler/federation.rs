@@ -0,0 +1,482 @@
+use super::auth::OAuthParams;
+use crate::AppState;
+use crate::error::{AppError, FormResponse, HtmlError, OptionExt};
+use crate::templates::{IdentitiesTemplate, IdentityView, ProviderLinkView};
+use crate::util::{get_cookie, set_cookie};
+use askama::Template;
+use axum::{
+    Form,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, HeaderValue, header},
+    response::{AppendHeaders, Html, IntoResponse, Redirect, Response},
+};
+use sea_orm::*;
+use serde::Deserialize;
+use std::{collections::HashMap, net::SocketAddr};
+
+// ties the trip to the browser that started it, or a callback link from someone else's attempt
+// would sign this browser in as them
+const STATE_COOKIE: &str = "sjallabong_federation";
+
+// "continue with x", off to the provider
+pub async fn start(
+    Path(provider_id): Path<String>,
+    Query(oauth): Query<OAuthParams>,
+    State(app_state): State<AppState>,
+) -> Result<Response, HtmlError> {
+    let provider = crate::federation::provider(&provider_id).or_not_found("Unknown provider")?;
+
+    // checked now rather than after the round trip
+    let client = crate::util::get_client(&oauth.client_id, &app_state.db).await?;
+    crate::util::validate_redirect_uri(&client, &oauth.redirect_uri)?;
+
+    let state =
+        crate::token::federation::Entity::create(&provider.id, &serde_json::to_string(&oauth)?, None, &app_state.db)
+            .await?;
+    let url = provider.authorization_url(&state).await.map_err(|e| {
+        tracing::warn!(provider = %provider.id, "Federated login unavailable: {e:#}");
+        AppError::bad_request(format!("{} sign-in isn't available right now", provider.name))
+    })?;
+
+    let max_age = crate::lifetime::federation().num_seconds();
+    Ok((
+        AppendHeaders([(
+            header::SET_COOKIE,
+            set_cookie(STATE_COOKIE, &state.state, max_age, true),
+        )]),
+        Redirect::to(&url),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct CallbackParams {
+    state: Option<String>,
+    code: Option<String>,
+    error: Option<String>,
+}
+
+pub async fn callback(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<CallbackParams>,
+    State(app_state): State<AppState>,
+) -> Result<Response, HtmlError> {
+    let state = params.state.clone().ok_or_else(expired)?;
+    if get_cookie(&headers, STATE_COOKIE).as_deref() != Some(state.as_str()) {
+        return Err(expired().into());
+    }
+    let state = crate::token::federation::Entity::consume(&state, &app_state.db)
+        .await?
+        .ok_or_else(expired)?;
+    let provider = crate::federation::provider(&state.provider).ok_or_else(expired)?;
+
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+    let mut response = match &state.link_user_id {
+        Some(user_id) => finish_link(provider, &state, user_id, params, &ip, &headers, &app_state)
+            .await?
+            .into_response(),
+        None => login(provider, &state, params, ip, &headers, &app_state).await?,
+    };
+    response.headers_mut().append(
+        header::SET_COOKIE,
+        HeaderValue::from_str(&set_cookie(STATE_COOKIE, "", 0, true)).map_err(anyhow::Error::from)?,
+    );
+    Ok(response)
+}
+
+fn expired() -> AppError {
+    AppError::bad_request("This sign-in expired, start over")
+}
+
+async fn login(
+    provider: &crate::federation::Provider,
+    state: &crate::token::federation::Model,
+    params: CallbackParams,
+    ip: String,
+    headers: &HeaderMap,
+    app_state: &AppState,
+) -> Result<Response, AppError> {
+    let oauth: OAuthParams = serde_json::from_str(&state.oauth)?;
+
+    // cancelled at the provider, back to our form
+    if let Some(error) = params.error {
+        tracing::info!(provider = %provider.id, %error, "Federated login declined");
+        return Ok(Redirect::to(&format!("/authorize?{}", oauth.query())).into_response());
+    }
+
+    let code = params.code.or_bad_request("Missing code")?;
+    let identity = provider.identity(&code, state).await.map_err(|e| {
+        tracing::warn!(provider = %provider.id, "Federated login failed: {e:#}");
+        AppError::bad_request(format!("Couldn't sign in with {}, try again", provider.name))
+    })?;
+
+    let user = resolve_user(provider, identity, &ip, app_state).await?;
+
+    let client = crate::util::get_client(&oauth.client_id, &app_state.db).await?;
+    crate::util::validate_redirect_uri(&client, &oauth.redirect_uri)?;
+
+    // the provider stands in for the password, not for a second factor set up here
+    if crate::user::Entity::has_second_factor(&user, &app_state.db).await? {
+        let challenge = crate::token::mfa::Entity::create(&user.id, "fed", &app_state.db).await?;
+        return Ok(crate::handler::totp::challenge_page(
+            &oauth,
+            &challenge.token,
+            &user,
+            HashMap::new(),
+            &app_state.db,
+        )
+        .await?
+        .into_response());
+    }
+    crate::handler::auth::sign_in(&user, &["fed"], &oauth, &client, ip, headers, app_state).await
+}
+
+// the linked account, else an existing one with the same email verified on both sides, else a new one
+async fn resolve_user(
+    provider: &crate::federation::Provider,
+    identity: crate::federation::Identity,
+    ip: &str,
+    app_state: &AppState,
+) -> Result<crate::user::Model, AppError> {
+    let db = &app_state.db;
+
+    let linked = crate::federated_identity::Entity::find_by_id((provider.id.clone(), identity.subject.clone()))
+        .one(db)
+        .await?;
+    if let Some(linked) = linked {
+        let user = crate::user::Entity::find_by_id(&linked.user_id)
+            .one(db)
+            .await?
+            .or_unauthorized("This account no longer exists")?;
+        crate::federated_identity::Entity::used(linked, identity.email.as_deref(), db).await?;
+        return Ok(user);
+    }
+
+    let email = identity
+        .email
+        .as_deref()
+        .or_bad_request(format!("{} didn't share an email address", provider.name))?;
+
+    if let Some(user) = crate::user::Entity::find_by_email(email, db).await? {
+        // only an address the provider checked says it's the same person
+        if !identity.email_verified {
+            return Err(AppError::bad_request(format!(
+                "There's already an account with this email. Sign in with your password, {} didn't confirm the address",
+                provider.name
+            )));
+        }
+        // anyone can register with someone else's address and set a password, linking to that would
+        // hand them the real owner's sign ins
+        if !user.is_verified {
+            return Err(AppError::bad_request(format!(
+                "There's already an account with this email that hasn't confirmed it. Sign in with its password and link {} from your account",
+                provider.name
+            )));
+        }
+
+        let txn = db.begin().await?;
+        crate::federated_identity::Entity::link(&provider.id, &identity.subject, &user.id, Some(email), &txn).await?;
+        crate::security_event::Entity::record(
+            &user.id,
+            crate::security_event::FEDERATED_LINKED,
+            Some(ip.to_string()),
+            &txn,
+        )
+        .await?;
+        txn.commit().await?;
+
+        tracing::info!(user_id = %user.id, provider = %provider.id, "Linked upstream identity by email");
+        return Ok(user);
+    }
+
+    match crate::handler::register::mode() {
+        crate::handler::register::Mode::Open => {}
+        crate::handler::register::Mode::Invite => {
+            return Err(AppError::forbidden(format!(
+                "New accounts need an invite. Register with it, then link {} from your account",
+                provider.name
+            )));
+        }
+        crate::handler::register::Mode::Closed => return Err(AppError::forbidden("Registration is closed")),
+    }
+
+    let username = available_username(identity.username.as_deref(), email, db).await?;
+    // no usable password until they set one through a reset
+    let password_hash = app_state.password.hash(&crate::util::generate_random_string(32))?;
+
+    let txn = db.begin().await?;
+    let user = crate::user::ActiveModel {
+        email: Set(email.to_string()),
+        username: Set(username),
+        password_hash: Set(password_hash),
+        has_password: Set(false),
+        is_verified: Set(identity.email_verified),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+    crate::federated_identity::Entity::link(&provider.id, &identity.subject, &user.id, Some(email), &txn).await?;
+    txn.commit().await?;
+
+    tracing::info!(user_id = %user.id, provider = %provider.id, "Registered through upstream provider");
+    crate::handler::verify_email::send(&user, app_state).await?;
+    Ok(user)
+}
+
+// the provider's name for them, squeezed into our username rules, with a number on if it's taken
+async fn available_username(preferred: Option<&str>, email: &str, db: &DatabaseConnection) -> Result<String, AppError> {
+    let raw = preferred.unwrap_or_else(|| email.split('@').next().unwrap_or_default());
+    let mut base: String = raw
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '_' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(20)
+        .collect();
+    if base.len() < 3 {
+        base = "user".to_string();
+    }
+
+    if !crate::user::Entity::username_taken(&base, None, db).await? {
+        return Ok(base);
+    }
+    for n in 2..100 {
+        let candidate = format!("{base}{n}");
+        if !crate::user::Entity::username_taken(&candidate, None, db).await? {
+            return Ok(candidate);
+        }
+    }
+    Ok(format!("{base}{}", crate::util::generate_random_string(4)))
+}
+
+async fn account_page(
+    session: &crate::session::Model,
+    user: &crate::user::Model,
+    error: Option<&str>,
+    db: &DatabaseConnection,
+) -> Result<Html<String>, AppError> {
+    let linked = crate::federated_identity::Entity::for_user(&user.id, db).await?;
+
+    let mut identities = Vec::new();
+    for identity in &linked {
+        identities.push(IdentityView {
+            provider: identity.provider.clone(),
+            // a provider taken out of the config still shows, by id
+            provider_name: crate::federation::provider(&identity.provider)
+                .map_or_else(|| identity.provider.clone(), |provider| provider.name.clone()),
+            subject: identity.subject.clone(),
+            email: identity.email.clone(),
+            created_at: identity.created_at.format("%Y-%m-%d").to_string(),
+            last_login_at: identity.last_login_at.map(|at| at.format("%Y-%m-%d").to_string()),
+            // one per form, they're single use
+            csrf_token: crate::util::generate_csrf_token().await,
+        });
+    }
+
+    let mut providers = Vec::new();
+    for provider in crate::federation::providers() {
+        if linked.iter().any(|identity| identity.provider == provider.id) {
+            continue;
+        }
+        providers.push(ProviderLinkView {
+            id: provider.id.clone(),
+            name: provider.name.clone(),
+            csrf_token: crate::util::generate_csrf_token().await,
+        });
+    }
+
+    let template = IdentitiesTemplate {
+        username: user.username.clone(),
+        identities,
+        providers,
+        fresh: session.is_fresh(),
+        has_password: user.has_password,
+        error: error.map(str::to_string),
+    };
+    Ok(Html(template.render()?))
+}
+
+pub async fn account_get(headers: HeaderMap, State(app_state): State<AppState>) -> Result<Html<String>, HtmlError> {
+    let (session, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+    Ok(account_page(&session, &user, None, &app_state.db).await?)
+}
+
+#[derive(Deserialize)]
+pub struct LinkForm {
+    provider: String,
+    #[serde(default)]
+    password: Option<String>,
+    csrf_token: String,
+}
+
+// "link x", off to the provider with the account to attach it to
+pub async fn link(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Form(form): Form<LinkForm>,
+) -> Result<Response, HtmlError> {
+    let (session, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+    let render_error = async |msg: &str| -> Result<Response, HtmlError> {
+        Ok(account_page(&session, &user, Some(msg), &app_state.db)
+            .await?
+            .into_response())
+    };
+
+    if !crate::util::validate_csrf_token(&form.csrf_token).await {
+        return render_error("Invalid request, try again").await;
+    }
+    let provider = crate::federation::provider(&form.provider).or_not_found("Unknown provider")?;
+    let linked = crate::federated_identity::Entity::for_user(&user.id, &app_state.db).await?;
+    if linked.iter().any(|identity| identity.provider == provider.id) {
+        return render_error(&format!("You've already linked a {} account", provider.name)).await;
+    }
+
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+    let reauth = crate::handler::session::reauthenticate(&session, &user, form.password.as_deref(), &ip, &app_state);
+    if let Some(error) = reauth.await? {
+        return render_error(error).await;
+    }
+
+    let state = crate::token::federation::Entity::create(&provider.id, "", Some(&user.id), &app_state.db).await?;
+    let url = provider.authorization_url(&state).await.map_err(|e| {
+        tracing::warn!(provider = %provider.id, "Federated login unavailable: {e:#}");
+        AppError::bad_request(format!("{} sign-in isn't available right now", provider.name))
+    })?;
+
+    let max_age = crate::lifetime::federation().num_seconds();
+    Ok((
+        AppendHeaders([(
+            header::SET_COOKIE,
+            set_cookie(STATE_COOKIE, &state.state, max_age, true),
+        )]),
+        Redirect::to(&url),
+    )
+        .into_response())
+}
+
+// back from the provider with the account to attach
+async fn finish_link(
+    provider: &crate::federation::Provider,
+    state: &crate::token::federation::Model,
+    user_id: &str,
+    params: CallbackParams,
+    ip: &str,
+    headers: &HeaderMap,
+    app_state: &AppState,
+) -> Result<Response, AppError> {
+    let db = &app_state.db;
+    // still the same person signed in here as when it started
+    let (session, user) = crate::handler::session::signed_in(headers, db).await?;
+    if user.id != user_id {
+        return Err(expired());
+    }
+
+    if let Some(error) = params.error {
+        tracing::info!(provider = %provider.id, %error, "Federated link declined");
+        return Ok(Redirect::to("/account/identities").into_response());
+    }
+
+    let code = params.code.or_bad_request("Missing code")?;
+    let identity = provider.identity(&code, state).await.map_err(|e| {
+        tracing::warn!(provider = %provider.id, "Federated link failed: {e:#}");
+        AppError::bad_request(format!("Couldn't link {}, try again", provider.name))
+    })?;
+
+    let render_error = async |msg: String| -> Result<Response, AppError> {
+        Ok(account_page(&session, &user, Some(&msg), db).await?.into_response())
+    };
+
+    let existing = crate::federated_identity::Entity::find_by_id((provider.id.clone(), identity.subject.clone()))
+        .one(db)
+        .await?;
+    if let Some(existing) = existing {
+        if existing.user_id == user.id {
+            return Ok(Redirect::to("/account/identities").into_response());
+        }
+        return render_error(format!(
+            "This {} account is already linked to another sjallabong account, unlink it there first",
+            provider.name
+        ))
+        .await;
+    }
+
+    // login matches by email when nothing's linked, so the address belongs with the account it names
+    if let Some(email) = &identity.email
+        && let Some(owner) = crate::user::Entity::find_by_email(email, db).await?
+        && owner.id != user.id
+    {
+        tracing::info!(user_id = %user.id, other_user_id = %owner.id, provider = %provider.id, "Federated link conflicts by email");
+        return render_error(format!(
+            "This {} account's email belongs to another sjallabong account. Sign in to that one to link it",
+            provider.name
+        ))
+        .await;
+    }
+
+    let txn = db.begin().await?;
+    crate::federated_identity::Entity::link(
+        &provider.id,
+        &identity.subject,
+        &user.id,
+        identity.email.as_deref(),
+        &txn,
+    )
+    .await?;
+    crate::security_event::Entity::record(
+        &user.id,
+        crate::security_event::FEDERATED_LINKED,
+        Some(ip.to_string()),
+        &txn,
+    )
+    .await?;
+    txn.commit().await?;
+
+    tracing::info!(user_id = %user.id, provider = %provider.id, "Linked upstream identity");
+    Ok(Redirect::to("/account/identities").into_response())
+}
+
+#[derive(Deserialize)]
+pub struct UnlinkForm {
+    provider: String,
+    subject: String,
+    csrf_token: String,
+}
+
+pub async fn unlink(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Form(form): Form<UnlinkForm>,
+) -> Result<FormResponse<Redirect>, HtmlError> {
+    let (session, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+
+    let error = if !crate::util::validate_csrf_token(&form.csrf_token).await {
+        Some("Invalid request, try again")
+    } else if crate::user::Entity::sign_in_methods(&user, &app_state.db).await? <= 1 {
+        Some("This is the only way you can sign in. Set a password through \"Forgot password\" or add a passkey first")
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        return Ok(FormResponse::ValidationErrors(
+            account_page(&session, &user, Some(error), &app_state.db).await?,
+        ));
+    }
+
+    let txn = app_state.db.begin().await?;
+    if crate::federated_identity::Entity::unlink(&form.provider, &form.subject, &user.id, &txn).await? {
+        let ip = crate::handler::geoloc::client_ip(&headers, addr);
+        crate::security_event::Entity::record(&user.id, crate::security_event::FEDERATED_UNLINKED, Some(ip), &txn)
+            .await?;
+        tracing::info!(user_id = %user.id, provider = %form.provider, "Unlinked upstream identity");
+    }
+    txn.commit().await?;
+
+    Ok(FormResponse::Success(Redirect::to("/account/identities")))
+}
@@ -1,8 +1,9 @@
 use axum::http::HeaderMap;
 use axum::{Json, extract::Query};
 use serde::Deserialize;
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::sync::LazyLock;
 
 use crate::IS_PRODUCTION;
 
@@ -30,14 +31,81 @@ pub async fn get_country_from_ip(ip_str: &str) -> Option<String> {
     country_data.country?.iso_code.map(std::string::ToString::to_string)
 }
 
-// get ip from headers
-pub fn get_forwarded_ip(headers: &HeaderMap) -> Option<String> {
+// a proxy address or range, "10.0.0.0/8"
+struct Network {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl Network {
+    fn parse(s: &str) -> Option<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr.parse().ok()?, Some(prefix.parse().ok()?)),
+            None => (s.parse().ok()?, None),
+        };
+        let bits = if matches!(addr, IpAddr::V4(_)) { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+        (prefix <= bits).then_some(Self { addr, prefix })
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        let mask = |bits: u32| u128::MAX.checked_shl(bits - self.prefix).unwrap_or(0);
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                (u128::from(u32::from(net)) ^ u128::from(u32::from(*ip))) & mask(32) & u128::from(u32::MAX) == 0
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net) ^ u128::from(*ip)) & mask(128) == 0,
+            _ => false,
+        }
+    }
+}
+
+// proxies in front of us, TRUSTED_PROXIES as comma separated addresses or ranges. only their forwarded
+// headers count, anyone else could put any address in them
+static TRUSTED_PROXIES: LazyLock<Vec<Network>> = LazyLock::new(|| {
+    std::env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| Network::parse(s).unwrap_or_else(|| panic!("Invalid TRUSTED_PROXIES entry: {s}")))
+        .collect()
+});
+
+pub fn trusted_proxies() -> usize {
+    TRUSTED_PROXIES.len()
+}
+
+fn is_trusted(ip: &IpAddr) -> bool {
+    TRUSTED_PROXIES.iter().any(|network| network.contains(ip))
+}
+
+// who's on the other end: the peer, or what a trusted proxy in between says it was
+pub fn client_ip(headers: &HeaderMap, peer: SocketAddr) -> String {
+    if is_trusted(&peer.ip())
+        && let Some(ip) = get_forwarded_ip(headers)
+    {
+        return ip;
+    }
+    peer.ip().to_string()
+}
+
+// get ip from headers, only to be believed from a trusted proxy
+fn get_forwarded_ip(headers: &HeaderMap) -> Option<String> {
     if let Some(cf_ip) = headers.get("cf-connecting-ip").and_then(|h| h.to_str().ok()) {
         return Some(cf_ip.to_string());
     }
 
+    // each proxy appends who it heard from, so the nearest one that isn't ours. the first is whatever the
+    // client sent
     if let Some(forwarded) = headers.get("x-forwarded-for").and_then(|h| h.to_str().ok()) {
-        return forwarded.split(',').next().map(|ip| ip.trim().to_string());
+        let hops: Vec<&str> = forwarded.split(',').map(str::trim).collect();
+        return hops
+            .iter()
+            .rev()
+            .find(|hop| hop.parse::<IpAddr>().map_or(true, |ip| !is_trusted(&ip)))
+            .or(hops.first())
+            .map(|ip| ip.to_string());
     }
 
     headers
@@ -0,0 +1,514 @@
+use crate::{
+    AppState,
+    error::{AppError, OptionExt},
+    group_member::{ADMIN, MEMBER, OWNER},
+    group_request::{INVITE, JOIN},
+    middleware::user::AuthenticatedUser,
+};
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+};
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// groups over the api. user ids in and out are the subs the calling client knows, like `update::user`
+
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Serialize)]
+pub struct GroupInfo {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub created_at: DateTime<Utc>,
+    // the caller's, None if they're not in it
+    pub role: Option<String>,
+    // members only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub members: Option<Vec<MemberInfo>>,
+}
+
+#[derive(Serialize)]
+pub struct MemberInfo {
+    pub user_id: String,
+    pub role: String,
+    pub joined_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct RequestInfo {
+    pub group_id: String,
+    pub user_id: String,
+    // `group_request::INVITE` or `JOIN`
+    pub kind: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+pub struct SuccessResponse {
+    pub success: bool,
+}
+
+// owners above admins above members. only ever act on someone ranked below you
+fn rank(role: Option<&str>) -> u8 {
+    match role {
+        Some(OWNER) => 2,
+        Some(ADMIN) => 1,
+        _ => 0,
+    }
+}
+
+struct Context {
+    client: crate::client::Model,
+    group: crate::group::Model,
+    // the caller's place in the group. groups:manage acts as the owner
+    acting_as: Option<String>,
+}
+
+impl Context {
+    async fn load(auth_user: &AuthenticatedUser, group_id: &str, db: &DatabaseConnection) -> Result<Self, AppError> {
+        let client = crate::util::get_client(&auth_user.access_token.client_id, db).await?;
+        let group = crate::group::Entity::find_by_id(group_id)
+            .one(db)
+            .await?
+            .or_not_found(format!("Group not found: {group_id}"))?;
+        let member = crate::group_member::Entity::find_member(&group.id, &auth_user.user.id, db).await?;
+        let acting_as = if auth_user.can(crate::permission::GROUPS_MANAGE) {
+            Some(OWNER.to_string())
+        } else {
+            member.map(|member| member.role)
+        };
+        Ok(Self {
+            client,
+            group,
+            acting_as,
+        })
+    }
+
+    fn rank(&self) -> u8 {
+        rank(self.acting_as.as_deref())
+    }
+
+    fn require_manager(&self) -> Result<(), AppError> {
+        if self.rank() < rank(Some(ADMIN)) {
+            return Err(AppError::forbidden("Only the group's owner and admins can do that"));
+        }
+        Ok(())
+    }
+
+    // a user named by the client
+    async fn user_id(&self, sub: &str, db: &DatabaseConnection) -> Result<String, AppError> {
+        let user_id = crate::subject::Entity::user_id(&self.client, sub, db)
+            .await?
+            .or_not_found(format!("User not found: {sub}"))?;
+        let user = crate::user::Entity::find_by_id(&user_id)
+            .one(db)
+            .await?
+            .or_not_found(format!("User not found: {sub}"))?;
+        if user.deleted_at.is_some() {
+            return Err(AppError::not_found(format!("User not found: {sub}")));
+        }
+        Ok(user.id)
+    }
+
+    fn request_info(&self, request: crate::group_request::Model) -> RequestInfo {
+        RequestInfo {
+            group_id: request.group_id,
+            user_id: self.client.subject_for(&request.user_id),
+            kind: request.kind,
+            created_at: request.created_at,
+        }
+    }
+
+    async fn info(&self, db: &DatabaseConnection) -> Result<GroupInfo, AppError> {
+        let members = match self.acting_as {
+            Some(_) => Some(
+                crate::group_member::Entity::for_group(&self.group.id, db)
+                    .await?
+                    .into_iter()
+                    .map(|member| MemberInfo {
+                        user_id: self.client.subject_for(&member.user_id),
+                        role: member.role,
+                        joined_at: member.created_at,
+                    })
+                    .collect(),
+            ),
+            None => None,
+        };
+        Ok(GroupInfo {
+            id: self.group.id.clone(),
+            name: self.group.name.clone(),
+            description: self.group.description.clone(),
+            created_at: self.group.created_at,
+            role: self.acting_as.clone(),
+            members,
+        })
+    }
+}
+
+fn validate_name(name: &str) -> Result<String, AppError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(AppError::bad_request(format!(
+            "Group names are 1 to {MAX_NAME_LENGTH} characters"
+        )));
+    }
+    Ok(name.to_string())
+}
+
+#[derive(Serialize)]
+pub struct GroupsResponse {
+    pub groups: Vec<crate::group_member::Membership>,
+    // invites waiting on the caller, and their own join requests
+    pub requests: Vec<RequestInfo>,
+}
+
+pub async fn list(
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    State(app_state): State<AppState>,
+) -> Result<Json<GroupsResponse>, AppError> {
+    let groups = crate::group_member::Entity::memberships(&auth_user.user.id, &app_state.db).await?;
+    let requests = crate::group_request::Entity::for_user(&auth_user.user.id, &app_state.db)
+        .await?
+        .into_iter()
+        .map(|request| RequestInfo {
+            group_id: request.group_id,
+            user_id: auth_user.sub.clone(),
+            kind: request.kind,
+            created_at: request.created_at,
+        })
+        .collect();
+    Ok(Json(GroupsResponse { groups, requests }))
+}
+
+#[derive(Deserialize)]
+pub struct CreateGroupRequest {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+// the caller owns it
+pub async fn create(
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    State(app_state): State<AppState>,
+    Json(req): Json<CreateGroupRequest>,
+) -> Result<Json<GroupInfo>, AppError> {
+    let name = validate_name(&req.name)?;
+
+    let txn = app_state.db.begin().await?;
+    let group = crate::group::ActiveModel {
+        name: Set(name),
+        description: Set(req.description),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+    crate::group_member::Entity::add(&group.id, &auth_user.user.id, OWNER, &txn).await?;
+    txn.commit().await?;
+
+    tracing::info!(group_id = %group.id, user_id = %auth_user.user.id, "Group created");
+    let context = Context::load(&auth_user, &group.id, &app_state.db).await?;
+    Ok(Json(context.info(&app_state.db).await?))
+}
+
+pub async fn get(
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(group_id): Path<String>,
+    State(app_state): State<AppState>,
+) -> Result<Json<GroupInfo>, AppError> {
+    let context = Context::load(&auth_user, &group_id, &app_state.db).await?;
+    Ok(Json(context.info(&app_state.db).await?))
+}
+
+#[derive(Deserialize)]
+pub struct UpdateGroupRequest {
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+pub async fn update(
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(group_id): Path<String>,
+    State(app_state): State<AppState>,
+    Json(req): Json<UpdateGroupRequest>,
+) -> Result<Json<GroupInfo>, AppError> {
+    let mut context = Context::load(&auth_user, &group_id, &app_state.db).await?;
+    context.require_manager()?;
+
+    let mut update: crate::group::ActiveModel = context.group.clone().into();
+    if let Some(name) = &req.name {
+        update.name = Set(validate_name(name)?);
+    }
+    if let Some(description) = req.description {
+        update.description = Set(Some(description).filter(|d| !d.trim().is_empty()));
+    }
+    update.updated_at = Set(Utc::now());
+    context.group = update.update(&app_state.db).await?;
+
+    Ok(Json(context.info(&app_state.db).await?))
+}
+
+pub async fn delete(
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(group_id): Path<String>,
+    State(app_state): State<AppState>,
+) -> Result<Json<SuccessResponse>, AppError> {
+    let context = Context::load(&auth_user, &group_id, &app_state.db).await?;
+    if context.rank() < rank(Some(OWNER)) {
+        return Err(AppError::forbidden("Only the group's owner can delete it"));
+    }
+
+    let txn = app_state.db.begin().await?;
+    crate::group::Entity::remove(&group_id, &txn).await?;
+    txn.commit().await?;
+
+    tracing::info!(%group_id, user_id = %auth_user.user.id, "Group deleted");
+    Ok(Json(SuccessResponse { success: true }))
+}
+
+#[derive(Deserialize)]
+pub struct UserRequest {
+    pub user_id: String,
+}
+
+#[derive(Serialize)]
+pub struct JoinResponse {
+    pub success: bool,
+    // false while it waits on the other side
+    pub member: bool,
+}
+
+// an invite that meets a join request, or the other way around, makes a member
+async fn admit(context: &Context, user_id: &str, db: &DatabaseConnection) -> Result<(), AppError> {
+    let txn = db.begin().await?;
+    crate::group_request::Entity::resolve(&context.group.id, user_id, &txn).await?;
+    crate::group_member::Entity::add(&context.group.id, user_id, MEMBER, &txn).await?;
+    txn.commit().await?;
+    tracing::info!(group_id = %context.group.id, %user_id, "Group member added");
+    Ok(())
+}
+
+pub async fn invite(
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(group_id): Path<String>,
+    State(app_state): State<AppState>,
+    Json(req): Json<UserRequest>,
+) -> Result<Json<JoinResponse>, AppError> {
+    let context = Context::load(&auth_user, &group_id, &app_state.db).await?;
+    context.require_manager()?;
+    let user_id = context.user_id(&req.user_id, &app_state.db).await?;
+
+    if crate::group_member::Entity::find_member(&group_id, &user_id, &app_state.db)
+        .await?
+        .is_some()
+    {
+        return Err(AppError::bad_request("They're already in the group"));
+    }
+    match crate::group_request::Entity::pending(&group_id, &user_id, &app_state.db).await? {
+        Some(request) if request.kind == JOIN => {
+            admit(&context, &user_id, &app_state.db).await?;
+            return Ok(Json(JoinResponse {
+                success: true,
+                member: true,
+            }));
+        }
+        Some(_) => return Err(AppError::bad_request("They're already invited")),
+        None => {}
+    }
+
+    crate::group_request::Entity::create(&group_id, &user_id, INVITE, &auth_user.user.id, &app_state.db).await?;
+    tracing::info!(%group_id, %user_id, invited_by = %auth_user.user.id, "Group invite sent");
+    Ok(Json(JoinResponse {
+        success: true,
+        member: false,
+    }))
+}
+
+// asks to join, or accepts an invite
+pub async fn join(
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(group_id): Path<String>,
+    State(app_state): State<AppState>,
+) -> Result<Json<JoinResponse>, AppError> {
+    let context = Context::load(&auth_user, &group_id, &app_state.db).await?;
+    let user_id = &auth_user.user.id;
+
+    if crate::group_member::Entity::find_member(&group_id, user_id, &app_state.db)
+        .await?
+        .is_some()
+    {
+        return Err(AppError::bad_request("You're already in the group"));
+    }
+    match crate::group_request::Entity::pending(&group_id, user_id, &app_state.db).await? {
+        Some(request) if request.kind == INVITE => {
+            admit(&context, user_id, &app_state.db).await?;
+            return Ok(Json(JoinResponse {
+                success: true,
+                member: true,
+            }));
+        }
+        Some(_) => return Err(AppError::bad_request("You've already asked to join")),
+        None => {}
+    }
+
+    crate::group_request::Entity::create(&group_id, user_id, JOIN, user_id, &app_state.db).await?;
+    tracing::info!(%group_id, %user_id, "Group join requested");
+    Ok(Json(JoinResponse {
+        success: true,
+        member: false,
+    }))
+}
+
+#[derive(Serialize)]
+pub struct RequestsResponse {
+    pub requests: Vec<RequestInfo>,
+}
+
+// everything pending, invites out and join requests in
+pub async fn requests(
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(group_id): Path<String>,
+    State(app_state): State<AppState>,
+) -> Result<Json<RequestsResponse>, AppError> {
+    let context = Context::load(&auth_user, &group_id, &app_state.db).await?;
+    context.require_manager()?;
+
+    let requests = crate::group_request::Entity::for_group(&group_id, &app_state.db)
+        .await?
+        .into_iter()
+        .map(|request| context.request_info(request))
+        .collect();
+    Ok(Json(RequestsResponse { requests }))
+}
+
+pub async fn approve(
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(group_id): Path<String>,
+    State(app_state): State<AppState>,
+    Json(req): Json<UserRequest>,
+) -> Result<Json<SuccessResponse>, AppError> {
+    let context = Context::load(&auth_user, &group_id, &app_state.db).await?;
+    context.require_manager()?;
+    let user_id = context.user_id(&req.user_id, &app_state.db).await?;
+
+    crate::group_request::Entity::pending(&group_id, &user_id, &app_state.db)
+        .await?
+        .filter(|request| request.kind == JOIN)
+        .or_not_found("No join request from them")?;
+    admit(&context, &user_id, &app_state.db).await?;
+    Ok(Json(SuccessResponse { success: true }))
+}
+
+#[derive(Deserialize)]
+pub struct DeclineRequest {
+    // someone else's, for the group's owner and admins. the caller's own invite or join request otherwise
+    pub user_id: Option<String>,
+}
+
+pub async fn decline(
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(group_id): Path<String>,
+    State(app_state): State<AppState>,
+    Json(req): Json<DeclineRequest>,
+) -> Result<Json<SuccessResponse>, AppError> {
+    let context = Context::load(&auth_user, &group_id, &app_state.db).await?;
+    let user_id = match &req.user_id {
+        Some(sub) if *sub != auth_user.sub => {
+            context.require_manager()?;
+            context.user_id(sub, &app_state.db).await?
+        }
+        _ => auth_user.user.id.clone(),
+    };
+
+    if !crate::group_request::Entity::resolve(&group_id, &user_id, &app_state.db).await? {
+        return Err(AppError::not_found("Nothing pending"));
+    }
+    Ok(Json(SuccessResponse { success: true }))
+}
+
+#[derive(Deserialize)]
+pub struct SetRoleRequest {
+    pub user_id: String,
+    // see `group_member::ROLES`. making someone owner hands the group over, the old owner stays as an admin
+    pub role: String,
+}
+
+pub async fn set_role(
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(group_id): Path<String>,
+    State(app_state): State<AppState>,
+    Json(req): Json<SetRoleRequest>,
+) -> Result<Json<GroupInfo>, AppError> {
+    let context = Context::load(&auth_user, &group_id, &app_state.db).await?;
+    if context.rank() < rank(Some(OWNER)) {
+        return Err(AppError::forbidden("Only the group's owner can change roles"));
+    }
+    if !crate::group_member::ROLES.contains(&req.role.as_str()) {
+        return Err(AppError::bad_request(format!("Unknown group role: {}", req.role)));
+    }
+    let user_id = context.user_id(&req.user_id, &app_state.db).await?;
+    let member = crate::group_member::Entity::find_member(&group_id, &user_id, &app_state.db)
+        .await?
+        .or_not_found("They're not in the group")?;
+    if member.role == OWNER && req.role != OWNER {
+        return Err(AppError::bad_request("Make someone else the owner first"));
+    }
+
+    let txn = app_state.db.begin().await?;
+    if req.role == OWNER && member.role != OWNER {
+        let owners = crate::group_member::Entity::find()
+            .filter(crate::group_member::Column::GroupId.eq(&group_id))
+            .filter(crate::group_member::Column::Role.eq(OWNER))
+            .all(&txn)
+            .await?;
+        for owner in owners {
+            crate::group_member::Entity::set_role(owner, ADMIN, &txn).await?;
+        }
+    }
+    crate::group_member::Entity::set_role(member, &req.role, &txn).await?;
+    txn.commit().await?;
+
+    tracing::info!(%group_id, %user_id, role = %req.role, changed_by = %auth_user.user.id, "Group role changed");
+    let context = Context::load(&auth_user, &group_id, &app_state.db).await?;
+    Ok(Json(context.info(&app_state.db).await?))
+}
+
+#[derive(Deserialize)]
+pub struct RemoveMemberRequest {
+    // the caller leaves if not given
+    pub user_id: Option<String>,
+}
+
+pub async fn remove_member(
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Path(group_id): Path<String>,
+    State(app_state): State<AppState>,
+    Json(req): Json<RemoveMemberRequest>,
+) -> Result<Json<SuccessResponse>, AppError> {
+    let context = Context::load(&auth_user, &group_id, &app_state.db).await?;
+    let leaving = req.user_id.as_ref().is_none_or(|sub| *sub == auth_user.sub);
+    let user_id = match &req.user_id {
+        Some(sub) if !leaving => context.user_id(sub, &app_state.db).await?,
+        _ => auth_user.user.id.clone(),
+    };
+    let member = crate::group_member::Entity::find_member(&group_id, &user_id, &app_state.db)
+        .await?
+        .or_not_found("They're not in the group")?;
+
+    if member.role == OWNER {
+        return Err(AppError::bad_request(
+            "The owner can't leave, hand the group to someone else or delete it",
+        ));
+    }
+    if !leaving && context.rank() <= rank(Some(&member.role)) {
+        return Err(AppError::forbidden("Insufficient permissions to remove them"));
+    }
+
+    crate::group_member::Entity::delete_by_id(&member.id)
+        .exec(&app_state.db)
+        .await?;
+    tracing::info!(%group_id, %user_id, removed_by = %auth_user.user.id, "Group member removed");
+    Ok(Json(SuccessResponse { success: true }))
+}
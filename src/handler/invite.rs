@@ -0,0 +1,208 @@
+use crate::{
+    AppState,
+    error::{AppError, OptionExt},
+    middleware::user::AuthenticatedUser,
+};
+use axum::{
+    Extension, Json,
+    extract::{Query, State},
+};
+use chrono::{DateTime, Duration, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+// anyone can invite a few friends. roles, and more uses or time, take invites:manage
+const USER_MAX_USES: i32 = 5;
+const USER_MAX_DAYS: i64 = 30;
+const DEFAULT_DAYS: i64 = 7;
+// seats across all of a user's invites in USER_MAX_DAYS, or one account could invite everyone
+const USER_MAX_SEATS: i64 = 10;
+
+#[derive(Serialize)]
+pub struct InviteInfo {
+    pub code: String,
+    // the sub the calling client knows, only when listing everyone's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<String>,
+    pub max_uses: Option<i32>,
+    pub uses: i32,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub roles: Vec<String>,
+    pub client_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl InviteInfo {
+    fn new(invite: crate::invite::Model, created_by: Option<String>) -> Self {
+        Self {
+            roles: invite.roles(),
+            code: invite.code,
+            created_by,
+            max_uses: invite.max_uses,
+            uses: invite.uses,
+            expires_at: invite.expires_at,
+            client_id: invite.client_id,
+            created_at: invite.created_at,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct InvitesQuery {
+    // everyone's, for invites:manage
+    #[serde(default)]
+    pub all: bool,
+}
+
+#[derive(Serialize)]
+pub struct InvitesResponse {
+    pub invites: Vec<InviteInfo>,
+}
+
+pub async fn get(
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    Query(query): Query<InvitesQuery>,
+    State(app_state): State<AppState>,
+) -> Result<Json<InvitesResponse>, AppError> {
+    if !query.all {
+        let invites = crate::invite::Entity::for_creator(&auth_user.user.id, &app_state.db)
+            .await?
+            .into_iter()
+            .map(|invite| InviteInfo::new(invite, None))
+            .collect();
+        return Ok(Json(InvitesResponse { invites }));
+    }
+
+    if !auth_user.can(crate::permission::INVITES_MANAGE) {
+        return Err(AppError::forbidden("Insufficient permissions to list invites"));
+    }
+    let client = crate::util::get_client(&auth_user.access_token.client_id, &app_state.db).await?;
+    let invites = crate::invite::Entity::find()
+        .order_by_desc(crate::invite::Column::CreatedAt)
+        .all(&app_state.db)
+        .await?
+        .into_iter()
+        .map(|invite| {
+            let created_by = client.subject_for(&invite.created_by);
+            InviteInfo::new(invite, Some(created_by))
+        })
+        .collect();
+    Ok(Json(InvitesResponse { invites }))
+}
+
+#[derive(Deserialize)]
+pub struct CreateInviteRequest {
+    // any number if not given, invites:manage only
+    pub max_uses: Option<i32>,
+    // never expires if 0, invites:manage only
+    pub days: Option<i64>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    pub client_id: Option<String>,
+}
+
+pub async fn post(
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    State(app_state): State<AppState>,
+    Json(req): Json<CreateInviteRequest>,
+) -> Result<Json<InviteInfo>, AppError> {
+    let manager = auth_user.can(crate::permission::INVITES_MANAGE);
+    if req.max_uses.is_some_and(|max_uses| max_uses < 1) {
+        return Err(AppError::bad_request("max_uses must be at least 1"));
+    }
+    if req.days.is_some_and(|days| days < 0) {
+        return Err(AppError::bad_request("days can't be negative"));
+    }
+    let days = req.days.unwrap_or(DEFAULT_DAYS);
+    if !manager && (req.max_uses.is_none_or(|max_uses| max_uses > USER_MAX_USES) || days == 0 || days > USER_MAX_DAYS) {
+        return Err(AppError::forbidden(format!(
+            "Invites are up to {USER_MAX_USES} uses and {USER_MAX_DAYS} days"
+        )));
+    }
+    if !manager {
+        let since = Utc::now() - Duration::days(USER_MAX_DAYS);
+        let given = crate::invite::Entity::seats_given(&auth_user.user.id, since, &app_state.db).await?;
+        if given + i64::from(req.max_uses.unwrap_or_default()) > USER_MAX_SEATS {
+            return Err(AppError::forbidden(format!(
+                "You can invite up to {USER_MAX_SEATS} people every {USER_MAX_DAYS} days, {} left",
+                (USER_MAX_SEATS - given).max(0)
+            )));
+        }
+    }
+
+    let can_give_roles = manager && auth_user.can(crate::permission::USERS_ROLES);
+    if !req.roles.is_empty() && !can_give_roles {
+        return Err(AppError::forbidden("Insufficient permissions to invite with roles"));
+    }
+    for role in &req.roles {
+        if role == crate::membership::MEMBER_ROLE {
+            return Err(AppError::bad_request(
+                "The member role follows memberships, see /update/membership",
+            ));
+        }
+        crate::role::Entity::find_by_id(role)
+            .one(&app_state.db)
+            .await?
+            .or_bad_request(format!("Unknown role: {role}"))?;
+    }
+    if let Some(client_id) = &req.client_id {
+        crate::util::get_client(client_id, &app_state.db).await?;
+    }
+
+    let roles = match req.roles.is_empty() {
+        true => None,
+        false => Some(serde_json::to_string(&req.roles)?),
+    };
+    let invite = crate::invite::ActiveModel {
+        created_by: Set(auth_user.user.id.clone()),
+        max_uses: Set(req.max_uses),
+        expires_at: Set((days > 0).then(|| Utc::now() + Duration::days(days))),
+        roles: Set(roles),
+        client_id: Set(req.client_id),
+        ..Default::default()
+    }
+    .insert(&app_state.db)
+    .await?;
+
+    tracing::info!(user_id = %auth_user.user.id, code = %invite.code, roles = ?req.roles, "Invite created");
+    Ok(Json(InviteInfo::new(invite, None)))
+}
+
+#[derive(Deserialize)]
+pub struct DeleteInviteRequest {
+    pub code: String,
+}
+
+#[derive(Serialize)]
+pub struct DeleteInviteResponse {
+    pub success: bool,
+}
+
+// the creator's own, or any for invites:manage. one that was used is closed rather than removed, so its
+// uses still count towards the creator's seats
+pub async fn delete(
+    Extension(auth_user): Extension<AuthenticatedUser>,
+    State(app_state): State<AppState>,
+    Json(req): Json<DeleteInviteRequest>,
+) -> Result<Json<DeleteInviteResponse>, AppError> {
+    let invite = crate::invite::Entity::find_by_id(&req.code)
+        .one(&app_state.db)
+        .await?
+        .or_not_found("Invite not found")?;
+    if invite.created_by != auth_user.user.id && !auth_user.can(crate::permission::INVITES_MANAGE) {
+        return Err(AppError::not_found("Invite not found"));
+    }
+
+    if invite.uses > 0 {
+        let uses = invite.uses;
+        let mut invite: crate::invite::ActiveModel = invite.clone().into();
+        invite.max_uses = Set(Some(uses));
+        invite.update(&app_state.db).await?;
+    } else {
+        crate::invite::Entity::delete_by_id(&invite.code)
+            .exec(&app_state.db)
+            .await?;
+    }
+    tracing::info!(user_id = %auth_user.user.id, code = %invite.code, "Invite deleted");
+    Ok(Json(DeleteInviteResponse { success: true }))
+}
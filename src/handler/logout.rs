@@ -0,0 +1,170 @@
+use crate::AppState;
+use crate::error::{AppError, HtmlError, OptionExt};
+use crate::handler::session::cleared_cookies;
+use crate::templates::{LogoutConfirmTemplate, LogoutTemplate};
+use askama::Template;
+use axum::{
+    Form,
+    extract::{Query, State},
+    http::{HeaderMap, header},
+    response::{AppendHeaders, Html, IntoResponse, Response},
+};
+use sea_orm::*;
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct LogoutParams {
+    client_id: Option<String>,
+    post_logout_redirect_uri: Option<String>,
+    state: Option<String>,
+    id_token_hint: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct LogoutForm {
+    csrf_token: String,
+    #[serde(flatten)]
+    params: LogoutParams,
+}
+
+// where to send the browser afterwards, if the client asked and may
+async fn redirect(params: &LogoutParams, db: &DatabaseConnection) -> Result<Option<String>, AppError> {
+    let Some(uri) = &params.post_logout_redirect_uri else {
+        return Ok(None);
+    };
+    let client_id = params
+        .client_id
+        .as_deref()
+        .or_bad_request("client_id is required with post_logout_redirect_uri")?;
+    let client = crate::util::get_client(client_id, db).await?;
+    crate::util::validate_redirect_uri(&client, uri)?;
+
+    let mut url = url::Url::parse(uri).map_err(|_| AppError::bad_request("Invalid redirect URI"))?;
+    if let Some(state) = &params.state {
+        url.query_pairs_mut().append_pair("state", state);
+    }
+    Ok(Some(url.to_string()))
+}
+
+// a link anyone could put on their page, so it only logs out straight away with an id token from this
+// session. otherwise the user confirms
+pub async fn get(
+    Query(params): Query<LogoutParams>,
+    State(app_state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response, HtmlError> {
+    let redirect = redirect(&params, &app_state.db).await?;
+
+    let Some(session) = crate::handler::session::current(&headers, &app_state.db).await? else {
+        return logged_out(None, redirect, &app_state.db).await;
+    };
+
+    if let Some(hint) = &params.id_token_hint {
+        let claims = crate::jwt::verify_id_token_hint(hint, &app_state.jwk)?;
+        if params
+            .client_id
+            .as_ref()
+            .is_some_and(|client_id| *client_id != claims.base.aud)
+        {
+            return Err(AppError::bad_request("id_token_hint was issued to another client").into());
+        }
+        if claims.sid.as_deref() == Some(session.public_id().as_str()) {
+            return logged_out(Some(session), redirect, &app_state.db).await;
+        }
+    }
+
+    let template = LogoutConfirmTemplate {
+        csrf_token: crate::util::generate_csrf_token().await,
+        client_id: params.client_id.unwrap_or_default(),
+        post_logout_redirect_uri: params.post_logout_redirect_uri.unwrap_or_default(),
+        state: params.state.unwrap_or_default(),
+    };
+    Ok(Html(template.render()?).into_response())
+}
+
+pub async fn post(
+    State(app_state): State<AppState>,
+    headers: HeaderMap,
+    Form(mut form): Form<LogoutForm>,
+) -> Result<Response, HtmlError> {
+    if !crate::util::validate_csrf_token(&form.csrf_token).await {
+        return Err(AppError::bad_request("Invalid request, try again").into());
+    }
+    // the confirm form posts them back empty
+    let params = &mut form.params;
+    for param in [
+        &mut params.client_id,
+        &mut params.post_logout_redirect_uri,
+        &mut params.state,
+    ] {
+        if param.as_deref() == Some("") {
+            *param = None;
+        }
+    }
+
+    let redirect = redirect(params, &app_state.db).await?;
+    let session = crate::handler::session::current(&headers, &app_state.db).await?;
+    logged_out(session, redirect, &app_state.db).await
+}
+
+// ends the sso session with the grants made in it, and frames every client's front-channel logout uri
+async fn logged_out(
+    session: Option<crate::session::Model>,
+    redirect: Option<String>,
+    db: &DatabaseConnection,
+) -> Result<Response, HtmlError> {
+    let ended = match session {
+        Some(session) => {
+            let txn = db.begin().await?;
+            crate::token::refresh::Entity::revoke_session(&session.sid, &txn).await?;
+            let ended = crate::session::Entity::end(&session.sid, &txn).await?;
+            txn.commit().await?;
+            ended
+        }
+        None => None,
+    };
+
+    let mut frames = Vec::new();
+    if let Some(session) = ended {
+        tracing::info!(user_id = %session.user_id, "Session ended");
+        for client_id in session.get_client_ids()? {
+            let Some(client) = crate::client::Entity::find_by_id(&client_id).one(db).await? else {
+                continue;
+            };
+            let Some(uri) = client.frontchannel_logout_uri else {
+                continue;
+            };
+            let Ok(mut url) = url::Url::parse(&uri) else {
+                tracing::warn!(client_id, "Invalid frontchannel_logout_uri");
+                continue;
+            };
+            url.query_pairs_mut()
+                .append_pair("iss", crate::jwt::issuer())
+                .append_pair("sid", &session.public_id());
+            frames.push(url);
+        }
+    }
+
+    let frame_src = frames
+        .iter()
+        .map(|url| url.origin().ascii_serialization())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let csp = format!(
+        "{}; frame-src 'self' {}",
+        crate::middleware::security::content_security_policy(),
+        frame_src
+    );
+
+    let template = LogoutTemplate {
+        frames: frames.into_iter().map(String::from).collect(),
+        redirect,
+    };
+
+    Ok((
+        AppendHeaders(cleared_cookies()),
+        [(header::CONTENT_SECURITY_POLICY, csp)],
+        Html(template.render()?),
+    )
+        .into_response())
+}
@@ -1,8 +1,23 @@
+pub mod account;
+pub mod activity;
 pub mod auth;
+pub mod devices;
+pub mod federation;
 pub mod geoloc;
+pub mod group;
+pub mod invite;
 pub mod jwks;
+pub mod logout;
+pub mod passkey;
+pub mod password_reset;
+pub mod portal;
+pub mod recovery;
 pub mod register;
 pub mod revoke;
+pub mod session;
 pub mod token;
+pub mod totp;
 pub mod update;
 pub mod userinfo;
+pub mod verify_email;
+pub mod webhook;
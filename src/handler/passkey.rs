@@ -0,0 +1,472 @@
+use super::auth::OAuthParams;
+use crate::AppState;
+use crate::error::{AppError, FormResponse, HtmlError, OptionExt};
+use crate::templates::{PasskeyView, PasskeysTemplate};
+use crate::token::webauthn::{LOGIN, REGISTER, SECOND_FACTOR};
+use askama::Template;
+use axum::{
+    Form, Json,
+    extract::{ConnectInfo, State},
+    http::HeaderMap,
+    response::{Html, Redirect, Response},
+};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+// how long the browser gives the user to touch the key, in ms
+const TIMEOUT: u64 = 5 * 60 * 1000;
+
+fn decode(field: &str, value: &str) -> Result<Vec<u8>, AppError> {
+    URL_SAFE_NO_PAD
+        .decode(value)
+        .map_err(|_| AppError::bad_request(format!("Invalid {field}")))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    id: String,
+    transports: Vec<String>,
+}
+
+impl From<&crate::passkey::Model> for CredentialDescriptor {
+    fn from(passkey: &crate::passkey::Model) -> Self {
+        Self {
+            kind: "public-key",
+            id: passkey.id.clone(),
+            transports: passkey.get_transports().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RelyingParty {
+    id: &'static str,
+    name: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserEntity {
+    id: String,
+    name: String,
+    display_name: String,
+}
+
+#[derive(Serialize)]
+pub struct CredentialParameters {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    alg: i64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    resident_key: &'static str,
+    user_verification: &'static str,
+}
+
+// PublicKeyCredentialCreationOptions, with buffers as base64url
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreationOptions {
+    challenge: String,
+    rp: RelyingParty,
+    user: UserEntity,
+    pub_key_cred_params: Vec<CredentialParameters>,
+    exclude_credentials: Vec<CredentialDescriptor>,
+    authenticator_selection: AuthenticatorSelection,
+    attestation: &'static str,
+    timeout: u64,
+}
+
+// PublicKeyCredentialRequestOptions
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestOptions {
+    challenge: String,
+    rp_id: &'static str,
+    allow_credentials: Vec<CredentialDescriptor>,
+    user_verification: &'static str,
+    timeout: u64,
+}
+
+pub async fn account_get(headers: HeaderMap, State(app_state): State<AppState>) -> Result<Html<String>, HtmlError> {
+    let (session, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+
+    let mut passkeys = Vec::new();
+    for passkey in crate::passkey::Entity::for_user(&user.id, &app_state.db).await? {
+        passkeys.push(PasskeyView {
+            id: passkey.id,
+            nickname: passkey.nickname,
+            created_at: passkey.created_at.format("%Y-%m-%d").to_string(),
+            last_used_at: passkey.last_used_at.map(|at| at.format("%Y-%m-%d").to_string()),
+            // one per delete form, they're single use
+            csrf_token: crate::util::generate_csrf_token().await,
+        });
+    }
+
+    let template = PasskeysTemplate {
+        username: user.username,
+        passkeys,
+        fresh: session.is_fresh(),
+        has_password: user.has_password,
+        csrf_token: crate::util::generate_csrf_token().await,
+    };
+    Ok(Html(template.render()?))
+}
+
+#[derive(Deserialize)]
+pub struct RegisterOptionsRequest {
+    #[serde(default)]
+    password: Option<String>,
+    csrf_token: String,
+}
+
+// a passkey signs in on its own and counts as two factors, so a borrowed session shouldn't get to add one
+async fn reauthenticate(
+    headers: &HeaderMap,
+    addr: SocketAddr,
+    password: Option<&str>,
+    app_state: &AppState,
+) -> Result<crate::user::Model, AppError> {
+    let (session, user) = crate::handler::session::signed_in(headers, &app_state.db).await?;
+    let ip = crate::handler::geoloc::client_ip(headers, addr);
+    if let Some(error) = crate::handler::session::reauthenticate(&session, &user, password, &ip, app_state).await? {
+        return Err(AppError::forbidden(error));
+    }
+    Ok(user)
+}
+
+pub async fn register_options(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Json(req): Json<RegisterOptionsRequest>,
+) -> Result<Json<CreationOptions>, AppError> {
+    if !crate::util::validate_csrf_token(&req.csrf_token).await {
+        return Err(AppError::bad_request("Invalid request, reload the page"));
+    }
+    let user = reauthenticate(&headers, addr, req.password.as_deref(), &app_state).await?;
+
+    let challenge = crate::token::webauthn::Entity::create(REGISTER, Some(&user.id), &app_state.db).await?;
+    let existing = crate::passkey::Entity::for_user(&user.id, &app_state.db).await?;
+
+    Ok(Json(CreationOptions {
+        challenge: challenge.challenge,
+        rp: RelyingParty {
+            id: crate::webauthn::rp_id(),
+            name: "sjallabong",
+        },
+        user: UserEntity {
+            id: URL_SAFE_NO_PAD.encode(user.id.as_bytes()),
+            name: user.username.clone(),
+            display_name: user.username,
+        },
+        pub_key_cred_params: vec![CredentialParameters {
+            kind: "public-key",
+            alg: crate::webauthn::ES256,
+        }],
+        exclude_credentials: existing.iter().map(CredentialDescriptor::from).collect(),
+        authenticator_selection: AuthenticatorSelection {
+            resident_key: "preferred",
+            user_verification: "preferred",
+        },
+        attestation: "none",
+        timeout: TIMEOUT,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct RegisterRequest {
+    challenge: String,
+    credential_id: String,
+    client_data_json: String,
+    attestation_object: String,
+    #[serde(default)]
+    transports: Vec<String>,
+    nickname: String,
+    // asked for again, the session may have gone stale while the key was being touched
+    #[serde(default)]
+    password: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct RegisterResponse {
+    success: bool,
+    // only when this was the user's first second factor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovery_codes: Option<Vec<String>>,
+}
+
+pub async fn register(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Json(req): Json<RegisterRequest>,
+) -> Result<Json<RegisterResponse>, AppError> {
+    let user = reauthenticate(&headers, addr, req.password.as_deref(), &app_state).await?;
+
+    let challenge = crate::token::webauthn::Entity::consume(&req.challenge, REGISTER, &app_state.db)
+        .await?
+        .filter(|challenge| challenge.user_id.as_deref() == Some(user.id.as_str()))
+        .or_bad_request("This took too long, try again")?;
+
+    let credential = crate::webauthn::verify_registration(
+        &decode("clientDataJSON", &req.client_data_json)?,
+        &decode("attestationObject", &req.attestation_object)?,
+        &challenge.challenge,
+    )
+    .map_err(|e| AppError::bad_request(format!("Passkey rejected: {e}")))?;
+
+    if credential.id != req.credential_id {
+        return Err(AppError::bad_request("Credential id mismatch"));
+    }
+    if crate::passkey::Entity::find_by_id(&credential.id)
+        .one(&app_state.db)
+        .await?
+        .is_some()
+    {
+        return Err(AppError::bad_request("This passkey is already registered"));
+    }
+
+    let nickname = match req.nickname.trim() {
+        "" => "Passkey".to_string(),
+        nickname => nickname.chars().take(64).collect(),
+    };
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+
+    let txn = app_state.db.begin().await?;
+    crate::passkey::ActiveModel {
+        id: Set(credential.id),
+        user_id: Set(user.id.clone()),
+        public_key: Set(URL_SAFE_NO_PAD.encode(&credential.public_key)),
+        sign_count: Set(i64::from(credential.sign_count)),
+        transports: Set(serde_json::to_string(&req.transports)?),
+        nickname: Set(nickname),
+        ..Default::default()
+    }
+    .insert(&txn)
+    .await?;
+    crate::security_event::Entity::record(&user.id, crate::security_event::PASSKEY_ADDED, Some(ip.clone()), &txn)
+        .await?;
+    let recovery_codes = crate::recovery_code::Entity::issue_if_missing(&user.id, &app_state.password, &txn).await?;
+    if recovery_codes.is_some() {
+        crate::security_event::Entity::record(
+            &user.id,
+            crate::security_event::RECOVERY_CODES_GENERATED,
+            Some(ip),
+            &txn,
+        )
+        .await?;
+    }
+    txn.commit().await?;
+
+    tracing::info!(user_id = %user.id, "Passkey added");
+    Ok(Json(RegisterResponse {
+        success: true,
+        recovery_codes,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct DeleteForm {
+    id: String,
+    csrf_token: String,
+}
+
+pub async fn delete(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Form(form): Form<DeleteForm>,
+) -> Result<Redirect, HtmlError> {
+    let (_, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+    if !crate::util::validate_csrf_token(&form.csrf_token).await {
+        return Err(AppError::bad_request("Invalid request, try again").into());
+    }
+
+    // a passkey only counts as one way in, however many there are
+    let passkeys = crate::passkey::Entity::for_user(&user.id, &app_state.db).await?;
+    if passkeys.len() == 1 && crate::user::Entity::sign_in_methods(&user, &app_state.db).await? == 1 {
+        return Err(AppError::bad_request(
+            "This passkey is the only way you can sign in, set a password or link another account first",
+        )
+        .into());
+    }
+
+    let deleted = crate::passkey::Entity::delete_many()
+        .filter(crate::passkey::Column::Id.eq(&form.id))
+        .filter(crate::passkey::Column::UserId.eq(&user.id))
+        .exec(&app_state.db)
+        .await?;
+    if deleted.rows_affected == 1 {
+        let ip = crate::handler::geoloc::client_ip(&headers, addr);
+        crate::security_event::Entity::record(
+            &user.id,
+            crate::security_event::PASSKEY_REMOVED,
+            Some(ip),
+            &app_state.db,
+        )
+        .await?;
+        tracing::info!(user_id = %user.id, "Passkey removed");
+    }
+
+    Ok(Redirect::to("/account/passkeys"))
+}
+
+#[derive(Deserialize)]
+pub struct LoginOptionsRequest {
+    // set when the passkey is the second step after a password
+    #[serde(default)]
+    mfa_challenge: Option<String>,
+}
+
+pub async fn login_options(
+    State(app_state): State<AppState>,
+    Json(req): Json<LoginOptionsRequest>,
+) -> Result<Json<RequestOptions>, AppError> {
+    let options = match req.mfa_challenge {
+        Some(mfa_challenge) => {
+            let mfa = crate::token::mfa::Entity::verify(&mfa_challenge, &app_state.db)
+                .await?
+                .or_bad_request("This sign-in expired, start over")?;
+            let passkeys = crate::passkey::Entity::for_user(&mfa.user_id, &app_state.db).await?;
+            let challenge =
+                crate::token::webauthn::Entity::create(SECOND_FACTOR, Some(&mfa.user_id), &app_state.db).await?;
+            RequestOptions {
+                challenge: challenge.challenge,
+                rp_id: crate::webauthn::rp_id(),
+                allow_credentials: passkeys.iter().map(CredentialDescriptor::from).collect(),
+                user_verification: "discouraged",
+                timeout: TIMEOUT,
+            }
+        }
+        // discoverable credentials, the authenticator offers whichever it has for us
+        None => {
+            let challenge = crate::token::webauthn::Entity::create(LOGIN, None, &app_state.db).await?;
+            RequestOptions {
+                challenge: challenge.challenge,
+                rp_id: crate::webauthn::rp_id(),
+                allow_credentials: Vec::new(),
+                user_verification: "required",
+                timeout: TIMEOUT,
+            }
+        }
+    };
+    Ok(Json(options))
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyLoginForm {
+    challenge: String,
+    credential_id: String,
+    client_data_json: String,
+    authenticator_data: String,
+    signature: String,
+    #[serde(default)]
+    mfa_challenge: Option<String>,
+    #[serde(flatten)]
+    pub oauth: OAuthParams,
+}
+
+pub async fn login(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Form(form): Form<PasskeyLoginForm>,
+) -> Result<FormResponse<Response>, HtmlError> {
+    let failed = || AppError::unauthorized("Passkey sign-in failed, try again or use your password");
+
+    let purpose = if form.mfa_challenge.is_some() {
+        SECOND_FACTOR
+    } else {
+        LOGIN
+    };
+    let challenge = crate::token::webauthn::Entity::consume(&form.challenge, purpose, &app_state.db)
+        .await?
+        .or_bad_request("This took too long, try again")?;
+
+    let passkey = crate::passkey::Entity::find_by_id(&form.credential_id)
+        .one(&app_state.db)
+        .await?
+        .ok_or_else(failed)?;
+
+    // the key has to belong to whoever passed the password
+    let mfa = match &form.mfa_challenge {
+        Some(mfa_challenge) => {
+            let mfa = crate::token::mfa::Entity::verify(mfa_challenge, &app_state.db)
+                .await?
+                .or_bad_request("This sign-in expired, start over")?;
+            if challenge.user_id.as_deref() != Some(mfa.user_id.as_str()) || passkey.user_id != mfa.user_id {
+                return Err(failed().into());
+            }
+            Some(mfa)
+        }
+        None => None,
+    };
+
+    let assertion = crate::webauthn::verify_assertion(
+        &decode("public key", &passkey.public_key)?,
+        &decode("clientDataJSON", &form.client_data_json)?,
+        &decode("authenticatorData", &form.authenticator_data)?,
+        &decode("signature", &form.signature)?,
+        &challenge.challenge,
+    );
+    let assertion = match assertion {
+        Ok(assertion) => assertion,
+        Err(e) => {
+            tracing::debug!(id = %passkey.id, "Passkey assertion failed: {e:#}");
+            let ip = crate::handler::geoloc::client_ip(&headers, addr);
+            let attempt = crate::handler::activity::Attempt {
+                user_id: Some(&passkey.user_id),
+                login: None,
+                client_id: Some(&form.oauth.client_id),
+                amr: &["hwk"],
+                failure: Some(crate::login_attempt::WRONG_PASSKEY),
+            };
+            crate::handler::activity::record(attempt, &ip, &headers, &app_state.db).await?;
+            if let Some(mfa) = mfa {
+                crate::throttle::second_factor_failed(&mfa.user_id).await;
+                crate::token::mfa::Entity::fail(&mfa.token, &app_state.db).await?;
+            }
+            return Err(failed().into());
+        }
+    };
+
+    // standing in for the password takes more than a touch
+    if mfa.is_none() && !assertion.user_verified {
+        return Err(AppError::unauthorized("This passkey didn't verify it's you, use your password").into());
+    }
+
+    let user_id = passkey.user_id.clone();
+    if !crate::passkey::Entity::used(passkey, assertion.sign_count, &app_state.db).await? {
+        return Err(failed().into());
+    }
+    if let Some(mfa) = &mfa
+        && !crate::token::mfa::Entity::consume(&mfa.token, &app_state.db).await?
+    {
+        return Err(AppError::bad_request("This sign-in expired, start over").into());
+    }
+
+    let user = crate::user::Entity::find_by_id(&user_id)
+        .one(&app_state.db)
+        .await?
+        .ok_or_else(failed)?;
+
+    let client = crate::util::get_client(&form.oauth.client_id, &app_state.db).await?;
+    crate::util::validate_redirect_uri(&client, &form.oauth.redirect_uri)?;
+
+    let amr = match &mfa {
+        Some(mfa) => [mfa.first_factor(), "hwk"],
+        None => ["hwk", "mfa"],
+    };
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+    let response = crate::handler::auth::sign_in(&user, &amr, &form.oauth, &client, ip, &headers, &app_state).await?;
+    Ok(FormResponse::Success(response))
+}
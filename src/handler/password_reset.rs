@@ -0,0 +1,189 @@
+use crate::AppState;
+use crate::error::{AppError, FormResponse, HtmlError};
+use crate::mail::Email;
+use crate::templates::{ForgotPasswordTemplate, NoticeTemplate, ResetPasswordTemplate};
+use askama::Template;
+use axum::{
+    Form,
+    extract::{ConnectInfo, Query, State},
+    http::HeaderMap,
+    response::Html,
+};
+use chrono::Utc;
+use sea_orm::*;
+use serde::Deserialize;
+use std::{collections::HashMap, net::SocketAddr};
+
+pub async fn forgot_get() -> Result<Html<String>, HtmlError> {
+    let template = ForgotPasswordTemplate {
+        errors: HashMap::new(),
+        email: String::new(),
+        csrf_token: crate::util::generate_csrf_token().await,
+        sent: false,
+    };
+    Ok(Html(template.render()?))
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordForm {
+    email: String,
+    csrf_token: String,
+}
+
+// looks the same whether or not the account exists, the mail goes out in the background
+pub async fn forgot_post(
+    State(app_state): State<AppState>,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Form(form): Form<ForgotPasswordForm>,
+) -> Result<FormResponse<Html<String>>, HtmlError> {
+    let render = async |errors: HashMap<String, String>, sent: bool| -> Result<Html<String>, HtmlError> {
+        let template = ForgotPasswordTemplate {
+            errors,
+            email: form.email.clone(),
+            csrf_token: crate::util::generate_csrf_token().await,
+            sent,
+        };
+        Ok(Html(template.render()?))
+    };
+
+    if !crate::util::validate_csrf_token(&form.csrf_token).await {
+        let mut errors = HashMap::new();
+        errors.insert("csrf".to_string(), "Invalid request, try again".to_string());
+        return Ok(FormResponse::ValidationErrors(render(errors, false).await?));
+    }
+
+    if form.email.is_empty() || !form.email.contains('@') {
+        let mut errors = HashMap::new();
+        errors.insert("email".to_string(), "Please enter a valid email address".to_string());
+        return Ok(FormResponse::ValidationErrors(render(errors, false).await?));
+    }
+
+    if !crate::throttle::mail_ip_allowed(&crate::handler::geoloc::client_ip(&headers, addr)).await {
+        let mut errors = HashMap::new();
+        errors.insert("email".to_string(), "Too many requests, try again later".to_string());
+        return Ok(FormResponse::ValidationErrors(render(errors, false).await?));
+    }
+
+    let user = crate::user::Entity::find_by_email(&form.email, &app_state.db).await?;
+
+    match (user, app_state.mailer.clone()) {
+        // held back the same as sent, or it'd tell which addresses have an account
+        (Some(user), Some(_)) if !crate::throttle::mail_address_allowed(&user.email).await => {
+            tracing::debug!(user_id = %user.id, "Password reset mail held back");
+        }
+        (Some(user), Some(mailer)) => {
+            let token = crate::token::reset::Entity::create(&user.id, &app_state.db).await?;
+            let email = Email {
+                to: user.email.clone(),
+                subject: "Reset your password".to_string(),
+                body: format!(
+                    "Hi {},\n\nSomeone asked to reset the password for your sjallabong account. Open this link to pick a new one:\n\n{}/reset-password?token={}\n\nIt works once and expires in {} minutes. If it wasn't you, ignore this email, your password hasn't changed.\n",
+                    user.username,
+                    crate::jwt::issuer(),
+                    urlencoding::encode(&token),
+                    crate::lifetime::password_reset().num_minutes()
+                ),
+            };
+            crate::mail::send_in_background(mailer, email);
+        }
+        (Some(user), None) => tracing::warn!(user_id = %user.id, "Can't send password reset email, no mailer"),
+        (None, _) => tracing::debug!("Password reset requested for unknown email"),
+    }
+
+    Ok(FormResponse::Success(render(HashMap::new(), true).await?))
+}
+
+#[derive(Deserialize)]
+pub struct ResetParams {
+    token: String,
+}
+
+pub async fn reset_get(
+    Query(params): Query<ResetParams>,
+    State(app_state): State<AppState>,
+) -> Result<Html<String>, HtmlError> {
+    if crate::token::reset::Entity::verify(&params.token, &app_state.db)
+        .await?
+        .is_none()
+    {
+        return Err(AppError::bad_request("This link has expired or was already used").into());
+    }
+
+    let template = ResetPasswordTemplate {
+        errors: HashMap::new(),
+        csrf_token: crate::util::generate_csrf_token().await,
+        token: params.token,
+    };
+    Ok(Html(template.render()?))
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordForm {
+    token: String,
+    password: String,
+    csrf_token: String,
+}
+
+pub async fn reset_post(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Form(form): Form<ResetPasswordForm>,
+) -> Result<FormResponse<Html<String>>, HtmlError> {
+    let render_error = async |errors: HashMap<String, String>| -> Result<FormResponse<Html<String>>, HtmlError> {
+        let template = ResetPasswordTemplate {
+            errors,
+            csrf_token: crate::util::generate_csrf_token().await,
+            token: form.token.clone(),
+        };
+        Ok(FormResponse::ValidationErrors(Html(template.render()?)))
+    };
+
+    if !crate::util::validate_csrf_token(&form.csrf_token).await {
+        let mut errors = HashMap::new();
+        errors.insert("general".to_string(), "Invalid request, try again".to_string());
+        return render_error(errors).await;
+    }
+
+    if let Some(error) = crate::password::validate(&form.password) {
+        let mut errors = HashMap::new();
+        errors.insert("password".to_string(), error.to_string());
+        return render_error(errors).await;
+    }
+
+    let password_hash = app_state.password.hash(&form.password)?;
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+
+    let txn = app_state.db.begin().await?;
+    let Some(reset) = crate::token::reset::Entity::consume(&form.token, &txn).await? else {
+        return Err(AppError::bad_request("This link has expired or was already used").into());
+    };
+
+    let Some(user) = crate::user::Entity::find_by_id(&reset.user_id).one(&txn).await? else {
+        return Err(AppError::not_found("User not found").into());
+    };
+
+    let mut user: crate::user::ActiveModel = user.into();
+    user.password_hash = Set(password_hash);
+    user.has_password = Set(true);
+    // they got the link, so the address is theirs
+    user.is_verified = Set(true);
+    user.updated_at = Set(Utc::now());
+    user.update(&txn).await?;
+
+    // whoever knew the old password is out
+    crate::token::revoke_all_for_user(&reset.user_id, None, &txn).await?;
+    crate::session::Entity::end_all(&reset.user_id, None, &txn).await?;
+    crate::security_event::Entity::record(&reset.user_id, crate::security_event::PASSWORD_RESET, Some(ip), &txn)
+        .await?;
+    txn.commit().await?;
+
+    tracing::info!(user_id = %reset.user_id, "Password reset");
+
+    let template = NoticeTemplate {
+        title: "Password changed".to_string(),
+        message: "You've been signed out everywhere, sign in again with your new password".to_string(),
+    };
+    Ok(FormResponse::Success(Html(template.render()?)))
+}
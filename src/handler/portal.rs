@@ -0,0 +1,186 @@
+use crate::AppState;
+use crate::error::{AppError, FormResponse, HtmlError};
+use crate::templates::PortalTemplate;
+use askama::Template;
+use axum::{
+    Form,
+    extract::State,
+    http::HeaderMap,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use chrono::Utc;
+use sea_orm::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// our own account pages, signed in to through the sso session like any other client
+
+// whose authorization request signs the browser in for us
+const PORTAL_CLIENT: &str = "sjallabong-main";
+
+// allowed as a redirect_uri for every client, see `util::validate_redirect_uri`
+pub fn uri() -> String {
+    format!("{}/success", crate::jwt::issuer())
+}
+
+// through /authorize like everyone else, so login, mfa and restrictions all apply. the code it sends
+// back goes unused, the session cookie is what we're after
+fn sign_in_redirect() -> Redirect {
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("client_id", PORTAL_CLIENT)
+        .append_pair("redirect_uri", &uri())
+        .append_pair("state", &crate::util::generate_random_string(16))
+        .append_pair("scope", "openid")
+        .append_pair("code_challenge", &crate::util::generate_random_string(43))
+        .append_pair("code_challenge_method", "S256")
+        .finish();
+    Redirect::to(&format!("/authorize?{query}"))
+}
+
+#[derive(Deserialize)]
+pub struct ProfileForm {
+    username: String,
+    #[serde(default)]
+    avatar_url: String,
+    #[serde(default)]
+    bio: String,
+    #[serde(default)]
+    country: String,
+    csrf_token: String,
+}
+
+async fn portal_page(
+    user: &crate::user::Model,
+    form: Option<&ProfileForm>,
+    errors: HashMap<String, String>,
+    db: &DatabaseConnection,
+) -> Result<Html<String>, AppError> {
+    let sessions = crate::session::Entity::for_user(&user.id, db).await?;
+    let mut apps: Vec<String> = Vec::new();
+    for token in crate::token::refresh::Entity::for_user(&user.id, db).await? {
+        if !apps.contains(&token.client_id) {
+            apps.push(token.client_id);
+        }
+    }
+
+    // what was typed, if it didn't go through
+    let (username, avatar_url, bio, country) = match form {
+        Some(form) => (
+            form.username.clone(),
+            form.avatar_url.clone(),
+            form.bio.clone(),
+            form.country.clone(),
+        ),
+        None => (
+            user.username.clone(),
+            user.avatar_url.clone().unwrap_or_default(),
+            user.bio.clone().unwrap_or_default(),
+            user.country.clone().unwrap_or_default(),
+        ),
+    };
+
+    let template = PortalTemplate {
+        username,
+        avatar_url,
+        bio,
+        country,
+        email: user.email.clone(),
+        is_verified: user.is_verified,
+        member_since: user.created_at.format("%Y-%m-%d").to_string(),
+        has_password: user.has_password,
+        has_totp: user.has_totp(),
+        passkeys: crate::passkey::Entity::for_user(&user.id, db).await?.len(),
+        recovery_codes: crate::recovery_code::Entity::remaining(&user.id, db).await?,
+        identities: crate::federated_identity::Entity::for_user(&user.id, db).await?.len(),
+        sessions: sessions.len(),
+        apps: apps.len(),
+        errors,
+        csrf_token: crate::util::generate_csrf_token().await,
+    };
+    Ok(Html(template.render()?))
+}
+
+pub async fn get(headers: HeaderMap, State(app_state): State<AppState>) -> Result<Response, HtmlError> {
+    // anything wrong with an existing session is for `signed_in` to explain
+    if crate::handler::session::current(&headers, &app_state.db)
+        .await?
+        .is_none()
+    {
+        return Ok(sign_in_redirect().into_response());
+    }
+    let (_, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+    Ok(portal_page(&user, None, HashMap::new(), &app_state.db)
+        .await?
+        .into_response())
+}
+
+fn validate_profile(form: &ProfileForm) -> HashMap<String, String> {
+    let mut errors = HashMap::new();
+
+    if let Some(error) = crate::handler::register::validate_username(&form.username) {
+        errors.insert("username".to_string(), error.to_string());
+    }
+
+    let avatar_url = form.avatar_url.trim();
+    if !avatar_url.is_empty() {
+        let valid = avatar_url.len() <= 512
+            && url::Url::parse(avatar_url).is_ok_and(|url| matches!(url.scheme(), "https" | "http"));
+        if !valid {
+            errors.insert("avatar_url".to_string(), "Please enter a valid image link".to_string());
+        }
+    }
+
+    if form.bio.chars().count() > 500 {
+        errors.insert("bio".to_string(), "Bio can be at most 500 characters".to_string());
+    }
+
+    let country = form.country.trim();
+    let is_code = country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic());
+    if !country.is_empty() && !is_code {
+        errors.insert(
+            "country".to_string(),
+            "Use the two letter country code, e.g. NO".to_string(),
+        );
+    }
+
+    errors
+}
+
+fn optional(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+pub async fn profile(
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Form(form): Form<ProfileForm>,
+) -> Result<FormResponse<Redirect>, HtmlError> {
+    let (_, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+
+    let mut errors = if !crate::util::validate_csrf_token(&form.csrf_token).await {
+        HashMap::from([("general".to_string(), "Invalid request, try again".to_string())])
+    } else {
+        validate_profile(&form)
+    };
+    let check_taken = !errors.contains_key("general") && !errors.contains_key("username");
+    if check_taken && crate::user::Entity::username_taken(&form.username, Some(&user.id), &app_state.db).await? {
+        errors.insert("username".to_string(), "This username is already taken".to_string());
+    }
+    if !errors.is_empty() {
+        return Ok(FormResponse::ValidationErrors(
+            portal_page(&user, Some(&form), errors, &app_state.db).await?,
+        ));
+    }
+
+    let mut update: crate::user::ActiveModel = user.clone().into();
+    update.username = Set(form.username.clone());
+    update.avatar_url = Set(optional(&form.avatar_url));
+    update.bio = Set(optional(&form.bio));
+    update.country = Set(optional(&form.country).map(|country| country.to_uppercase()));
+    update.updated_at = Set(Utc::now());
+    update.update(&app_state.db).await?;
+    tracing::info!(user_id = %user.id, "Profile updated");
+
+    Ok(FormResponse::Success(Redirect::to("/success")))
+}
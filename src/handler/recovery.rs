@@ -0,0 +1,173 @@
+use super::auth::OAuthParams;
+use crate::AppState;
+use crate::error::{AppError, FormResponse, HtmlError};
+use crate::templates::{RecoveryCodesTemplate, RecoveryTemplate};
+use askama::Template;
+use axum::{
+    Form,
+    extract::{ConnectInfo, State},
+    http::HeaderMap,
+    response::{Html, Response},
+};
+use sea_orm::*;
+use serde::Deserialize;
+use std::{collections::HashMap, net::SocketAddr};
+
+// shown once, right after a set is made
+pub fn codes_page(title: &str, message: &str, codes: Vec<String>) -> Result<Html<String>, AppError> {
+    let template = RecoveryCodesTemplate {
+        title: title.to_string(),
+        message: message.to_string(),
+        codes,
+    };
+    Ok(Html(template.render()?))
+}
+
+#[derive(Deserialize)]
+pub struct RecoveryForm {
+    challenge: String,
+    recovery_code: String,
+    csrf_token: String,
+    #[serde(flatten)]
+    pub oauth: OAuthParams,
+}
+
+// in place of the second factor at login
+pub async fn post(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Form(form): Form<RecoveryForm>,
+) -> Result<FormResponse<Response>, HtmlError> {
+    let expired = || AppError::bad_request("This sign-in expired, start over");
+    let challenge = crate::token::mfa::Entity::verify(&form.challenge, &app_state.db)
+        .await?
+        .ok_or_else(expired)?;
+    let user = crate::user::Entity::find_by_id(&challenge.user_id)
+        .one(&app_state.db)
+        .await?
+        .ok_or_else(expired)?;
+
+    let render_error = async |msg: &str| -> Result<FormResponse<Response>, HtmlError> {
+        let mut errors = HashMap::new();
+        errors.insert("recovery_code".to_string(), msg.to_string());
+        let page =
+            crate::handler::totp::challenge_page(&form.oauth, &form.challenge, &user, errors, &app_state.db).await?;
+        Ok(FormResponse::ValidationErrors(page))
+    };
+
+    if !crate::util::validate_csrf_token(&form.csrf_token).await {
+        return render_error("Invalid request, try again").await;
+    }
+
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+    // a recovery code is a one-time password as far as rfc 8176 goes
+    let amr = [challenge.first_factor(), "otp"];
+    if crate::throttle::check_second_factor(&user.id).await.is_some() {
+        return render_error("Too many wrong codes, try again later").await;
+    }
+    if !crate::recovery_code::Entity::redeem(&user.id, &form.recovery_code, &app_state.password, &app_state.db).await? {
+        let attempt = crate::handler::activity::Attempt {
+            user_id: Some(&user.id),
+            login: None,
+            client_id: Some(&form.oauth.client_id),
+            amr: &amr,
+            failure: Some(crate::login_attempt::WRONG_CODE),
+        };
+        crate::handler::activity::record(attempt, &ip, &headers, &app_state.db).await?;
+        crate::throttle::second_factor_failed(&user.id).await;
+        if crate::token::mfa::Entity::fail(&challenge.token, &app_state.db).await? {
+            return render_error("Wrong or used recovery code").await;
+        }
+        return Err(AppError::unauthorized("Too many wrong codes, start over").into());
+    }
+
+    if !crate::token::mfa::Entity::consume(&challenge.token, &app_state.db).await? {
+        return Err(expired().into());
+    }
+
+    let client = crate::util::get_client(&form.oauth.client_id, &app_state.db).await?;
+    crate::util::validate_redirect_uri(&client, &form.oauth.redirect_uri)?;
+
+    crate::security_event::Entity::record(
+        &user.id,
+        crate::security_event::RECOVERY_CODE_USED,
+        Some(ip.clone()),
+        &app_state.db,
+    )
+    .await?;
+    tracing::info!(user_id = %user.id, "Signed in with a recovery code");
+
+    let response = crate::handler::auth::sign_in(&user, &amr, &form.oauth, &client, ip, &headers, &app_state).await?;
+    Ok(FormResponse::Success(response))
+}
+
+async fn account_page(
+    user: &crate::user::Model,
+    error: Option<&str>,
+    db: &DatabaseConnection,
+) -> Result<Html<String>, AppError> {
+    let template = RecoveryTemplate {
+        username: user.username.clone(),
+        has_second_factor: crate::user::Entity::has_second_factor(user, db).await?,
+        remaining: crate::recovery_code::Entity::remaining(&user.id, db).await?,
+        error: error.map(str::to_string),
+        csrf_token: crate::util::generate_csrf_token().await,
+    };
+    Ok(Html(template.render()?))
+}
+
+pub async fn account_get(headers: HeaderMap, State(app_state): State<AppState>) -> Result<Html<String>, HtmlError> {
+    let (_, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+    Ok(account_page(&user, None, &app_state.db).await?)
+}
+
+#[derive(Deserialize)]
+pub struct RegenerateForm {
+    csrf_token: String,
+}
+
+// a new set, the old codes stop working
+pub async fn account_post(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Form(form): Form<RegenerateForm>,
+) -> Result<FormResponse<Html<String>>, HtmlError> {
+    let (session, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+
+    let error = if !crate::util::validate_csrf_token(&form.csrf_token).await {
+        Some("Invalid request, try again")
+    } else if !crate::user::Entity::has_second_factor(&user, &app_state.db).await? {
+        Some("Set up an authenticator app or a passkey first")
+    } else if !session.is_multi_factor() {
+        // or a stolen session could swap out the codes
+        Some("Sign in again with your second factor first")
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        return Ok(FormResponse::ValidationErrors(
+            account_page(&user, Some(error), &app_state.db).await?,
+        ));
+    }
+
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+    let txn = app_state.db.begin().await?;
+    let codes = crate::recovery_code::Entity::regenerate(&user.id, &app_state.password, &txn).await?;
+    crate::security_event::Entity::record(
+        &user.id,
+        crate::security_event::RECOVERY_CODES_GENERATED,
+        Some(ip),
+        &txn,
+    )
+    .await?;
+    txn.commit().await?;
+
+    tracing::info!(user_id = %user.id, "Recovery codes regenerated");
+    Ok(FormResponse::Success(codes_page(
+        "New recovery codes",
+        "Your old codes no longer work",
+        codes,
+    )?))
+}
@@ -1,7 +1,7 @@
 use super::auth::OAuthParams;
 use crate::AppState;
 use crate::error::{AppError, FormResponse, HtmlError};
-use crate::handler::geoloc::{get_country_from_ip, get_forwarded_ip};
+use crate::handler::geoloc::{client_ip, get_country_from_ip};
 use crate::templates::RegisterTemplate;
 use askama::Template;
 use axum::http::HeaderMap;
@@ -12,24 +12,74 @@ use axum::{
 };
 use sea_orm::*;
 use serde::Deserialize;
+use std::sync::LazyLock;
 use std::{collections::HashMap, net::SocketAddr};
 
+// who may make an account, REGISTRATION_MODE. federated logins only make new accounts while open
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Open,
+    // needs a code, see `invite`
+    Invite,
+    Closed,
+}
+
+static MODE: LazyLock<Mode> = LazyLock::new(|| match std::env::var("REGISTRATION_MODE").as_deref() {
+    Ok("open") | Err(_) => Mode::Open,
+    Ok("invite") => Mode::Invite,
+    Ok("closed") => Mode::Closed,
+    Ok(other) => {
+        tracing::warn!("Unknown REGISTRATION_MODE {other:?}, registration is closed");
+        Mode::Closed
+    }
+});
+
+pub fn mode() -> Mode {
+    *MODE
+}
+
 #[derive(Deserialize)]
 pub struct CreateUserRequest {
     email: String,
     username: String,
     password: String,
+    #[serde(default)]
+    invite: String,
     csrf_token: String,
     #[serde(flatten)]
+    pow: crate::pow::Solution,
+    #[serde(flatten)]
     pub oauth: OAuthParams,
 }
 
+#[derive(Deserialize)]
+pub struct RegisterQuery {
+    // from an invite link, fills in the field
+    #[serde(default)]
+    invite: String,
+    #[serde(flatten)]
+    oauth: OAuthParams,
+}
+
 // this needs the client id and allat in order to login after
-pub async fn get(Query(oauth): Query<OAuthParams>) -> Result<Html<String>, HtmlError> {
+pub async fn get(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Query(query): Query<RegisterQuery>,
+) -> Result<Html<String>, HtmlError> {
+    if mode() == Mode::Closed {
+        return Err(AppError::forbidden("Registration is closed").into());
+    }
+
+    let ip = client_ip(&headers, addr);
+    let oauth = query.oauth;
     let template = RegisterTemplate {
         errors: HashMap::new(),
         email: String::new(),
         username: String::new(),
+        invite: query.invite,
+        invite_required: mode() == Mode::Invite,
         client_id: oauth.client_id,
         redirect_uri: oauth.redirect_uri,
         state: oauth.state,
@@ -37,6 +87,7 @@ pub async fn get(Query(oauth): Query<OAuthParams>) -> Result<Html<String>, HtmlE
         code_challenge: oauth.code_challenge,
         code_challenge_method: oauth.code_challenge_method,
         csrf_token: crate::util::generate_csrf_token().await,
+        pow: crate::pow::issue(crate::pow::REGISTER, &ip, &app_state.db).await?,
     };
     Ok(Html(template.render()?))
 }
@@ -48,36 +99,39 @@ enum InputError {
     Password,
 }
 
-fn validate_format(req: &CreateUserRequest) -> HashMap<String, String> {
-    let mut errors = HashMap::new();
-
-    if req.email.is_empty() || !req.email.contains('@') {
-        errors.insert(InputError::Email, "Please enter a valid email address");
-    }
-
-    if req.username.trim() != req.username {
-        errors.insert(InputError::Username, "Username cannot start or end with spaces");
+// also used when renaming, see `portal`
+pub fn validate_username(username: &str) -> Option<&'static str> {
+    fn is_valid_username(c: char) -> bool {
+        c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | ' ' | '\'')
     }
 
-    let username = req.username.trim();
-    if username.contains("  ") {
-        errors.insert(InputError::Username, "Username cannot contain consecutive spaces");
+    let trimmed = username.trim();
+    if trimmed.len() < 3 || trimmed.len() > 24 {
+        Some("Username must be 3-24 characters")
+    } else if !trimmed.chars().all(is_valid_username) {
+        Some("Something not allowed in username")
+    } else if trimmed.contains("  ") {
+        Some("Username cannot contain consecutive spaces")
+    } else if trimmed != username {
+        Some("Username cannot start or end with spaces")
+    } else {
+        None
     }
+}
 
-    fn is_valid_username(c: char) -> bool {
-        c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | ' ' | '\'')
-    }
+fn validate_format(req: &CreateUserRequest) -> HashMap<String, String> {
+    let mut errors = HashMap::new();
 
-    if !username.chars().all(is_valid_username) {
-        errors.insert(InputError::Username, "Something not allowed in username");
+    if req.email.is_empty() || !req.email.contains('@') {
+        errors.insert(InputError::Email, "Please enter a valid email address");
     }
 
-    if username.len() < 3 || username.len() > 24 {
-        errors.insert(InputError::Username, "Username must be 3-24 characters");
+    if let Some(error) = validate_username(&req.username) {
+        errors.insert(InputError::Username, error);
     }
 
-    if req.password.len() < 6 || req.password.len() > 128 {
-        errors.insert(InputError::Password, "Password must be 6+ characters long");
+    if let Some(error) = crate::password::validate(&req.password) {
+        errors.insert(InputError::Password, error);
     }
 
     errors
@@ -99,27 +153,38 @@ async fn validate_database(
 ) -> Result<HashMap<String, String>, AppError> {
     let mut errors = HashMap::new();
 
-    let existing = crate::user::Entity::find()
-        .filter(
-            Condition::any()
-                .add(crate::user::Column::Email.eq(&req.email))
-                .add(crate::user::Column::Username.eq(&req.username)),
-        )
-        .one(db)
-        .await?;
-
-    if let Some(existing_user) = existing {
-        if existing_user.email == req.email {
-            errors.insert("email".to_string(), "This email is already registered".to_string());
-        }
-        if existing_user.username == req.username {
-            errors.insert("username".to_string(), "This username is already taken".to_string());
-        }
+    if crate::user::Entity::email_taken(&req.email, None, db).await? {
+        errors.insert("email".to_string(), "This email is already registered".to_string());
+    }
+    if crate::user::Entity::username_taken(&req.username, None, db).await? {
+        errors.insert("username".to_string(), "This username is already taken".to_string());
     }
 
     Ok(errors)
 }
 
+// the invite given on the form, required while invite only. Err is the message for the invite field
+async fn check_invite(
+    req: &CreateUserRequest,
+    db: &DatabaseConnection,
+) -> Result<Result<Option<crate::invite::Model>, String>, AppError> {
+    let code = req.invite.trim();
+    if code.is_empty() {
+        return Ok(match mode() {
+            Mode::Invite => Err("You need an invite to register".to_string()),
+            _ => Ok(None),
+        });
+    }
+
+    let Some(invite) = crate::invite::Entity::find_by_id(code).one(db).await? else {
+        return Ok(Err("This invite code isn't valid".to_string()));
+    };
+    if let Some(problem) = invite.problem(&req.oauth.client_id) {
+        return Ok(Err(problem.to_string()));
+    }
+    Ok(Ok(Some(invite)))
+}
+
 // redirects if successful, returns html form the form if fails
 pub async fn post(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
@@ -128,11 +193,14 @@ pub async fn post(
     Form(form): Form<CreateUserRequest>,
 ) -> Result<FormResponse<Redirect>, HtmlError> {
     let oauth = &form.oauth;
+    let ip = client_ip(&headers, addr);
     let render_error = async |errors: HashMap<String, String>| -> Result<FormResponse<Redirect>, HtmlError> {
         let template = RegisterTemplate {
             errors,
             email: form.email.clone(),
             username: form.username.clone(),
+            invite: form.invite.clone(),
+            invite_required: mode() == Mode::Invite,
             client_id: oauth.client_id.clone(),
             redirect_uri: oauth.redirect_uri.clone(),
             state: oauth.state.clone(),
@@ -140,23 +208,43 @@ pub async fn post(
             code_challenge: oauth.code_challenge.clone(),
             code_challenge_method: oauth.code_challenge_method.clone(),
             csrf_token: crate::util::generate_csrf_token().await,
+            pow: crate::pow::issue(crate::pow::REGISTER, &ip, &app_state.db).await?,
         };
         let rendered = template.render()?;
         Ok(FormResponse::ValidationErrors(Html(rendered)))
     };
 
+    if mode() == Mode::Closed {
+        return Err(AppError::forbidden("Registration is closed").into());
+    }
+
     if !crate::util::validate_csrf_token(&form.csrf_token).await {
         let mut errors = HashMap::new();
         errors.insert("csrf".to_string(), "Invalid request, try again".to_string());
         return render_error(errors).await;
     }
 
+    if let Err(error) = crate::pow::verify(crate::pow::REGISTER, &form.pow, &ip).await {
+        let mut errors = HashMap::new();
+        errors.insert("general".to_string(), error.to_string());
+        return render_error(errors).await;
+    }
+
     // check format fast first
     let format_errors = validate_format(&form);
     if !format_errors.is_empty() {
         return render_error(format_errors).await;
     }
 
+    let invite = match check_invite(&form, &app_state.db).await? {
+        Ok(invite) => invite,
+        Err(error) => {
+            let mut errors = HashMap::new();
+            errors.insert("invite".to_string(), error);
+            return render_error(errors).await;
+        }
+    };
+
     // db check
     let db_errors = validate_database(&form, &app_state.db).await?;
     if !db_errors.is_empty() {
@@ -165,18 +253,35 @@ pub async fn post(
 
     let password_hash = app_state.password.hash(&form.password)?;
 
-    let ip = get_forwarded_ip(&headers).unwrap_or_else(|| addr.ip().to_string());
     let country = get_country_from_ip(&ip).await;
 
     let user = crate::user::ActiveModel {
-        email: Set(form.email.clone()),
+        email: Set(form.email.trim().to_string()),
         username: Set(form.username.clone()),
         password_hash: Set(password_hash),
         country: Set(country),
         ..Default::default()
     };
 
-    user.insert(&app_state.db).await?;
+    let txn = app_state.db.begin().await?;
+    if let Some(invite) = &invite
+        && !crate::invite::Entity::redeem(&invite.code, &txn).await?
+    {
+        let mut errors = HashMap::new();
+        errors.insert("invite".to_string(), "This invite has been used up".to_string());
+        return render_error(errors).await;
+    }
+    let user = user.insert(&txn).await?;
+    if let Some(invite) = &invite {
+        for role in invite.roles() {
+            crate::user_role::Entity::assign(&user.id, &role, None, Some(&invite.created_by), &txn).await?;
+        }
+    }
+    txn.commit().await?;
+    if let Some(invite) = &invite {
+        tracing::info!(user_id = %user.id, invite = %invite.code, "Registered with an invite");
+    }
+    crate::handler::verify_email::send(&user, &app_state).await?;
 
     let redirect_url = format!(
         "/authorize?client_id={}&redirect_uri={}&scope={}&state={}&code_challenge={}&code_challenge_method={}",
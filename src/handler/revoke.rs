@@ -1,26 +1,55 @@
 use crate::AppState;
-use crate::error::{AppError, OptionExt};
+use crate::error::AppError;
 use crate::token::{access, refresh};
-use axum::{Form, extract::State, http::StatusCode};
-use sea_orm::*;
+use axum::{
+    Form,
+    extract::State,
+    http::{HeaderMap, StatusCode},
+};
 use serde::Deserialize;
 
 #[derive(Deserialize)]
 pub struct RevokeRequest {
     token: String,
-    client_id: String,
+    token_type_hint: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
 }
 
-pub async fn post(State(app_state): State<AppState>, Form(form): Form<RevokeRequest>) -> Result<StatusCode, AppError> {
-    crate::client::Entity::find_by_id(&form.client_id)
-        .one(&app_state.db)
-        .await?
-        .or_bad_request(format!("Invalid client_id: {}", form.client_id))?;
+// rfc 7009. unknown tokens, or ones issued to another client, still get a 200
+pub async fn post(
+    State(app_state): State<AppState>,
+    headers: HeaderMap,
+    Form(form): Form<RevokeRequest>,
+) -> Result<StatusCode, AppError> {
+    let client = crate::util::authenticate_client(
+        &headers,
+        form.client_id.as_deref(),
+        form.client_secret.as_deref(),
+        &app_state,
+    )
+    .await?;
 
-    if access::Entity::revoke(&form.token, &form.client_id, &app_state.db).await? {
-        return Ok(StatusCode::OK);
+    // the hint only decides what to look for first
+    if form.token_type_hint.as_deref() == Some("refresh_token") {
+        if !refresh::Entity::revoke(&form.token, &client.client_id, &app_state.db).await? {
+            revoke_access_token(&form.token, &client, &app_state).await?;
+        }
+    } else if !revoke_access_token(&form.token, &client, &app_state).await? {
+        refresh::Entity::revoke(&form.token, &client.client_id, &app_state.db).await?;
     }
 
-    refresh::Entity::revoke(&form.token, &form.client_id, &app_state.db).await?;
     Ok(StatusCode::OK)
 }
+
+async fn revoke_access_token(
+    token: &str,
+    client: &crate::client::Model,
+    app_state: &AppState,
+) -> Result<bool, AppError> {
+    // expired access tokens can still be revoked, their family may live on
+    let Ok(claims) = crate::jwt::verify_access_token(token, &app_state.jwk, true) else {
+        return Ok(false);
+    };
+    Ok(access::Entity::revoke(&claims.base.jti, client, &app_state.db).await?)
+}
@@ -0,0 +1,133 @@
+use crate::AppState;
+use crate::error::{AppError, HtmlError, OptionExt};
+use crate::templates::CheckSessionTemplate;
+use crate::util::{get_cookie, set_cookie};
+use askama::Template;
+use axum::{
+    extract::State,
+    http::{HeaderMap, HeaderName, header},
+    response::{Html, IntoResponse, Response},
+};
+use sea_orm::*;
+use sha2::{Digest, Sha256};
+
+pub const SESSION_COOKIE: &str = "sjallabong_session";
+// read by the check_session iframe, so not http only
+pub const BROWSER_STATE_COOKIE: &str = "sjallabong_bs";
+
+pub async fn current(headers: &HeaderMap, db: &DatabaseConnection) -> Result<Option<crate::session::Model>, AppError> {
+    let Some(sid) = get_cookie(headers, SESSION_COOKIE) else {
+        return Ok(None);
+    };
+    Ok(crate::session::Entity::verify(&sid, db).await?)
+}
+
+// for our own pages, which sign in with the session cookie rather than a token
+pub async fn signed_in(
+    headers: &HeaderMap,
+    db: &DatabaseConnection,
+) -> Result<(crate::session::Model, crate::user::Model), AppError> {
+    let session = current(headers, db).await?.or_unauthorized("Sign in first")?;
+    let user = crate::user::Entity::find_by_id(&session.user_id)
+        .one(db)
+        .await?
+        .or_unauthorized("Sign in first")?;
+    if user.delete_after.is_some() {
+        return Err(AppError::unauthorized(
+            "This account is being deleted, sign in again to keep it",
+        ));
+    }
+    if let Some(restriction) = crate::user::Entity::restriction(&user, db).await? {
+        return Err(AppError::forbidden(restriction));
+    }
+    Ok((session, user))
+}
+
+// for changes someone at a borrowed, signed in browser shouldn't get to make: a login in the last few
+// minutes, or the password again. the error to show, if neither
+pub async fn reauthenticate(
+    session: &crate::session::Model,
+    user: &crate::user::Model,
+    password: Option<&str>,
+    ip: &str,
+    app_state: &crate::AppState,
+) -> Result<Option<&'static str>, AppError> {
+    if session.is_fresh() {
+        return Ok(None);
+    }
+    if !user.has_password {
+        return Ok(Some("Sign in again first"));
+    }
+    let account = crate::throttle::account(Some(user), &user.username);
+    if crate::throttle::check(&account, ip).await.is_some() {
+        return Ok(Some("Too many attempts, try again later"));
+    }
+    if !app_state
+        .password
+        .verify(password.unwrap_or_default(), &user.password_hash)?
+    {
+        crate::throttle::failed(&account, ip).await;
+        return Ok(Some("Wrong password"));
+    }
+    crate::throttle::unlock_account(&account).await;
+    Ok(None)
+}
+
+pub fn cookies(session: &crate::session::Model) -> [(HeaderName, String); 2] {
+    let max_age = (session.expires_at - chrono::Utc::now()).num_seconds();
+    [
+        (
+            header::SET_COOKIE,
+            set_cookie(SESSION_COOKIE, &session.sid, max_age, true),
+        ),
+        (
+            header::SET_COOKIE,
+            set_cookie(BROWSER_STATE_COOKIE, &session.browser_state, max_age, false),
+        ),
+    ]
+}
+
+pub fn cleared_cookies() -> [(HeaderName, String); 2] {
+    [
+        (header::SET_COOKIE, set_cookie(SESSION_COOKIE, "", 0, true)),
+        (header::SET_COOKIE, set_cookie(BROWSER_STATE_COOKIE, "", 0, false)),
+    ]
+}
+
+// oidc session management 1.0, section 3. the iframe recomputes this from the browser state cookie
+pub fn session_state(client_id: &str, redirect_uri: &str, browser_state: &str) -> Result<String, AppError> {
+    let origin = url::Url::parse(redirect_uri)
+        .map_err(|_| AppError::bad_request("Invalid redirect URI"))?
+        .origin()
+        .ascii_serialization();
+    let salt = crate::util::generate_random_string(16);
+
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{client_id} {origin} {browser_state} {salt}").as_bytes());
+    Ok(format!("{:x}.{salt}", hasher.finalize()))
+}
+
+// check_session_iframe, framed by the clients' own pages
+pub async fn check(State(app_state): State<AppState>) -> Result<Response, HtmlError> {
+    let mut origins = Vec::new();
+    for client in crate::client::Entity::find().all(&app_state.db).await? {
+        origins.extend(serde_json::from_str::<Vec<String>>(&client.authorized_origins)?);
+    }
+    origins.sort();
+    origins.dedup();
+
+    let csp = format!(
+        "default-src 'none'; script-src 'unsafe-inline'; frame-ancestors {}",
+        if origins.is_empty() {
+            "'none'".to_string()
+        } else {
+            origins.join(" ")
+        }
+    );
+
+    let template = CheckSessionTemplate {
+        cookie_name: BROWSER_STATE_COOKIE,
+    };
+
+    Ok(([(header::CONTENT_SECURITY_POLICY, csp)], Html(template.render()?)).into_response())
+}
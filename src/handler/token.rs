@@ -1,18 +1,21 @@
 use crate::{
-    AppState,
+    AppState, client,
     error::{AppError, OptionExt},
+    token::IssuedTokens,
 };
-use axum::{Form, Json, extract::State};
+use axum::{Form, Json, extract::State, http::HeaderMap};
 use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Debug)]
 pub struct TokenRequest {
-    client_id: String,
+    // not needed with basic auth
+    client_id: Option<String>,
     grant_type: String,
     code: Option<String>,
     refresh_token: Option<String>,
     redirect_uri: Option<String>,
     code_verifier: String,
+    client_secret: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -28,23 +31,34 @@ pub struct TokenResponse {
 
 pub async fn post(
     State(app_state): State<AppState>,
+    headers: HeaderMap,
     Form(form): Form<TokenRequest>,
 ) -> Result<Json<TokenResponse>, AppError> {
-    let client = crate::util::get_client(&form.client_id, &app_state.db).await?;
+    let client = crate::util::authenticate_client(
+        &headers,
+        form.client_id.as_deref(),
+        form.client_secret.as_deref(),
+        &app_state,
+    )
+    .await?;
     crate::util::validate_redirect_uri(&client, &form.redirect_uri.clone().unwrap_or_default())?;
     match form.grant_type.as_str() {
-        "authorization_code" => handle_authorization_code(&app_state, form).await,
-        "refresh_token" => handle_refresh_token(&app_state, form).await,
+        "authorization_code" => handle_authorization_code(&app_state, &client, form).await,
+        "refresh_token" => handle_refresh_token(&app_state, &client, form).await,
         _ => Err(AppError::bad_request("Unsupported grant_type")),
     }
 }
 
-async fn handle_authorization_code(state: &AppState, form: TokenRequest) -> Result<Json<TokenResponse>, AppError> {
+async fn handle_authorization_code(
+    state: &AppState,
+    client: &client::Model,
+    form: TokenRequest,
+) -> Result<Json<TokenResponse>, AppError> {
     let code = form.code.or_bad_request("Missing parameter: code")?;
     let redirect_uri = form.redirect_uri.or_bad_request("Missing redirect URI")?;
-    let (access_token, refresh_token, scopes, user) = crate::token::auth::Entity::exchange_for_tokens(
+    let issued = crate::token::auth::Entity::exchange_for_tokens(
         &code,
-        &form.client_id,
+        client,
         &redirect_uri,
         &form.code_verifier,
         &state.db,
@@ -52,45 +66,55 @@ async fn handle_authorization_code(state: &AppState, form: TokenRequest) -> Resu
     )
     .await?;
 
-    let id_token = if scopes.contains("openid") {
-        Some(crate::jwt::create_jwt(
-            &user,
-            &form.client_id,
-            crate::jwt::TokenType::IdToken,
-            &scopes,
-            &state.jwk.encoding_key,
-        )?)
-    } else {
-        None
-    };
-
-    Ok(Json(TokenResponse {
-        access_token,
-        token_type: "Bearer".to_string(),
-        expires_in: 3600,
-        refresh_token,
-        scope: scopes,
-        id_token,
-    }))
+    token_response(state, client, issued).await
 }
 
-async fn handle_refresh_token(state: &AppState, form: TokenRequest) -> Result<Json<TokenResponse>, AppError> {
+async fn handle_refresh_token(
+    state: &AppState,
+    client: &client::Model,
+    form: TokenRequest,
+) -> Result<Json<TokenResponse>, AppError> {
     let refresh_token = form.refresh_token.or_bad_request("Missing refresh token")?;
 
-    let (access_token, new_refresh_token, scopes, user) = crate::token::refresh::Entity::refresh_tokens(
-        &refresh_token,
-        &form.client_id,
-        &state.db,
-        &state.jwk.encoding_key,
-    )
-    .await?;
+    let issued =
+        crate::token::refresh::Entity::refresh_tokens(&refresh_token, client, &state.db, &state.jwk.encoding_key)
+            .await?;
+
+    token_response(state, client, issued).await
+}
+
+async fn token_response(
+    state: &AppState,
+    client: &client::Model,
+    issued: IssuedTokens,
+) -> Result<Json<TokenResponse>, AppError> {
+    // grants from before a deactivation or suspension that weren't revoked, or one that started later
+    if let Some(restriction) = crate::user::Entity::restriction(&issued.user, &state.db).await? {
+        crate::token::refresh::Entity::revoke(&issued.refresh_token, &client.client_id, &state.db).await?;
+        return Err(AppError::forbidden(restriction));
+    }
+
+    // also catches families from before the user needed 2fa, or had it
+    if crate::user::Entity::requires_mfa(&issued.user, &state.db).await? && !crate::token::is_multi_factor(&issued.amr)
+    {
+        crate::token::refresh::Entity::revoke(&issued.refresh_token, &client.client_id, &state.db).await?;
+        return Err(AppError::forbidden("Two-factor authentication required"));
+    }
+
+    let lifetimes = client.lifetimes();
 
-    let id_token = if scopes.contains("openid") {
+    let id_token = if issued.scopes.contains("openid") {
+        let extra = crate::token::extra_claims(&issued.user.id, &client.client_id, &issued.scopes, &state.db).await?;
         Some(crate::jwt::create_jwt(
-            &user,
-            &form.client_id,
-            crate::jwt::TokenType::IdToken,
-            &scopes,
+            &issued.user,
+            client,
+            crate::jwt::TokenType::IdToken {
+                sid: issued.sid.as_deref().map(crate::session::public_id),
+                amr: issued.amr,
+            },
+            &issued.scopes,
+            &extra,
+            chrono::Utc::now() + lifetimes.id,
             &state.jwk.encoding_key,
         )?)
     } else {
@@ -98,11 +122,11 @@ async fn handle_refresh_token(state: &AppState, form: TokenRequest) -> Result<Js
     };
 
     Ok(Json(TokenResponse {
-        access_token,
+        access_token: issued.access_token,
         token_type: "Bearer".to_string(),
-        expires_in: 3600,
-        refresh_token: new_refresh_token,
-        scope: scopes,
+        expires_in: lifetimes.access.num_seconds() as u64,
+        refresh_token: issued.refresh_token,
+        scope: issued.scopes,
         id_token,
     }))
 }
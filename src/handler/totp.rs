@@ -0,0 +1,298 @@
+use super::auth::OAuthParams;
+use crate::AppState;
+use crate::error::{AppError, FormResponse, HtmlError};
+use crate::templates::{NoticeTemplate, TotpSetupTemplate, TotpStartTemplate, TotpTemplate};
+use askama::Template;
+use axum::{
+    Form,
+    extract::{ConnectInfo, State},
+    http::HeaderMap,
+    response::{Html, Response},
+};
+use chrono::Utc;
+use sea_orm::*;
+use serde::Deserialize;
+use std::{collections::HashMap, net::SocketAddr};
+
+// second login step, shown once the password checked out. offers whichever factors the user has
+pub async fn challenge_page(
+    oauth: &OAuthParams,
+    challenge: &str,
+    user: &crate::user::Model,
+    errors: HashMap<String, String>,
+    db: &DatabaseConnection,
+) -> Result<Html<String>, AppError> {
+    let template = TotpTemplate {
+        errors,
+        totp: user.has_totp(),
+        passkey: crate::passkey::Entity::exists_for_user(&user.id, db).await?,
+        recovery_csrf_token: crate::util::generate_csrf_token().await,
+        challenge: challenge.to_string(),
+        csrf_token: crate::util::generate_csrf_token().await,
+        client_id: oauth.client_id.clone(),
+        redirect_uri: oauth.redirect_uri.clone(),
+        state: oauth.state.clone(),
+        scope: oauth.scope.clone(),
+        code_challenge: oauth.code_challenge.clone(),
+        code_challenge_method: oauth.code_challenge_method.clone(),
+    };
+    Ok(Html(template.render()?))
+}
+
+#[derive(Deserialize)]
+pub struct TotpForm {
+    challenge: String,
+    code: String,
+    csrf_token: String,
+    #[serde(flatten)]
+    pub oauth: OAuthParams,
+}
+
+pub async fn post(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Form(form): Form<TotpForm>,
+) -> Result<FormResponse<Response>, HtmlError> {
+    let expired = || AppError::bad_request("This sign-in expired, start over");
+    let challenge = crate::token::mfa::Entity::verify(&form.challenge, &app_state.db)
+        .await?
+        .ok_or_else(expired)?;
+    let user = crate::user::Entity::find_by_id(&challenge.user_id)
+        .one(&app_state.db)
+        .await?
+        .ok_or_else(expired)?;
+
+    let render_error = async |msg: &str| -> Result<FormResponse<Response>, HtmlError> {
+        let mut errors = HashMap::new();
+        errors.insert("code".to_string(), msg.to_string());
+        let page = challenge_page(&form.oauth, &form.challenge, &user, errors, &app_state.db).await?;
+        Ok(FormResponse::ValidationErrors(page))
+    };
+
+    if !crate::util::validate_csrf_token(&form.csrf_token).await {
+        return render_error("Invalid request, try again").await;
+    }
+
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+    let amr = [challenge.first_factor(), "otp"];
+    if crate::throttle::check_second_factor(&user.id).await.is_some() {
+        return render_error("Too many wrong codes, try again later").await;
+    }
+    if !crate::user::Entity::check_totp(&user, &form.code, &app_state.db).await? {
+        let attempt = crate::handler::activity::Attempt {
+            user_id: Some(&user.id),
+            login: None,
+            client_id: Some(&form.oauth.client_id),
+            amr: &amr,
+            failure: Some(crate::login_attempt::WRONG_CODE),
+        };
+        crate::handler::activity::record(attempt, &ip, &headers, &app_state.db).await?;
+        crate::throttle::second_factor_failed(&user.id).await;
+        if crate::token::mfa::Entity::fail(&challenge.token, &app_state.db).await? {
+            return render_error("Wrong code").await;
+        }
+        return Err(AppError::unauthorized("Too many wrong codes, start over").into());
+    }
+
+    if !crate::token::mfa::Entity::consume(&challenge.token, &app_state.db).await? {
+        return Err(expired().into());
+    }
+
+    let client = crate::util::get_client(&form.oauth.client_id, &app_state.db).await?;
+    crate::util::validate_redirect_uri(&client, &form.oauth.redirect_uri)?;
+
+    let response = crate::handler::auth::sign_in(&user, &amr, &form.oauth, &client, ip, &headers, &app_state).await?;
+    Ok(FormResponse::Success(response))
+}
+
+async fn setup_page(
+    session: &crate::session::Model,
+    user: &crate::user::Model,
+    errors: HashMap<String, String>,
+) -> Result<Html<String>, AppError> {
+    let secret = user
+        .totp_pending_secret
+        .as_deref()
+        .ok_or_else(|| AppError::bad_request("Start over"))?;
+    let secret = crate::crypto::decrypt(secret, &crate::user::totp_context(&user.id))?;
+    let enrolment = crate::totp::enrolment(secret, &user.username)?;
+
+    let template = TotpSetupTemplate {
+        errors,
+        qr_svg: enrolment.qr_svg,
+        secret: enrolment.secret_base32,
+        fresh: session.is_fresh(),
+        csrf_token: crate::util::generate_csrf_token().await,
+    };
+    Ok(Html(template.render()?))
+}
+
+fn already_on() -> Result<Html<String>, AppError> {
+    let template = NoticeTemplate {
+        title: "Two-factor is on".to_string(),
+        message: "Your authenticator app is already set up".to_string(),
+    };
+    Ok(Html(template.render()?))
+}
+
+async fn start_page(user: &crate::user::Model, error: Option<&str>) -> Result<Html<String>, AppError> {
+    let template = TotpStartTemplate {
+        has_password: user.has_password,
+        error: error.map(str::to_string),
+        csrf_token: crate::util::generate_csrf_token().await,
+    };
+    Ok(Html(template.render()?))
+}
+
+// a fresh seed every time, until one is confirmed
+async fn new_seed(
+    session: &crate::session::Model,
+    user: crate::user::Model,
+    db: &DatabaseConnection,
+) -> Result<Html<String>, AppError> {
+    let secret = crate::totp::generate_secret();
+    let mut update: crate::user::ActiveModel = user.into();
+    update.totp_pending_secret = Set(Some(crate::crypto::encrypt(
+        &secret,
+        &crate::user::totp_context(update.id.as_ref()),
+    )?));
+    let user = update.update(db).await?;
+
+    setup_page(session, &user, HashMap::new()).await
+}
+
+// enrolment. whoever sets up the second factor can lock the owner out, so a borrowed session has to give
+// the password first
+pub async fn setup_get(headers: HeaderMap, State(app_state): State<AppState>) -> Result<Html<String>, HtmlError> {
+    let (session, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+
+    if user.has_totp() {
+        return Ok(already_on()?);
+    }
+    if !session.is_fresh() {
+        return Ok(start_page(&user, None).await?);
+    }
+    Ok(new_seed(&session, user, &app_state.db).await?)
+}
+
+#[derive(Deserialize)]
+pub struct TotpStartForm {
+    #[serde(default)]
+    password: Option<String>,
+    csrf_token: String,
+}
+
+pub async fn setup_start(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Form(form): Form<TotpStartForm>,
+) -> Result<FormResponse<Html<String>>, HtmlError> {
+    let (session, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+    if user.has_totp() {
+        return Ok(FormResponse::Success(already_on()?));
+    }
+
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+    let error = if !crate::util::validate_csrf_token(&form.csrf_token).await {
+        Some("Invalid request, try again")
+    } else {
+        crate::handler::session::reauthenticate(&session, &user, form.password.as_deref(), &ip, &app_state).await?
+    };
+    if let Some(error) = error {
+        return Ok(FormResponse::ValidationErrors(start_page(&user, Some(error)).await?));
+    }
+
+    Ok(FormResponse::Success(new_seed(&session, user, &app_state.db).await?))
+}
+
+#[derive(Deserialize)]
+pub struct TotpSetupForm {
+    code: String,
+    // asked for again unless the login was recent, see `setup_get`
+    #[serde(default)]
+    password: Option<String>,
+    csrf_token: String,
+}
+
+pub async fn setup_post(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Form(form): Form<TotpSetupForm>,
+) -> Result<FormResponse<Html<String>>, HtmlError> {
+    let (session, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+    if user.has_totp() {
+        return Err(AppError::bad_request("Two-factor is already on").into());
+    }
+
+    let render_error = async |field: &str, msg: &str| -> Result<FormResponse<Html<String>>, HtmlError> {
+        let mut errors = HashMap::new();
+        errors.insert(field.to_string(), msg.to_string());
+        Ok(FormResponse::ValidationErrors(
+            setup_page(&session, &user, errors).await?,
+        ))
+    };
+
+    if !crate::util::validate_csrf_token(&form.csrf_token).await {
+        return render_error("code", "Invalid request, try again").await;
+    }
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+    let reauth = crate::handler::session::reauthenticate(&session, &user, form.password.as_deref(), &ip, &app_state);
+    if let Some(error) = reauth.await? {
+        return render_error("password", error).await;
+    }
+
+    // the first code proves the app has the seed before we start asking for it
+    let pending = user
+        .totp_pending_secret
+        .as_deref()
+        .ok_or_else(|| AppError::bad_request("Start over"))?;
+    let secret = crate::crypto::decrypt(pending, &crate::user::totp_context(&user.id))?;
+    let Some(step) = crate::totp::verify(secret, &user.username, &form.code, None)? else {
+        return render_error("code", "Wrong code, check your app's clock").await;
+    };
+
+    let user_id = user.id.clone();
+
+    let txn = app_state.db.begin().await?;
+    let mut update: crate::user::ActiveModel = user.clone().into();
+    update.totp_secret = Set(Some(pending.to_string()));
+    update.totp_pending_secret = Set(None);
+    update.totp_enabled_at = Set(Some(Utc::now()));
+    update.totp_last_step = Set(Some(step));
+    update.updated_at = Set(Utc::now());
+    update.update(&txn).await?;
+
+    // the session keeps the amr it signed in with, the code only counts from the next login
+    crate::security_event::Entity::record(&user_id, crate::security_event::TOTP_ENABLED, Some(ip.clone()), &txn)
+        .await?;
+    let codes = crate::recovery_code::Entity::issue_if_missing(&user_id, &app_state.password, &txn).await?;
+    if codes.is_some() {
+        crate::security_event::Entity::record(
+            &user_id,
+            crate::security_event::RECOVERY_CODES_GENERATED,
+            Some(ip),
+            &txn,
+        )
+        .await?;
+    }
+    txn.commit().await?;
+
+    tracing::info!(%user_id, "TOTP enabled");
+
+    let message = "You'll be asked for a code from your app when you sign in";
+    if let Some(codes) = codes {
+        return Ok(FormResponse::Success(crate::handler::recovery::codes_page(
+            "Two-factor is on",
+            message,
+            codes,
+        )?));
+    }
+    let template = NoticeTemplate {
+        title: "Two-factor is on".to_string(),
+        message: message.to_string(),
+    };
+    Ok(FormResponse::Success(Html(template.render()?)))
+}
@@ -0,0 +1,74 @@
+use crate::{
+    AppState,
+    error::{AppError, OptionExt},
+};
+use axum::{Extension, Json, extract::State};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct SecretRequest {
+    pub client_id: String,
+}
+
+#[derive(Serialize)]
+pub struct SecretResponse {
+    pub success: bool,
+    // only ever shown here, we keep the hash
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_secret: Option<String>,
+}
+
+async fn target(
+    auth_user: &crate::middleware::user::AuthenticatedUser,
+    client_id: &str,
+    db: &DatabaseConnection,
+) -> Result<crate::client::Model, AppError> {
+    if !auth_user.can(crate::permission::CLIENTS_MANAGE) {
+        return Err(AppError::forbidden("Insufficient permissions to manage clients"));
+    }
+
+    crate::client::Entity::find_by_id(client_id)
+        .one(db)
+        .await?
+        .or_not_found(format!("Client not found: {client_id}"))
+}
+
+// sets a new secret, making the client confidential. the old one stops working right away
+pub async fn post(
+    Extension(auth_user): Extension<crate::middleware::user::AuthenticatedUser>,
+    State(app_state): State<AppState>,
+    Json(req): Json<SecretRequest>,
+) -> Result<Json<SecretResponse>, AppError> {
+    let client = target(&auth_user, &req.client_id, &app_state.db).await?;
+
+    let secret = crate::util::generate_random_string(48);
+    let mut active: crate::client::ActiveModel = client.into();
+    active.client_secret_hash = Set(Some(app_state.password.hash(&secret)?));
+    active.update(&app_state.db).await?;
+
+    tracing::info!(client_id = %req.client_id, admin_id = %auth_user.user.id, "Client secret rotated");
+    Ok(Json(SecretResponse {
+        success: true,
+        client_secret: Some(secret),
+    }))
+}
+
+// drops the secret, the client is public again and only pkce protects its codes
+pub async fn delete(
+    Extension(auth_user): Extension<crate::middleware::user::AuthenticatedUser>,
+    State(app_state): State<AppState>,
+    Json(req): Json<SecretRequest>,
+) -> Result<Json<SecretResponse>, AppError> {
+    let client = target(&auth_user, &req.client_id, &app_state.db).await?;
+
+    let mut active: crate::client::ActiveModel = client.into();
+    active.client_secret_hash = Set(None);
+    active.update(&app_state.db).await?;
+
+    tracing::info!(client_id = %req.client_id, admin_id = %auth_user.user.id, "Client secret removed");
+    Ok(Json(SecretResponse {
+        success: true,
+        client_secret: None,
+    }))
+}
@@ -0,0 +1,41 @@
+use crate::AppState;
+use crate::error::AppError;
+use axum::{Extension, Json, extract::State};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct UnlockRequest {
+    // a username or an email, either clears the account's
+    pub login: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct UnlockResponse {
+    pub success: bool,
+}
+
+// staff clearing a login lockout early
+pub async fn delete(
+    Extension(auth_user): Extension<crate::middleware::user::AuthenticatedUser>,
+    State(app_state): State<AppState>,
+    Json(req): Json<UnlockRequest>,
+) -> Result<Json<UnlockResponse>, AppError> {
+    if !auth_user.can(crate::permission::LOCKOUTS_CLEAR) {
+        return Err(AppError::forbidden("Insufficient permissions to clear lockouts"));
+    }
+    if req.login.is_none() && req.ip.is_none() {
+        return Err(AppError::bad_request("Give a login or an ip"));
+    }
+
+    if let Some(login) = &req.login {
+        let user = crate::user::Entity::find_by_login(login, &app_state.db).await?;
+        crate::throttle::unlock_account(&crate::throttle::account(user.as_ref(), login)).await;
+    }
+    if let Some(ip) = &req.ip {
+        crate::throttle::unlock_ip(ip).await;
+    }
+
+    tracing::info!(admin_id = %auth_user.user.id, login = ?req.login, ip = ?req.ip, "Login lockout cleared");
+    Ok(Json(UnlockResponse { success: true }))
+}
@@ -0,0 +1,158 @@
+use crate::{
+    AppState,
+    error::{AppError, OptionExt},
+};
+use axum::{
+    Extension, Json,
+    extract::{Query, State},
+};
+use chrono::Duration;
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+const MAX_TIER_LENGTH: usize = 32;
+
+#[derive(Deserialize)]
+pub struct MembershipQuery {
+    // the sub the calling client knows, like `update::user`
+    pub user_id: String,
+}
+
+#[derive(Serialize)]
+pub struct MembershipsResponse {
+    pub standing: Option<crate::membership::Standing>,
+    // newest first, revoked and expired ones included
+    pub memberships: Vec<crate::membership::Model>,
+}
+
+#[derive(Deserialize)]
+pub struct GrantRequest {
+    pub user_id: String,
+    // `membership::DEFAULT_TIER` if not given
+    pub tier: Option<String>,
+    // for good if not given, otherwise added after what's already lined up in the tier
+    pub days: Option<i64>,
+    // see `membership::SOURCES`, manual if not given
+    pub source: Option<String>,
+    // the promo code, or the payment's id. granting with one that was used before does nothing
+    pub reference: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct GrantResponse {
+    pub success: bool,
+    pub membership: crate::membership::Model,
+    pub standing: Option<crate::membership::Standing>,
+}
+
+#[derive(Serialize)]
+pub struct RevokeResponse {
+    pub success: bool,
+    pub revoked: u64,
+}
+
+async fn target(
+    auth_user: &crate::middleware::user::AuthenticatedUser,
+    sub: &str,
+    db: &DatabaseConnection,
+) -> Result<crate::user::Model, AppError> {
+    if !auth_user.can(crate::permission::MEMBERSHIPS_MANAGE) {
+        return Err(AppError::forbidden("Insufficient permissions to manage memberships"));
+    }
+
+    let client = crate::util::get_client(&auth_user.access_token.client_id, db).await?;
+    let user_id = crate::subject::Entity::user_id(&client, sub, db)
+        .await?
+        .or_not_found(format!("User not found: {sub}"))?;
+    crate::user::Entity::find_by_id(&user_id)
+        .one(db)
+        .await?
+        .filter(|user| user.deleted_at.is_none())
+        .or_not_found(format!("User not found: {sub}"))
+}
+
+// shared with the payment webhook
+pub fn validate_grant(tier: Option<&str>, days: Option<i64>) -> Result<(String, Option<Duration>), AppError> {
+    let tier = tier.map(str::trim).unwrap_or(crate::membership::DEFAULT_TIER);
+    if tier.is_empty() || tier.chars().count() > MAX_TIER_LENGTH {
+        return Err(AppError::bad_request(format!(
+            "Tiers are 1 to {MAX_TIER_LENGTH} characters"
+        )));
+    }
+    if days.is_some_and(|days| days <= 0) {
+        return Err(AppError::bad_request("days must be positive"));
+    }
+    Ok((tier.to_string(), days.map(Duration::days)))
+}
+
+pub async fn get(
+    Extension(auth_user): Extension<crate::middleware::user::AuthenticatedUser>,
+    Query(query): Query<MembershipQuery>,
+    State(app_state): State<AppState>,
+) -> Result<Json<MembershipsResponse>, AppError> {
+    let user = target(&auth_user, &query.user_id, &app_state.db).await?;
+    Ok(Json(MembershipsResponse {
+        standing: crate::membership::Entity::standing(&user.id, &app_state.db).await?,
+        memberships: crate::membership::Entity::for_user(&user.id, &app_state.db).await?,
+    }))
+}
+
+// grants or extends
+pub async fn post(
+    Extension(auth_user): Extension<crate::middleware::user::AuthenticatedUser>,
+    State(app_state): State<AppState>,
+    Json(req): Json<GrantRequest>,
+) -> Result<Json<GrantResponse>, AppError> {
+    let user = target(&auth_user, &req.user_id, &app_state.db).await?;
+    let (tier, length) = validate_grant(req.tier.as_deref(), req.days)?;
+    let source = req.source.as_deref().unwrap_or(crate::membership::MANUAL);
+    if !crate::membership::SOURCES.contains(&source) {
+        return Err(AppError::bad_request(format!("Unknown source: {source}")));
+    }
+
+    let txn = app_state.db.begin().await?;
+    let membership = crate::membership::Entity::grant(
+        &user.id,
+        &tier,
+        length,
+        source,
+        req.reference.as_deref(),
+        Some(&auth_user.user.id),
+        &txn,
+    )
+    .await?
+    .or_bad_request("This reference was already used for another user")?;
+    txn.commit().await?;
+
+    tracing::info!(
+        user_id = %user.id,
+        admin_id = %auth_user.user.id,
+        %tier,
+        expires_at = ?membership.expires_at,
+        "Membership granted"
+    );
+    Ok(Json(GrantResponse {
+        success: true,
+        membership,
+        standing: crate::membership::Entity::standing(&user.id, &app_state.db).await?,
+    }))
+}
+
+// ends the current membership and any lined up after it
+pub async fn delete(
+    Extension(auth_user): Extension<crate::middleware::user::AuthenticatedUser>,
+    State(app_state): State<AppState>,
+    Json(req): Json<MembershipQuery>,
+) -> Result<Json<RevokeResponse>, AppError> {
+    let user = target(&auth_user, &req.user_id, &app_state.db).await?;
+
+    let txn = app_state.db.begin().await?;
+    let revoked = crate::membership::Entity::revoke(&user.id, &txn).await?;
+    txn.commit().await?;
+
+    tracing::info!(user_id = %user.id, admin_id = %auth_user.user.id, revoked, "Membership revoked");
+    Ok(Json(RevokeResponse {
+        success: revoked > 0,
+        revoked,
+    }))
+}
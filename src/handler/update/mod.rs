@@ -1 +1,6 @@
+pub mod client;
+pub mod lockout;
+pub mod membership;
+pub mod password;
+pub mod suspension;
 pub mod user;
@@ -0,0 +1,243 @@
+use crate::{
+    AppState,
+    error::{AppError, FormResponse, HtmlError},
+    mail::Email,
+    templates::{ChangePasswordTemplate, NoticeTemplate},
+};
+use askama::Template;
+use axum::{
+    Extension, Form, Json,
+    extract::{ConnectInfo, State},
+    http::HeaderMap,
+    response::Html,
+};
+use chrono::Utc;
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, net::SocketAddr};
+
+// the password a signed in user gives again, Unauthorized if it's wrong. counted like logins and
+// `session::reauthenticate`, a token or session alone mustn't make guessing free
+pub async fn verify_current(
+    user: &crate::user::Model,
+    current_password: &str,
+    ip: &str,
+    app_state: &AppState,
+) -> Result<(), AppError> {
+    let account = crate::throttle::account(Some(user), &user.username);
+    if crate::throttle::check(&account, ip).await.is_some() {
+        return Err(AppError::unauthorized("Too many attempts, try again later"));
+    }
+    // argon2 is slow on purpose, so off the async workers
+    let verified = {
+        let (password, current, hash) = (
+            app_state.password.clone(),
+            current_password.to_string(),
+            user.password_hash.clone(),
+        );
+        tokio::task::spawn_blocking(move || password.verify(&current, &hash)).await??
+    };
+    if !verified {
+        crate::throttle::failed(&account, ip).await;
+        return Err(AppError::unauthorized("Wrong password"));
+    }
+    crate::throttle::unlock_account(&account).await;
+    Ok(())
+}
+
+// the sso session the calling token was issued in, if it came from one and it's still there
+pub async fn token_session(
+    auth_user: &crate::middleware::user::AuthenticatedUser,
+    db: &DatabaseConnection,
+) -> Result<Option<crate::session::Model>, AppError> {
+    let Some(family_id) = &auth_user.access_token.family_id else {
+        return Ok(None);
+    };
+    let sid = crate::token::refresh::Entity::find()
+        .filter(crate::token::refresh::Column::FamilyId.eq(family_id))
+        .one(db)
+        .await?
+        .and_then(|token| token.sid);
+    match sid {
+        Some(sid) => Ok(crate::session::Entity::find_by_id(sid).one(db).await?),
+        None => Ok(None),
+    }
+}
+
+// shared by the api and the form. Unauthorized means the current password was wrong, BadRequest the new one
+async fn change_password(
+    user: &crate::user::Model,
+    current_password: &str,
+    new_password: &str,
+    // the session to stay signed in, if signing out everywhere else
+    sign_out_others: Option<Option<&str>>,
+    ip: String,
+    app_state: &AppState,
+) -> Result<(), AppError> {
+    verify_current(user, current_password, &ip, app_state).await?;
+    if let Some(error) = crate::password::validate(new_password) {
+        return Err(AppError::bad_request(error));
+    }
+    if new_password == current_password {
+        return Err(AppError::bad_request("That's your current password"));
+    }
+
+    let password_hash = app_state.password.hash(new_password)?;
+
+    let txn = app_state.db.begin().await?;
+    let mut update: crate::user::ActiveModel = user.clone().into();
+    update.password_hash = Set(password_hash);
+    update.updated_at = Set(Utc::now());
+    update.update(&txn).await?;
+
+    if let Some(keep_sid) = sign_out_others {
+        crate::token::revoke_all_for_user(&user.id, keep_sid, &txn).await?;
+        crate::session::Entity::end_all(&user.id, keep_sid, &txn).await?;
+    }
+    crate::security_event::Entity::record(
+        &user.id,
+        crate::security_event::PASSWORD_CHANGED,
+        Some(ip.clone()),
+        &txn,
+    )
+    .await?;
+    txn.commit().await?;
+
+    tracing::info!(user_id = %user.id, "Password changed");
+
+    if let Some(mailer) = app_state.mailer.clone() {
+        let email = Email {
+            to: user.email.clone(),
+            subject: "Your password was changed".to_string(),
+            body: format!(
+                "Hi {},\n\nThe password for your sjallabong account was changed at {} from {}.\n\nIf this wasn't you, reset it right away: {}/forgot-password\n",
+                user.username,
+                Utc::now().format("%Y-%m-%d %H:%M UTC"),
+                ip,
+                crate::jwt::issuer()
+            ),
+        };
+        crate::mail::send_in_background(mailer, email);
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+    #[serde(default)]
+    pub sign_out_other_sessions: bool,
+}
+
+#[derive(Serialize)]
+pub struct ChangePasswordResponse {
+    pub success: bool,
+}
+
+pub async fn post(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Extension(auth_user): Extension<crate::middleware::user::AuthenticatedUser>,
+    State(app_state): State<AppState>,
+    Json(req): Json<ChangePasswordRequest>,
+) -> Result<Json<ChangePasswordResponse>, AppError> {
+    // keep the session the calling token was issued in
+    let sid = token_session(&auth_user, &app_state.db)
+        .await?
+        .map(|session| session.sid);
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+
+    change_password(
+        &auth_user.user,
+        &req.current_password,
+        &req.new_password,
+        req.sign_out_other_sessions.then_some(sid.as_deref()),
+        ip,
+        &app_state,
+    )
+    .await?;
+
+    Ok(Json(ChangePasswordResponse { success: true }))
+}
+
+pub async fn form_get(headers: HeaderMap, State(app_state): State<AppState>) -> Result<Html<String>, HtmlError> {
+    let (_, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+    let template = ChangePasswordTemplate {
+        errors: HashMap::new(),
+        username: user.username,
+        csrf_token: crate::util::generate_csrf_token().await,
+    };
+    Ok(Html(template.render()?))
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordForm {
+    current_password: String,
+    password: String,
+    // checkbox, absent when unticked
+    #[serde(default)]
+    sign_out_others: Option<String>,
+    csrf_token: String,
+}
+
+pub async fn form_post(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    Form(form): Form<ChangePasswordForm>,
+) -> Result<FormResponse<Html<String>>, HtmlError> {
+    let (session, user) = crate::handler::session::signed_in(&headers, &app_state.db).await?;
+    let render_error = async |errors: HashMap<String, String>| -> Result<FormResponse<Html<String>>, HtmlError> {
+        let template = ChangePasswordTemplate {
+            errors,
+            username: user.username.clone(),
+            csrf_token: crate::util::generate_csrf_token().await,
+        };
+        Ok(FormResponse::ValidationErrors(Html(template.render()?)))
+    };
+
+    if !crate::util::validate_csrf_token(&form.csrf_token).await {
+        let mut errors = HashMap::new();
+        errors.insert("general".to_string(), "Invalid request, try again".to_string());
+        return render_error(errors).await;
+    }
+
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+    let sign_out_others = form.sign_out_others.is_some().then_some(Some(session.sid.as_str()));
+
+    match change_password(
+        &user,
+        &form.current_password,
+        &form.password,
+        sign_out_others,
+        ip,
+        &app_state,
+    )
+    .await
+    {
+        Ok(()) => {}
+        Err(AppError::Unauthorized(msg)) => {
+            let mut errors = HashMap::new();
+            errors.insert("current_password".to_string(), msg);
+            return render_error(errors).await;
+        }
+        Err(AppError::BadRequest(msg)) => {
+            let mut errors = HashMap::new();
+            errors.insert("password".to_string(), msg);
+            return render_error(errors).await;
+        }
+        Err(e) => return Err(e.into()),
+    }
+
+    let template = NoticeTemplate {
+        title: "Password changed".to_string(),
+        message: if sign_out_others.is_some() {
+            "Done, and you've been signed out everywhere else".to_string()
+        } else {
+            "Done, use it next time you sign in".to_string()
+        },
+    };
+    Ok(FormResponse::Success(Html(template.render()?)))
+}
@@ -0,0 +1,120 @@
+use crate::{
+    AppState,
+    error::{AppError, OptionExt},
+};
+use axum::{Extension, Json, extract::State};
+use chrono::{DateTime, Utc};
+use sea_orm::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct SuspendRequest {
+    // the sub the calling client knows, like `update::user`
+    pub user_id: String,
+    pub reason: String,
+    // now if not given
+    pub starts_at: Option<DateTime<Utc>>,
+    // until lifted if not given
+    pub ends_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+pub struct SuspendResponse {
+    pub success: bool,
+    pub suspension: crate::suspension::Model,
+}
+
+#[derive(Deserialize)]
+pub struct LiftRequest {
+    pub user_id: String,
+}
+
+#[derive(Serialize)]
+pub struct LiftResponse {
+    pub success: bool,
+    pub lifted: u64,
+}
+
+// users:suspend covers regular accounts, staff need users:suspend_staff too
+async fn target(
+    auth_user: &crate::middleware::user::AuthenticatedUser,
+    sub: &str,
+    db: &DatabaseConnection,
+) -> Result<crate::user::Model, AppError> {
+    let moderator = &auth_user.user;
+    if !auth_user.can(crate::permission::USERS_SUSPEND) {
+        return Err(AppError::forbidden("Insufficient permissions to suspend users"));
+    }
+
+    let client = crate::util::get_client(&auth_user.access_token.client_id, db).await?;
+    let user_id = crate::subject::Entity::user_id(&client, sub, db)
+        .await?
+        .or_not_found(format!("User not found: {sub}"))?;
+    let user = crate::user::Entity::find_by_id(&user_id)
+        .one(db)
+        .await?
+        .or_not_found(format!("User not found: {sub}"))?;
+
+    if user.id == moderator.id {
+        return Err(AppError::bad_request("You can't suspend yourself"));
+    }
+    if crate::user::Entity::requires_mfa(&user, db).await? && !auth_user.can(crate::permission::USERS_SUSPEND_STAFF) {
+        return Err(AppError::forbidden("Insufficient permissions to suspend staff"));
+    }
+    Ok(user)
+}
+
+pub async fn post(
+    Extension(auth_user): Extension<crate::middleware::user::AuthenticatedUser>,
+    State(app_state): State<AppState>,
+    Json(req): Json<SuspendRequest>,
+) -> Result<Json<SuspendResponse>, AppError> {
+    let user = target(&auth_user, &req.user_id, &app_state.db).await?;
+
+    let reason = req.reason.trim();
+    if reason.is_empty() {
+        return Err(AppError::bad_request("A reason is required, the user is shown it"));
+    }
+    let now = Utc::now();
+    let starts_at = req.starts_at.unwrap_or(now).max(now);
+    if req.ends_at.is_some_and(|ends_at| ends_at <= starts_at) {
+        return Err(AppError::bad_request("ends_at must be after the start"));
+    }
+
+    let txn = app_state.db.begin().await?;
+    let suspension =
+        crate::suspension::Entity::create(&user.id, reason, &auth_user.user.id, starts_at, req.ends_at, &txn).await?;
+    // one that starts later is caught by the checks on every request once it does
+    if starts_at <= now {
+        crate::token::revoke_all_for_user(&user.id, None, &txn).await?;
+        crate::session::Entity::end_all(&user.id, None, &txn).await?;
+    }
+    txn.commit().await?;
+
+    tracing::info!(
+        user_id = %user.id,
+        moderator_id = %auth_user.user.id,
+        ends_at = ?suspension.ends_at,
+        "User suspended"
+    );
+    Ok(Json(SuspendResponse {
+        success: true,
+        suspension,
+    }))
+}
+
+// lifts the current suspension, and any scheduled ones
+pub async fn delete(
+    Extension(auth_user): Extension<crate::middleware::user::AuthenticatedUser>,
+    State(app_state): State<AppState>,
+    Json(req): Json<LiftRequest>,
+) -> Result<Json<LiftResponse>, AppError> {
+    let user = target(&auth_user, &req.user_id, &app_state.db).await?;
+    let lifted = crate::suspension::Entity::lift(&user.id, &auth_user.user.id, &app_state.db).await?;
+
+    tracing::info!(user_id = %user.id, moderator_id = %auth_user.user.id, lifted, "Suspension lifted");
+    Ok(Json(LiftResponse {
+        success: lifted > 0,
+        lifted,
+    }))
+}
@@ -2,10 +2,15 @@ use crate::{
     AppState,
     error::{AppError, OptionExt},
 };
-use axum::{Extension, Json, extract::State};
+use axum::{
+    Extension, Json,
+    extract::{ConnectInfo, State},
+    http::HeaderMap,
+};
 use chrono::Utc;
 use sea_orm::*;
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
 
 #[derive(Deserialize)]
 pub struct UpdateUserRequest {
@@ -15,9 +20,15 @@ pub struct UpdateUserRequest {
     pub country: Option<String>,
     pub avatar_url: Option<String>,
     pub bio: Option<String>,
-    pub is_moderator: Option<bool>,
-    pub is_admin: Option<bool>,
     pub is_active: Option<bool>,
+    // role names, see `roles`. given everywhere, or only in `role_client_id`'s tokens
+    #[serde(default)]
+    pub add_roles: Vec<String>,
+    #[serde(default)]
+    pub remove_roles: Vec<String>,
+    pub role_client_id: Option<String>,
+    // changing your own email needs it, unless the token is from a login in the last few minutes
+    pub current_password: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -27,23 +38,100 @@ pub struct UpdateUserResponse {
 }
 
 pub async fn patch(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Extension(auth_user): Extension<crate::middleware::user::AuthenticatedUser>,
     State(app_state): State<AppState>,
     Json(req): Json<UpdateUserRequest>,
 ) -> Result<Json<UpdateUserResponse>, AppError> {
-    if req.user_id != auth_user.user.id && !auth_user.user.is_admin {
+    // user_id is the sub the calling client knows, which may be pairwise
+    let client = crate::util::get_client(&auth_user.access_token.client_id, &app_state.db).await?;
+    let user_id = crate::subject::Entity::user_id(&client, &req.user_id, &app_state.db)
+        .await?
+        .or_not_found(format!("User not found: {}", req.user_id))?;
+
+    if user_id != auth_user.user.id && !auth_user.can(crate::permission::USERS_UPDATE) {
         return Err(AppError::forbidden("Insufficient permissions to update this user"));
     }
+    if req.is_active.is_some() && !auth_user.can(crate::permission::USERS_DEACTIVATE) {
+        return Err(AppError::forbidden("Insufficient permissions to deactivate users"));
+    }
+    let changes_roles = !req.add_roles.is_empty() || !req.remove_roles.is_empty();
+    if changes_roles && !auth_user.can(crate::permission::USERS_ROLES) {
+        return Err(AppError::forbidden("Insufficient permissions to change roles"));
+    }
+    if let Some(client_id) = &req.role_client_id {
+        crate::util::get_client(client_id, &app_state.db).await?;
+    }
+    if req
+        .add_roles
+        .iter()
+        .chain(&req.remove_roles)
+        .any(|role| role == crate::membership::MEMBER_ROLE)
+    {
+        return Err(AppError::bad_request(
+            "The member role follows memberships, see /update/membership",
+        ));
+    }
+    for role in &req.add_roles {
+        if crate::role::Entity::find_by_id(role)
+            .one(&app_state.db)
+            .await?
+            .is_none()
+        {
+            return Err(AppError::bad_request(format!("Unknown role: {role}")));
+        }
+    }
 
-    let user = crate::user::Entity::find_by_id(&req.user_id)
+    let user = crate::user::Entity::find_by_id(&user_id)
         .one(&app_state.db)
         .await?
         .or_not_found(format!("User not found: {}", req.user_id))?;
 
-    let mut user_update: crate::user::ActiveModel = user.into();
+    // stored trimmed, like on registration
+    let email = req.email.as_deref().map(str::trim);
+    if email.is_some_and(|email| email.is_empty() || !email.contains('@')) {
+        return Err(AppError::bad_request("Please enter a valid email address"));
+    }
+    if let Some(email) = email
+        && crate::user::Entity::email_taken(email, Some(&user.id), &app_state.db).await?
+    {
+        return Err(AppError::bad_request("This email is already registered"));
+    }
+    if let Some(error) = req
+        .username
+        .as_deref()
+        .and_then(crate::handler::register::validate_username)
+    {
+        return Err(AppError::bad_request(error));
+    }
+    if let Some(username) = &req.username
+        && crate::user::Entity::username_taken(username, Some(&user.id), &app_state.db).await?
+    {
+        return Err(AppError::bad_request("This username is already taken"));
+    }
 
-    if let Some(email) = req.email {
-        user_update.email = Set(email);
+    // a new address needs verifying again, a new casing of the same one doesn't
+    let email_changed = email.is_some_and(|email| crate::user::normalize(email) != crate::user::normalize(&user.email));
+    let ip = crate::handler::geoloc::client_ip(&headers, addr);
+    // the address password resets go to. any client's token would otherwise be enough to take the account
+    if email_changed && user.id == auth_user.user.id {
+        let session = crate::handler::update::password::token_session(&auth_user, &app_state.db).await?;
+        if !session.is_some_and(|session| session.is_fresh()) {
+            let password = req
+                .current_password
+                .as_deref()
+                .or_unauthorized("current_password is needed to change your email")?;
+            crate::handler::update::password::verify_current(&user, password, &ip, &app_state).await?;
+        }
+    }
+
+    let mut user_update: crate::user::ActiveModel = user.clone().into();
+    if let Some(email) = email {
+        user_update.email = Set(email.to_string());
+    }
+    if email_changed {
+        user_update.is_verified = Set(false);
     }
     if let Some(username) = req.username {
         user_update.username = Set(username);
@@ -58,21 +146,60 @@ pub async fn patch(
         user_update.bio = Set(Some(bio));
     }
 
-    if auth_user.user.is_admin {
-        if let Some(is_moderator) = req.is_moderator {
-            user_update.is_moderator = Set(is_moderator);
-        }
-        if let Some(is_admin) = req.is_admin {
-            user_update.is_admin = Set(is_admin);
-        }
-        if let Some(is_active) = req.is_active {
-            user_update.is_active = Set(is_active);
-        }
+    if let Some(is_active) = req.is_active {
+        user_update.is_active = Set(is_active);
     }
 
     user_update.updated_at = Set(Utc::now());
 
-    let updated_user = user_update.update(&app_state.db).await?;
+    let txn = app_state.db.begin().await?;
+    let mut updated_user = user_update.update(&txn).await?;
+    if user.is_active && !updated_user.is_active {
+        // out everywhere now, not when the access tokens run out
+        crate::token::revoke_all_for_user(&user.id, None, &txn).await?;
+        crate::session::Entity::end_all(&user.id, None, &txn).await?;
+        tracing::info!(user_id = %user.id, admin_id = %auth_user.user.id, "User deactivated");
+    }
+    if email_changed {
+        // links mailed to the old address stop working
+        crate::token::reset::Entity::delete_many()
+            .filter(crate::token::reset::Column::UserId.eq(&user.id))
+            .exec(&txn)
+            .await?;
+        crate::security_event::Entity::record(&user.id, crate::security_event::EMAIL_CHANGED, Some(ip.clone()), &txn)
+            .await?;
+    }
+    let client_id = req.role_client_id.as_deref();
+    for role in &req.add_roles {
+        if crate::user_role::Entity::assign(&user.id, role, client_id, Some(&auth_user.user.id), &txn).await? {
+            tracing::info!(user_id = %user.id, admin_id = %auth_user.user.id, role, ?client_id, "Role granted");
+        }
+    }
+    for role in &req.remove_roles {
+        if crate::user_role::Entity::unassign(&user.id, role, client_id, &txn).await? {
+            tracing::info!(user_id = %user.id, admin_id = %auth_user.user.id, role, ?client_id, "Role removed");
+        }
+    }
+    txn.commit().await?;
+    if email_changed {
+        crate::handler::verify_email::send(&updated_user, &app_state).await?;
+        if let Some(mailer) = app_state.mailer.clone() {
+            let email = crate::mail::Email {
+                to: user.email.clone(),
+                subject: "Your email was changed".to_string(),
+                body: format!(
+                    "Hi {},\n\nThe email for your sjallabong account was changed to {} at {} from {}.\n\nIf this wasn't you, contact us right away.\n",
+                    user.username,
+                    updated_user.email,
+                    Utc::now().format("%Y-%m-%d %H:%M UTC"),
+                    ip
+                ),
+            };
+            crate::mail::send_in_background(mailer, email);
+        }
+    }
+    // don't hand a pairwise client the real id
+    updated_user.id = client.subject_for(&updated_user.id);
 
     Ok(Json(UpdateUserResponse {
         success: true,
@@ -1,5 +1,5 @@
-use crate::error::AppError;
-use axum::{Extension, Json};
+use crate::{AppState, error::AppError};
+use axum::{Extension, Json, extract::State};
 use serde::Serialize;
 
 #[derive(Serialize)]
@@ -8,6 +8,8 @@ pub struct UserInfoResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     email: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    email_verified: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     username: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     country: Option<String>,
@@ -16,11 +18,15 @@ pub struct UserInfoResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     bio: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    is_admin: Option<bool>,
+    roles: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    permissions: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groups: Option<Vec<crate::group_member::Membership>>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    is_moderator: Option<bool>,
+    membership_tier: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    is_member: Option<bool>,
+    membership_expires_at: Option<i64>,
 }
 
 impl UserInfoResponse {
@@ -28,28 +34,33 @@ impl UserInfoResponse {
         Self {
             id,
             email: None,
+            email_verified: None,
             username: None,
             country: None,
             avatar_url: None,
             bio: None,
-            is_admin: None,
-            is_moderator: None,
-            is_member: None,
+            roles: None,
+            permissions: None,
+            groups: None,
+            membership_tier: None,
+            membership_expires_at: None,
         }
     }
 }
 
 pub async fn get(
     Extension(auth_user): Extension<crate::middleware::user::AuthenticatedUser>,
+    State(app_state): State<AppState>,
 ) -> Result<Json<UserInfoResponse>, AppError> {
     if !auth_user.has_openid() {
         return Err(AppError::forbidden("OpenID scope required"));
     }
 
-    let mut user_info = UserInfoResponse::new(auth_user.user.id.to_string());
+    let mut user_info = UserInfoResponse::new(auth_user.sub.clone());
 
     if auth_user.has_email() {
         user_info.email = Some(auth_user.user.email.clone());
+        user_info.email_verified = Some(auth_user.user.is_verified);
     }
 
     if auth_user.has_profile() {
@@ -60,9 +71,23 @@ pub async fn get(
     }
 
     if auth_user.has_roles() {
-        user_info.is_admin = Some(auth_user.user.is_admin);
-        user_info.is_moderator = Some(auth_user.user.is_moderator);
-        user_info.is_member = Some(auth_user.user.is_member);
+        // the calling client's own roles count here, unlike in `auth_user.grants`
+        let grants = crate::user_role::Entity::grants(
+            &auth_user.user.id,
+            Some(&auth_user.access_token.client_id),
+            &app_state.db,
+        )
+        .await?;
+        user_info.roles = Some(grants.roles);
+        user_info.permissions = Some(grants.permissions);
+        if let Some(standing) = crate::membership::Entity::standing(&auth_user.user.id, &app_state.db).await? {
+            user_info.membership_tier = Some(standing.tier);
+            user_info.membership_expires_at = standing.expires_at.map(|expires_at| expires_at.timestamp());
+        }
+    }
+
+    if auth_user.has_groups() {
+        user_info.groups = Some(crate::group_member::Entity::memberships(&auth_user.user.id, &app_state.db).await?);
     }
 
     Ok(Json(user_info))
@@ -0,0 +1,115 @@
+use crate::AppState;
+use crate::error::{AppError, HtmlError, OptionExt};
+use crate::mail::Email;
+use crate::templates::{NoticeTemplate, VerifyEmailTemplate};
+use askama::Template;
+use axum::{
+    Form,
+    extract::{Query, State},
+    response::Html,
+};
+use chrono::{Duration, Utc};
+use sea_orm::*;
+use serde::Deserialize;
+
+// don't mail the same address again if a link went out this recently
+const RESEND_AFTER: Duration = Duration::minutes(5);
+
+// mails a link for the user's current address. no-op if already verified or no mailer is configured
+pub async fn send(user: &crate::user::Model, app_state: &AppState) -> Result<(), AppError> {
+    if user.is_verified {
+        return Ok(());
+    }
+    let Some(mailer) = app_state.mailer.clone() else {
+        tracing::warn!(user_id = %user.id, "Can't send verification email, no mailer");
+        return Ok(());
+    };
+
+    if let Some(pending) = crate::token::verification::Entity::pending(&user.id, &user.email, &app_state.db).await?
+        && pending.created_at > Utc::now() - RESEND_AFTER
+    {
+        return Ok(());
+    }
+
+    let verification = crate::token::verification::Entity::create(&user.id, &user.email, &app_state.db).await?;
+    let token = crate::jwt::create_email_verification(
+        &user.id,
+        &user.email,
+        &verification.jti,
+        verification.expires_at,
+        &app_state.jwk.encoding_key,
+    )?;
+
+    let email = Email {
+        to: user.email.clone(),
+        subject: "Verify your email".to_string(),
+        body: format!(
+            "Hi {},\n\nOpen this link to verify your email address:\n\n{}/verify-email?token={}\n\nIt works once and expires in {} hours. If you didn't sign up, ignore this email.\n",
+            user.username,
+            crate::jwt::issuer(),
+            urlencoding::encode(&token),
+            crate::lifetime::email_verification().num_hours()
+        ),
+    };
+
+    crate::mail::send_in_background(mailer, email);
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct VerifyParams {
+    pub token: String,
+}
+
+// mail scanners and link previews open links too, so opening it only asks. the token is the secret, the
+// form needs no csrf token
+pub async fn get(
+    Query(params): Query<VerifyParams>,
+    State(app_state): State<AppState>,
+) -> Result<Html<String>, HtmlError> {
+    let claims = crate::jwt::verify_email_verification(&params.token, &app_state.jwk)?;
+    crate::token::verification::Entity::verify(&claims.base.jti, &app_state.db)
+        .await?
+        .or_bad_request("This link has already been used or was replaced by a newer one")?;
+
+    let template = VerifyEmailTemplate {
+        email: claims.email,
+        token: params.token,
+    };
+    Ok(Html(template.render()?))
+}
+
+pub async fn post(
+    State(app_state): State<AppState>,
+    Form(params): Form<VerifyParams>,
+) -> Result<Html<String>, HtmlError> {
+    let claims = crate::jwt::verify_email_verification(&params.token, &app_state.jwk)?;
+
+    let verification = crate::token::verification::Entity::consume(&claims.base.jti, &app_state.db)
+        .await?
+        .or_bad_request("This link has already been used or was replaced by a newer one")?;
+
+    let user = crate::user::Entity::find_by_id(&claims.base.sub)
+        .one(&app_state.db)
+        .await?
+        .or_not_found("User not found")?;
+
+    // the address changed since the link was sent
+    if verification.user_id != user.id || verification.email != claims.email || user.email != claims.email {
+        return Err(AppError::bad_request("This link is for a different email address").into());
+    }
+
+    let mut user: crate::user::ActiveModel = user.into();
+    user.is_verified = Set(true);
+    user.updated_at = Set(Utc::now());
+    user.update(&app_state.db).await?;
+
+    tracing::info!(user_id = %claims.base.sub, "Email verified");
+
+    let template = NoticeTemplate {
+        title: "Email verified".to_string(),
+        message: "Thanks! You can close this page and sign in again".to_string(),
+    };
+    Ok(Html(template.render()?))
+}
@@ -0,0 +1,97 @@
+use crate::{
+    AppState,
+    error::{AppError, OptionExt},
+    handler::update::membership::GrantResponse,
+};
+use axum::{Json, body::Bytes, extract::State, http::HeaderMap};
+use chrono::Utc;
+use sea_orm::*;
+use serde::Deserialize;
+use std::sync::LazyLock;
+
+// the payment system's side of memberships. requests are signed with the shared MEMBERSHIP_WEBHOOK_SECRET:
+// X-Webhook-Timestamp is unix seconds, X-Webhook-Signature the hex hmac-sha256 of "{timestamp}.{body}"
+static SECRET: LazyLock<Option<String>> = LazyLock::new(|| std::env::var("MEMBERSHIP_WEBHOOK_SECRET").ok());
+
+// how old a signed request may be, so a captured one can't be replayed later
+const MAX_AGE_SECS: i64 = 5 * 60;
+
+#[derive(Deserialize)]
+pub struct MembershipEvent {
+    // the account's id, which is the sub public clients see
+    pub user_id: String,
+    pub tier: Option<String>,
+    // for good if not given
+    pub days: Option<i64>,
+    // the payment's id. retries with the same one are answered with the membership it made
+    pub reference: String,
+}
+
+fn verify_signature(headers: &HeaderMap, body: &[u8], secret: &str) -> Result<(), AppError> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let timestamp = header("x-webhook-timestamp").or_unauthorized("Missing signature")?;
+    let signature = header("x-webhook-signature").or_unauthorized("Missing signature")?;
+
+    let sent_at: i64 = timestamp
+        .parse()
+        .map_err(|_| AppError::unauthorized("Invalid signature"))?;
+    if (Utc::now().timestamp() - sent_at).abs() > MAX_AGE_SECS {
+        return Err(AppError::unauthorized("Signature expired"));
+    }
+
+    let mut message = format!("{timestamp}.").into_bytes();
+    message.extend_from_slice(body);
+    if !crate::crypto::verify_hmac(secret.as_bytes(), &message, signature) {
+        return Err(AppError::unauthorized("Invalid signature"));
+    }
+    Ok(())
+}
+
+// grants or extends
+pub async fn membership(
+    headers: HeaderMap,
+    State(app_state): State<AppState>,
+    body: Bytes,
+) -> Result<Json<GrantResponse>, AppError> {
+    let secret = SECRET.as_deref().or_not_found("Webhook not configured")?;
+    verify_signature(&headers, &body, secret)?;
+
+    let event: MembershipEvent =
+        serde_json::from_slice(&body).map_err(|e| AppError::bad_request(format!("Invalid event: {e}")))?;
+    if event.reference.trim().is_empty() {
+        return Err(AppError::bad_request("A reference is required"));
+    }
+    let (tier, length) = crate::handler::update::membership::validate_grant(event.tier.as_deref(), event.days)?;
+    let user = crate::user::Entity::find_by_id(&event.user_id)
+        .one(&app_state.db)
+        .await?
+        .filter(|user| user.deleted_at.is_none())
+        .or_not_found(format!("User not found: {}", event.user_id))?;
+
+    let txn = app_state.db.begin().await?;
+    let membership = crate::membership::Entity::grant(
+        &user.id,
+        &tier,
+        length,
+        crate::membership::PAYMENT,
+        Some(event.reference.trim()),
+        None,
+        &txn,
+    )
+    .await?
+    .or_bad_request("This reference was already used for another user")?;
+    txn.commit().await?;
+
+    tracing::info!(
+        user_id = %user.id,
+        %tier,
+        reference = %event.reference,
+        expires_at = ?membership.expires_at,
+        "Membership paid for"
+    );
+    Ok(Json(GrantResponse {
+        success: true,
+        membership,
+        standing: crate::membership::Entity::standing(&user.id, &app_state.db).await?,
+    }))
+}
@@ -1,14 +1,17 @@
 use crate::error::AppError;
-use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, decode_header, encode};
 use rsa::RsaPublicKey;
 use rsa::pkcs8::DecodePublicKey;
 use rsa::traits::PublicKeyParts;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Clone)]
 pub struct Jwk {
     pub encoding_key: EncodingKey,
+    pub decoding_key: DecodingKey,
     pub n: rsa::BigUint,
     pub e: rsa::BigUint,
 }
@@ -16,101 +19,176 @@ pub struct Jwk {
 pub fn generate_jwk() -> Jwk {
     let private_key = std::fs::read("private_key.pem").expect("Failed to read private key");
     let public_key_pem = std::fs::read_to_string("public_key.pem").expect("Failed to read public key");
-    let encoding_key = EncodingKey::from_rsa_pem(&private_key).expect("Failed to parse private key");
-    let public_key = RsaPublicKey::from_public_key_pem(&public_key_pem).expect("Failed to parse public key");
+    jwk_from_pem(&private_key, &public_key_pem)
+}
+
+pub fn jwk_from_pem(private_key: &[u8], public_key_pem: &str) -> Jwk {
+    let encoding_key = EncodingKey::from_rsa_pem(private_key).expect("Failed to parse private key");
+    let public_key = RsaPublicKey::from_public_key_pem(public_key_pem).expect("Failed to parse public key");
+    let decoding_key = DecodingKey::from_rsa_pem(public_key_pem.as_bytes()).expect("Failed to parse public key");
     Jwk {
         encoding_key,
+        decoding_key,
         n: public_key.n().clone(),
         e: public_key.e().clone(),
     }
 }
 
+// kid advertised in jwks
+const KEY_ID: &str = "main";
+// rfc 9068, keeps id tokens from being accepted as access tokens
+const ACCESS_TOKEN_TYP: &str = "at+jwt";
+const EMAIL_VERIFICATION_TYP: &str = "email-verification+jwt";
+
+pub fn issuer() -> &'static str {
+    if *crate::IS_PRODUCTION {
+        "https://auth.sjallabong.eu"
+    } else {
+        "http://localhost:3001"
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum TokenType {
-    IdToken,
-    AccessToken,
+    // amr is rfc 8176, how the user authenticated. sid is the session's public id, never the cookie
+    IdToken { sid: Option<String>, amr: Vec<String> },
+    AccessToken { jti: String, amr: Vec<String> },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct BaseClaims {
-    sub: String,
-    iss: String,
-    aud: String,
-    exp: u64,
-    iat: u64,
+pub struct BaseClaims {
+    pub sub: String,
+    pub iss: String,
+    pub aud: String,
+    pub exp: u64,
+    pub iat: u64,
+    pub jti: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct AccessTokenClaims {
+pub struct AccessTokenClaims {
     #[serde(flatten)]
-    base: BaseClaims,
-    scope: String,
+    pub base: BaseClaims,
+    pub client_id: String,
+    pub scope: String,
     username: String,
     avatar_url: Option<String>,
     country: Option<String>,
-    is_admin: bool,
-    is_moderator: bool,
-    is_member: bool,
+    // only under the roles scope, see `user_role::Entity::grants`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roles: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<Vec<String>>,
+    // only under the groups scope
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub groups: Option<Vec<crate::group_member::Membership>>,
+    // only while a membership runs. expires_at is unix seconds like exp, absent if it doesn't run out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub membership_tier: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub membership_expires_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub amr: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct IdTokenClaims {
+pub struct IdTokenClaims {
     #[serde(flatten)]
-    base: BaseClaims,
+    pub base: BaseClaims,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    amr: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     email: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    email_verified: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     username: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    roles: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    groups: Option<Vec<crate::group_member::Membership>>,
+}
+
+// claims that take a lookup, see `token::extra_claims`. grants and groups only when their scope was granted
+#[derive(Debug, Default)]
+pub struct ExtraClaims {
+    // what the user holds for this client
+    pub grants: Option<crate::user_role::Grants>,
+    pub groups: Option<Vec<crate::group_member::Membership>>,
+    pub membership: Option<crate::membership::Standing>,
 }
 
 pub fn create_jwt(
     user: &crate::user::Model,
-    client_id: &str,
+    client: &crate::client::Model,
     token_type: TokenType,
     scopes: &str,
+    extra: &ExtraClaims,
+    expires_at: DateTime<Utc>,
     encoding_key: &EncodingKey,
 ) -> Result<String, AppError> {
     let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-    let mut issuer = "https://auth.sjallabong.eu";
 
-    if !*crate::IS_PRODUCTION {
-        issuer = "http://localhost:3001";
-    }
+    let jti = match &token_type {
+        TokenType::AccessToken { jti, .. } => jti.clone(),
+        TokenType::IdToken { .. } => uuid::Uuid::new_v4().to_string(),
+    };
 
     let base = BaseClaims {
-        sub: user.id.clone(),
-        iss: issuer.to_string(),
-        aud: client_id.to_string(),
-        exp: now + 3600,
+        sub: client.subject_for(&user.id),
+        iss: issuer().to_string(),
+        aud: client.client_id.clone(),
+        exp: expires_at.timestamp() as u64,
         iat: now,
+        jti,
     };
 
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(KEY_ID.to_string());
+
     match token_type {
-        TokenType::AccessToken => {
+        TokenType::AccessToken { amr, .. } => {
             let claims = AccessTokenClaims {
                 base,
+                client_id: client.client_id.clone(),
                 scope: scopes.to_string(),
                 username: user.username.clone(),
                 avatar_url: user.avatar_url.clone(),
                 country: user.country.clone(),
-                is_admin: user.is_admin,
-                is_moderator: user.is_moderator,
-                is_member: user.is_member,
+                roles: extra.grants.as_ref().map(|grants| grants.roles.clone()),
+                permissions: extra.grants.as_ref().map(|grants| grants.permissions.clone()),
+                groups: extra.groups.clone(),
+                membership_tier: extra.membership.as_ref().map(|m| m.tier.clone()),
+                membership_expires_at: extra
+                    .membership
+                    .as_ref()
+                    .and_then(|m| m.expires_at)
+                    .map(|expires_at| expires_at.timestamp()),
+                amr,
             };
 
-            let header = Header::new(Algorithm::RS256);
+            header.typ = Some(ACCESS_TOKEN_TYP.to_string());
             encode(&header, &claims, encoding_key).map_err(AppError::from)
         }
-        TokenType::IdToken => {
+        TokenType::IdToken { sid, amr } => {
             let claims = IdTokenClaims {
                 base,
+                sid,
+                amr,
                 email: if scopes.contains("email") {
                     Some(user.email.clone())
                 } else {
                     None
                 },
+                email_verified: if scopes.contains("email") {
+                    Some(user.is_verified)
+                } else {
+                    None
+                },
                 username: if scopes.contains("profile") {
                     Some(user.username.clone())
                 } else {
@@ -121,10 +199,96 @@ pub fn create_jwt(
                 } else {
                     None
                 },
+                roles: extra.grants.as_ref().map(|grants| grants.roles.clone()),
+                groups: extra.groups.clone(),
             };
 
-            let header = Header::new(Algorithm::RS256);
             encode(&header, &claims, encoding_key).map_err(AppError::from)
         }
     }
 }
+
+// checks signature, typ, iss and (unless `allow_expired`) exp. revocation is up to the caller, by jti
+pub fn verify_access_token(token: &str, jwk: &Jwk, allow_expired: bool) -> Result<AccessTokenClaims, AppError> {
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_issuer(&[issuer()]);
+    validation.set_required_spec_claims(&["exp", "iss", "sub", "aud"]);
+    // aud is the client, any of ours may call us
+    validation.validate_aud = false;
+    validation.validate_exp = !allow_expired;
+
+    decode_typed(token, ACCESS_TOKEN_TYP, &validation, jwk).map_err(|e| match e.kind() {
+        ErrorKind::ExpiredSignature => AppError::unauthorized("Access token expired"),
+        _ => AppError::unauthorized("Invalid access token"),
+    })
+}
+
+// an id token we issued, handed back as `id_token_hint`. expired ones still say who it was for
+pub fn verify_id_token_hint(token: &str, jwk: &Jwk) -> Result<IdTokenClaims, AppError> {
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_issuer(&[issuer()]);
+    validation.set_required_spec_claims(&["iss", "sub", "aud"]);
+    validation.validate_aud = false;
+    validation.validate_exp = false;
+
+    decode_typed(token, "JWT", &validation, jwk).map_err(|_| AppError::bad_request("Invalid id_token_hint"))
+}
+
+// the typ header is checked first, so one kind of token can't stand in for another
+fn decode_typed<T: DeserializeOwned>(
+    token: &str,
+    typ: &str,
+    validation: &Validation,
+    jwk: &Jwk,
+) -> Result<T, jsonwebtoken::errors::Error> {
+    let header = decode_header(token)?;
+    if header.typ.as_deref() != Some(typ) {
+        return Err(ErrorKind::InvalidToken.into());
+    }
+    decode::<T>(token, &jwk.decoding_key, validation).map(|data| data.claims)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailVerificationClaims {
+    #[serde(flatten)]
+    pub base: BaseClaims,
+    // the address being verified, so a link dies when the email changes
+    pub email: String,
+}
+
+// signed link token, single use is enforced by its jti row in `token::verification`
+pub fn create_email_verification(
+    user_id: &str,
+    email: &str,
+    jti: &str,
+    expires_at: DateTime<Utc>,
+    encoding_key: &EncodingKey,
+) -> Result<String, AppError> {
+    let claims = EmailVerificationClaims {
+        base: BaseClaims {
+            sub: user_id.to_string(),
+            iss: issuer().to_string(),
+            aud: issuer().to_string(),
+            exp: expires_at.timestamp() as u64,
+            iat: Utc::now().timestamp() as u64,
+            jti: jti.to_string(),
+        },
+        email: email.to_string(),
+    };
+
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(KEY_ID.to_string());
+    header.typ = Some(EMAIL_VERIFICATION_TYP.to_string());
+    encode(&header, &claims, encoding_key).map_err(AppError::from)
+}
+
+pub fn verify_email_verification(token: &str, jwk: &Jwk) -> Result<EmailVerificationClaims, AppError> {
+    let mut validation = Validation::new(Algorithm::RS256);
+    validation.set_issuer(&[issuer()]);
+    validation.set_audience(&[issuer()]);
+
+    decode_typed(token, EMAIL_VERIFICATION_TYP, &validation, jwk).map_err(|e| match e.kind() {
+        ErrorKind::ExpiredSignature => AppError::bad_request("This link has expired"),
+        _ => AppError::bad_request("Invalid verification link"),
+    })
+}
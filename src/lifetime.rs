@@ -0,0 +1,105 @@
+use chrono::Duration;
+use std::sync::LazyLock;
+
+// global defaults, overridable with env vars (in seconds) and then per client
+static DEFAULTS: LazyLock<TokenLifetimes> = LazyLock::new(|| TokenLifetimes {
+    access: from_env("ACCESS_TOKEN_LIFETIME", 60 * 60),
+    id: from_env("ID_TOKEN_LIFETIME", 60 * 60),
+    refresh_idle: from_env("REFRESH_TOKEN_IDLE_LIFETIME", 60 * 60 * 24 * 30),
+    refresh_absolute: from_env("REFRESH_TOKEN_ABSOLUTE_LIFETIME", 60 * 60 * 24 * 90),
+    code: from_env("AUTH_CODE_LIFETIME", 60 * 10),
+});
+
+fn from_env(key: &str, default_secs: i64) -> Duration {
+    let secs = std::env::var(key)
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|&secs| secs > 0)
+        .unwrap_or(default_secs);
+    Duration::seconds(secs)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TokenLifetimes {
+    pub access: Duration,
+    pub id: Duration,
+    // a refresh token dies if unused for `refresh_idle`, and its whole rotation chain after `refresh_absolute`
+    pub refresh_idle: Duration,
+    pub refresh_absolute: Duration,
+    pub code: Duration,
+}
+
+impl Default for TokenLifetimes {
+    fn default() -> Self {
+        *DEFAULTS
+    }
+}
+
+impl TokenLifetimes {
+    pub fn for_client(client: &crate::client::Model) -> Self {
+        let defaults = Self::default();
+        let or_default =
+            |secs: Option<i64>, default: Duration| secs.filter(|&s| s > 0).map(Duration::seconds).unwrap_or(default);
+
+        Self {
+            access: or_default(client.access_token_lifetime, defaults.access),
+            id: or_default(client.id_token_lifetime, defaults.id),
+            refresh_idle: or_default(client.refresh_token_idle_lifetime, defaults.refresh_idle),
+            refresh_absolute: or_default(client.refresh_token_absolute_lifetime, defaults.refresh_absolute),
+            code: or_default(client.auth_code_lifetime, defaults.code),
+        }
+    }
+}
+
+// the sso session isn't tied to a client, so it only has a global lifetime
+static SESSION: LazyLock<Duration> = LazyLock::new(|| from_env("SESSION_LIFETIME", 60 * 60 * 24 * 14));
+
+pub fn session() -> Duration {
+    *SESSION
+}
+
+static EMAIL_VERIFICATION: LazyLock<Duration> = LazyLock::new(|| from_env("EMAIL_VERIFICATION_LIFETIME", 60 * 60 * 24));
+
+pub fn email_verification() -> Duration {
+    *EMAIL_VERIFICATION
+}
+
+static PASSWORD_RESET: LazyLock<Duration> = LazyLock::new(|| from_env("PASSWORD_RESET_LIFETIME", 60 * 30));
+
+pub fn password_reset() -> Duration {
+    *PASSWORD_RESET
+}
+
+static MFA_CHALLENGE: LazyLock<Duration> = LazyLock::new(|| from_env("MFA_CHALLENGE_LIFETIME", 60 * 5));
+
+pub fn mfa_challenge() -> Duration {
+    *MFA_CHALLENGE
+}
+
+// time to get through the upstream provider's login
+static FEDERATION: LazyLock<Duration> = LazyLock::new(|| from_env("FEDERATION_LIFETIME", 60 * 10));
+
+pub fn federation() -> Duration {
+    *FEDERATION
+}
+
+// how recent a login has to be to stand in for the password on sensitive changes
+static REAUTHENTICATION: LazyLock<Duration> = LazyLock::new(|| from_env("REAUTHENTICATION_LIFETIME", 60 * 10));
+
+pub fn reauthentication() -> Duration {
+    *REAUTHENTICATION
+}
+
+// how long a deleted account can still be restored before its data is purged
+static ACCOUNT_DELETION: LazyLock<Duration> = LazyLock::new(|| from_env("ACCOUNT_DELETION_GRACE", 60 * 60 * 24 * 30));
+
+pub fn account_deletion() -> Duration {
+    *ACCOUNT_DELETION
+}
+
+// how long login attempts are kept
+static LOGIN_HISTORY: LazyLock<Duration> = LazyLock::new(|| from_env("LOGIN_HISTORY_RETENTION", 60 * 60 * 24 * 90));
+
+pub fn login_history() -> Duration {
+    *LOGIN_HISTORY
+}
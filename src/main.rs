@@ -1,7 +1,8 @@
 use anyhow::Result;
 use axum::{
     Router, middleware as axum_mw,
-    routing::{get, patch, post},
+    response::Redirect,
+    routing::{delete, get, patch, post},
 };
 use std::time::Duration;
 use std::{net::SocketAddr, sync::Arc};
@@ -12,16 +13,28 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 // CLEANUP TODOODOTODOTODOO holy
 mod clients;
+mod crypto;
 mod db;
 mod entity;
 mod error;
+mod federation;
 mod handler;
 mod jwt;
+mod lifetime;
+mod mail;
 mod middleware;
 mod password;
+mod pow;
+mod roles;
 mod templates;
+mod throttle;
+mod totp;
 mod util;
-use entity::{client, token, user};
+mod webauthn;
+use entity::{
+    client, federated_identity, group, group_member, group_request, invite, login_attempt, membership, passkey,
+    permission, recovery_code, role, security_event, session, subject, suspension, token, user, user_role,
+};
 
 use std::sync::LazyLock;
 
@@ -41,6 +54,7 @@ pub struct AppState {
     pub db: sea_orm::DatabaseConnection,
     pub password: password::PasswordService,
     pub jwk: Jwk,
+    pub mailer: Option<Arc<dyn mail::Mailer>>,
 }
 
 #[tokio::main]
@@ -59,13 +73,21 @@ async fn main() -> Result<()> {
     // redirect uri validation
 
     let jwk = jwt::generate_jwk();
+    crypto::init();
+    client::init();
 
     let db = db::init_db().await?;
+    // read now, so a broken providers file stops the start rather than a login
+    tracing::info!("Upstream providers: {}", federation::providers().len());
+    tracing::info!("Registration: {:?}", handler::register::mode());
+    tracing::info!("Trusted proxies: {}", handler::geoloc::trusted_proxies());
+    tokio::spawn(db::housekeeping(db.clone()));
 
     let app_state = AppState {
         db: db.clone(),
         password: password::PasswordService::default(),
         jwk,
+        mailer: mail::from_env()?,
     };
 
     // basic rate limit
@@ -78,17 +100,109 @@ async fn main() -> Result<()> {
     );
 
     let app = Router::new()
-        .route("/", get(|| async { "hello from sjallabong" }))
+        .route("/", get(|| async { Redirect::to("/success") }))
+        .route("/success", get(handler::portal::get))
         .route("/token", post(handler::token::post))
         .route("/authorize", get(handler::auth::get).post(handler::auth::post))
+        .route("/authorize/totp", post(handler::totp::post))
+        .route("/authorize/recovery", post(handler::recovery::post))
+        .route("/authorize/federated/callback", get(handler::federation::callback))
+        .route("/authorize/federated/{provider}", get(handler::federation::start))
+        .route("/authorize/passkey", post(handler::passkey::login))
+        .route("/authorize/passkey/options", post(handler::passkey::login_options))
         .route("/register", get(handler::register::get).post(handler::register::post))
         .route("/revoke", post(handler::revoke::post))
+        .route("/webhooks/membership", post(handler::webhook::membership))
+        .route("/logout", get(handler::logout::get).post(handler::logout::post))
+        .route("/session/check", get(handler::session::check))
+        .route(
+            "/verify-email",
+            get(handler::verify_email::get).post(handler::verify_email::post),
+        )
+        .route(
+            "/forgot-password",
+            get(handler::password_reset::forgot_get).post(handler::password_reset::forgot_post),
+        )
+        .route(
+            "/reset-password",
+            get(handler::password_reset::reset_get).post(handler::password_reset::reset_post),
+        )
+        .route(
+            "/account/totp",
+            get(handler::totp::setup_get).post(handler::totp::setup_post),
+        )
+        .route("/account/totp/start", post(handler::totp::setup_start))
+        .route(
+            "/account/passkeys",
+            get(handler::passkey::account_get).post(handler::passkey::register),
+        )
+        .route("/account/passkeys/options", post(handler::passkey::register_options))
+        .route("/account/passkeys/delete", post(handler::passkey::delete))
+        .route("/account/profile", post(handler::portal::profile))
+        .route("/account/activity", get(handler::activity::account_get))
+        .route("/account/sessions", get(handler::devices::account_get))
+        .route("/account/sessions/revoke", post(handler::devices::account_revoke))
+        .route("/account/data", get(handler::account::data_get))
+        .route("/account/data/export", get(handler::account::export))
+        .route("/account/delete", post(handler::account::delete))
+        .route("/account/restore", post(handler::account::restore))
+        .route("/account/identities", get(handler::federation::account_get))
+        .route("/account/identities/link", post(handler::federation::link))
+        .route("/account/identities/unlink", post(handler::federation::unlink))
+        .route(
+            "/account/recovery-codes",
+            get(handler::recovery::account_get).post(handler::recovery::account_post),
+        )
+        .route(
+            "/account/password",
+            get(handler::update::password::form_get).post(handler::update::password::form_post),
+        )
         .route("/.well-known/jwks.json", get(handler::jwks::get))
         .layer(GovernorLayer::new(rate_limit_config))
         .merge(
             Router::new()
                 .route("/userinfo", get(handler::userinfo::get))
+                .route("/activity", get(handler::activity::get))
+                .route("/sessions", get(handler::devices::get).delete(handler::devices::delete))
+                .route("/groups", get(handler::group::list).post(handler::group::create))
+                .route(
+                    "/groups/{id}",
+                    get(handler::group::get)
+                        .patch(handler::group::update)
+                        .delete(handler::group::delete),
+                )
+                .route(
+                    "/groups/{id}/members",
+                    patch(handler::group::set_role).delete(handler::group::remove_member),
+                )
+                .route("/groups/{id}/invites", post(handler::group::invite))
+                .route("/groups/{id}/join", post(handler::group::join))
+                .route("/groups/{id}/requests", get(handler::group::requests))
+                .route("/groups/{id}/requests/approve", post(handler::group::approve))
+                .route("/groups/{id}/requests/decline", post(handler::group::decline))
+                .route(
+                    "/invites",
+                    get(handler::invite::get)
+                        .post(handler::invite::post)
+                        .delete(handler::invite::delete),
+                )
                 .route("/update/user", patch(handler::update::user::patch))
+                .route("/update/password", post(handler::update::password::post))
+                .route("/update/lockout", delete(handler::update::lockout::delete))
+                .route(
+                    "/update/client/secret",
+                    post(handler::update::client::post).delete(handler::update::client::delete),
+                )
+                .route(
+                    "/update/membership",
+                    get(handler::update::membership::get)
+                        .post(handler::update::membership::post)
+                        .delete(handler::update::membership::delete),
+                )
+                .route(
+                    "/update/suspension",
+                    post(handler::update::suspension::post).delete(handler::update::suspension::delete),
+                )
                 .layer(axum_mw::from_fn_with_state(app_state.clone(), middleware::user::auth)),
         )
         .route("/geolocate", get(handler::geoloc::get))
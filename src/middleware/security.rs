@@ -28,13 +28,9 @@ pub fn cors_layer() -> CorsLayer {
     }
 }
 
-pub async fn headers(request: Request, next: Next) -> Response {
-    let mut response = next.run(request).await;
-
-    let headers = response.headers_mut();
-
-    // TODO DONT HARDCODE
-    let csp = if *crate::IS_PRODUCTION {
+// TODO DONT HARDCODE
+pub fn content_security_policy() -> &'static str {
+    if *crate::IS_PRODUCTION {
         "default-src 'self'; \
         script-src 'self' 'unsafe-inline'; \
         style-src 'self' 'unsafe-inline' https://fonts.googleapis.com; \
@@ -51,11 +47,23 @@ pub async fn headers(request: Request, next: Next) -> Response {
         font-src 'self' https://fonts.gstatic.com; \
         img-src 'self' data: http: https:; \
         frame-ancestors 'none'"
-    };
+    }
+}
 
-    headers.insert(header::CONTENT_SECURITY_POLICY, csp.parse().unwrap());
+pub async fn headers(request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+
+    let headers = response.headers_mut();
+
+    // pages that get framed (check_session) or frame others (logout) bring their own policy
+    if !headers.contains_key(header::CONTENT_SECURITY_POLICY) {
+        headers.insert(
+            header::CONTENT_SECURITY_POLICY,
+            content_security_policy().parse().unwrap(),
+        );
+        headers.insert("x-frame-options", "DENY".parse().unwrap());
+    }
 
-    headers.insert("x-frame-options", "DENY".parse().unwrap());
     headers.insert("x-content-type-options", "nosniff".parse().unwrap());
     headers.insert("x-xss-protection", "1; mode=block".parse().unwrap());
     headers.insert(
@@ -14,6 +14,10 @@ use sea_orm::*;
 pub struct AuthenticatedUser {
     pub user: crate::user::Model,
     pub access_token: crate::token::access::Model,
+    // what the calling client knows the user as, see `client::Model::subject_for`
+    pub sub: String,
+    // global roles only, what our own endpoints check
+    pub grants: crate::user_role::Grants,
 }
 
 impl AuthenticatedUser {
@@ -36,6 +40,14 @@ impl AuthenticatedUser {
     pub fn has_roles(&self) -> bool {
         self.has_scope("roles")
     }
+
+    pub fn has_groups(&self) -> bool {
+        self.has_scope("groups")
+    }
+
+    pub fn can(&self, permission: &str) -> bool {
+        self.grants.can(permission)
+    }
 }
 
 pub async fn auth(
@@ -50,24 +62,29 @@ pub async fn auth(
         .and_then(|h| h.strip_prefix("Bearer "))
         .or_unauthorized("Bearer token required")?;
 
-    let access_token = crate::token::access::Entity::verify(token, &app_state.db).await?;
-    let access_token = match access_token {
-        Some(token) => token,
-        None => {
-            if let Some(_expired_token) = crate::token::access::Entity::find_by_id(token)
-                .one(&app_state.db).await? {
-                return Err(AppError::unauthorized("Access token expired"));
-            } else {
-                return Err(AppError::unauthorized("Invalid access token"));
-            }
-        }
-    };
+    // signature and claims are checked locally, the db only says whether the jti was revoked
+    let claims = crate::jwt::verify_access_token(token, &app_state.jwk, false)?;
+
+    let access_token = crate::token::access::Entity::verify(&claims.base.jti, &app_state.db)
+        .await?
+        .or_unauthorized("Access token revoked")?;
 
     let user = crate::user::Entity::find_by_id(&access_token.user_id)
         .one(&app_state.db)
         .await?
         .or_unauthorized("User not found")?;
-    let auth_user = AuthenticatedUser { user, access_token };
+    if let Some(restriction) = crate::user::Entity::restriction(&user, &app_state.db).await? {
+        return Err(AppError::forbidden(restriction));
+    }
+
+    let grants = crate::user_role::Entity::grants(&user.id, None, &app_state.db).await?;
+
+    let auth_user = AuthenticatedUser {
+        user,
+        access_token,
+        sub: claims.base.sub,
+        grants,
+    };
     request.extensions_mut().insert(auth_user);
     Ok(next.run(request).await)
 }
@@ -56,3 +56,11 @@ impl PasswordService {
         }
     }
 }
+
+// same rules everywhere a password is chosen
+pub fn validate(password: &str) -> Option<&'static str> {
+    if password.len() < 6 || password.len() > 128 {
+        return Some("Password must be 6+ characters long");
+    }
+    None
+}
@@ -0,0 +1,174 @@
+use crate::IS_PRODUCTION;
+use chrono::{Duration, Utc};
+use redis::AsyncCommands;
+use sea_orm::*;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// proof of work on the login and register forms, in place of a captcha. the page gets a challenge, the
+// browser looks for a nonce whose sha-256 with it starts with `difficulty` zero bits, and the form posts
+// both back. ips that failed lately get harder ones. a honeypot field and a minimum time on the page catch
+// the bots that don't run scripts at all
+
+pub const LOGIN: &str = "login";
+pub const REGISTER: &str = "register";
+
+// zero bits asked of everyone, about 16k hashes. POW_DIFFICULTY overrides it, 0 turns the work off
+static BASE_DIFFICULTY: LazyLock<u32> = LazyLock::new(|| {
+    std::env::var("POW_DIFFICULTY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(14)
+        .min(MAX_DIFFICULTY)
+});
+// each bit doubles the work, this is some seconds on a phone
+const MAX_DIFFICULTY: u32 = 22;
+
+const CHALLENGE_LIFETIME: u64 = 60 * 60;
+
+// a person takes at least this long to fill the form in, password managers included
+fn min_fill_secs(purpose: &str) -> u64 {
+    match purpose {
+        REGISTER => 3,
+        _ => 1,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Challenge {
+    pub challenge: String,
+    pub difficulty: u32,
+}
+
+// what the form posts back. serde defaults, so a form without them fails the check rather than the parse
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct Solution {
+    #[serde(default)]
+    pub pow_challenge: String,
+    #[serde(default)]
+    pub pow_nonce: String,
+    // the honeypot, hidden from people
+    #[serde(default)]
+    pub website: String,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+// issued challenges, debug only. like the csrf tokens, redis in production
+static CHALLENGES: LazyLock<RwLock<HashMap<String, (String, u64)>>> = LazyLock::new(|| RwLock::new(HashMap::new()));
+
+fn key(challenge: &str) -> String {
+    format!("pow:{challenge}")
+}
+
+// "{purpose}:{difficulty}:{issued_at}:{ip}", the ip last since v6 ones have colons of their own
+async fn store(challenge: &str, value: String) {
+    if *IS_PRODUCTION {
+        if let Ok(mut conn) = crate::get_redis_connection().await {
+            let _: Result<(), _> = conn.set_ex(key(challenge), value, CHALLENGE_LIFETIME).await;
+        }
+    } else if let Ok(mut challenges) = CHALLENGES.write() {
+        let now = now();
+        challenges.retain(|_, (_, expiry)| *expiry > now);
+        challenges.insert(challenge.to_string(), (value, now + CHALLENGE_LIFETIME));
+    }
+}
+
+// single use, it's gone once read
+async fn take(challenge: &str) -> Option<String> {
+    if *IS_PRODUCTION {
+        let mut conn = crate::get_redis_connection().await.ok()?;
+        conn.get_del(key(challenge)).await.ok()
+    } else {
+        let mut challenges = CHALLENGES.write().ok()?;
+        let (value, expiry) = challenges.remove(challenge)?;
+        (expiry > now()).then_some(value)
+    }
+}
+
+// extra bits for an ip: one per recent failed challenge or five failed logins, and more for a day of
+// failed logins behind it
+async fn difficulty(ip: &str, db: &DatabaseConnection) -> Result<u32, DbErr> {
+    if *BASE_DIFFICULTY == 0 {
+        return Ok(0);
+    }
+
+    let recent = crate::throttle::pow_failures(ip).await.min(4) + crate::throttle::ip_failures(ip).await / 5;
+    let failed_logins = crate::login_attempt::Entity::find()
+        .filter(crate::login_attempt::Column::Ip.eq(ip))
+        .filter(crate::login_attempt::Column::Success.eq(false))
+        .filter(crate::login_attempt::Column::CreatedAt.gt(Utc::now() - Duration::days(1)))
+        .count(db)
+        .await?;
+    let reputation = match failed_logins {
+        0..5 => 0,
+        5..20 => 1,
+        20..100 => 2,
+        _ => 4,
+    };
+    Ok((*BASE_DIFFICULTY + recent + reputation).min(MAX_DIFFICULTY))
+}
+
+pub async fn issue(purpose: &str, ip: &str, db: &DatabaseConnection) -> Result<Challenge, DbErr> {
+    let challenge = crate::util::generate_random_string(32);
+    let difficulty = difficulty(ip, db).await?;
+    store(&challenge, format!("{purpose}:{difficulty}:{}:{ip}", now())).await;
+    Ok(Challenge { challenge, difficulty })
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+// Err is what to tell the user. checked before anything costly like a password hash
+pub async fn verify(purpose: &str, solution: &Solution, ip: &str) -> Result<(), &'static str> {
+    let result = check(purpose, solution, ip).await;
+    if let Err(reason) = &result {
+        tracing::info!(%ip, purpose, reason, "Proof of work failed");
+        crate::throttle::pow_failed(ip).await;
+    }
+    result.map_err(|_| "Your browser couldn't be checked, try again")
+}
+
+// Err is the reason, for the log
+async fn check(purpose: &str, solution: &Solution, ip: &str) -> Result<(), &'static str> {
+    if !solution.website.is_empty() {
+        return Err("honeypot");
+    }
+    let stored = take(&solution.pow_challenge).await.ok_or("unknown challenge")?;
+    let mut parts = stored.splitn(4, ':');
+    let (Some(stored_purpose), Some(difficulty), Some(issued_at), Some(stored_ip)) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err("unknown challenge");
+    };
+    if stored_purpose != purpose {
+        return Err("wrong form");
+    }
+    // the difficulty was picked for that ip, an easy one fetched from elsewhere doesn't count
+    if stored_ip != ip {
+        return Err("other ip");
+    }
+    let issued_at: u64 = issued_at.parse().map_err(|_| "unknown challenge")?;
+    if now().saturating_sub(issued_at) < min_fill_secs(purpose) {
+        return Err("too fast");
+    }
+
+    let difficulty: u32 = difficulty.parse().map_err(|_| "unknown challenge")?;
+    let hash = Sha256::digest(format!("{}:{}", solution.pow_challenge, solution.pow_nonce).as_bytes());
+    if leading_zero_bits(&hash) < difficulty {
+        return Err("not solved");
+    }
+    Ok(())
+}
@@ -0,0 +1,36 @@
+use anyhow::Result;
+use sea_orm::*;
+
+use crate::permission;
+
+// the roles we rely on. permissions added here later reach existing installs on the next start
+pub async fn create_roles(db: &DatabaseConnection) -> Result<()> {
+    let roles = vec![
+        ("admin", "Can do anything", permission::ALL.to_vec()),
+        (
+            "moderator",
+            "Suspends regular accounts",
+            vec![permission::USERS_SUSPEND],
+        ),
+        ("member", "Sjallabong member", vec![]),
+    ];
+
+    for (name, description, permissions) in roles {
+        let existing = crate::role::Entity::find_by_id(name).one(db).await?;
+        if existing.is_none() {
+            let role = crate::role::ActiveModel {
+                name: Set(name.to_string()),
+                description: Set(description.to_string()),
+                ..Default::default()
+            };
+            role.insert(db).await?;
+            tracing::info!("Created role: {name}");
+        }
+
+        for permission in permissions {
+            crate::permission::Entity::add(name, permission, db).await?;
+        }
+    }
+
+    Ok(())
+}
@@ -9,7 +9,11 @@ pub struct RegisterTemplate {
     // preserve
     pub email: String,
     pub username: String,
+    pub invite: String,
     pub csrf_token: String,
+    // while registration is invite only
+    pub invite_required: bool,
+    pub pow: crate::pow::Challenge,
 
     pub client_id: String,
     pub redirect_uri: String,
@@ -25,6 +29,11 @@ pub struct LoginTemplate {
     pub errors: HashMap<String, String>,
     pub login: String, // preserve
     pub csrf_token: String,
+    // "continue with x" buttons
+    pub providers: &'static [crate::federation::Provider],
+    // no register button while registration is closed
+    pub can_register: bool,
+    pub pow: crate::pow::Challenge,
 
     pub client_id: String,
     pub redirect_uri: String,
@@ -40,3 +49,276 @@ pub struct ErrorTemplate {
     pub status_code: u16,
     pub message: String,
 }
+
+#[derive(Template)]
+#[template(path = "check_session.html")]
+pub struct CheckSessionTemplate {
+    pub cookie_name: &'static str,
+}
+
+#[derive(Template)]
+#[template(path = "logout.html")]
+pub struct LogoutTemplate {
+    pub frames: Vec<String>,
+    pub redirect: Option<String>,
+}
+
+// a logout without an id token from the session
+#[derive(Template)]
+#[template(path = "logout_confirm.html")]
+pub struct LogoutConfirmTemplate {
+    pub csrf_token: String,
+    pub client_id: String,
+    pub post_logout_redirect_uri: String,
+    pub state: String,
+}
+
+#[derive(Template)]
+#[template(path = "verify_email.html")]
+pub struct VerifyEmailTemplate {
+    pub email: String,
+    pub token: String,
+}
+
+#[derive(Template)]
+#[template(path = "notice.html")]
+pub struct NoticeTemplate {
+    pub title: String,
+    pub message: String,
+}
+
+#[derive(Template)]
+#[template(path = "forgot_password.html")]
+pub struct ForgotPasswordTemplate {
+    pub errors: HashMap<String, String>,
+    pub email: String, // preserve
+    pub csrf_token: String,
+    pub sent: bool,
+}
+
+#[derive(Template)]
+#[template(path = "reset_password.html")]
+pub struct ResetPasswordTemplate {
+    pub errors: HashMap<String, String>,
+    pub csrf_token: String,
+    pub token: String,
+}
+
+#[derive(Template)]
+#[template(path = "change_password.html")]
+pub struct ChangePasswordTemplate {
+    pub errors: HashMap<String, String>,
+    pub username: String,
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "totp.html")]
+pub struct TotpTemplate {
+    pub errors: HashMap<String, String>,
+    // which second factors to offer
+    pub totp: bool,
+    pub passkey: bool,
+    pub challenge: String,
+    pub csrf_token: String,
+    pub recovery_csrf_token: String,
+
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub state: String,
+    pub scope: String,
+    pub code_challenge: String,
+    pub code_challenge_method: String,
+}
+
+#[derive(Template)]
+#[template(path = "totp_setup.html")]
+pub struct TotpSetupTemplate {
+    pub errors: HashMap<String, String>,
+    pub qr_svg: String,
+    pub secret: String,
+    // a recent login stands in for the password
+    pub fresh: bool,
+    pub csrf_token: String,
+}
+
+// the password before a seed is made, unless the login was recent
+#[derive(Template)]
+#[template(path = "totp_start.html")]
+pub struct TotpStartTemplate {
+    pub has_password: bool,
+    pub error: Option<String>,
+    pub csrf_token: String,
+}
+
+pub struct PasskeyView {
+    pub id: String,
+    pub nickname: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "passkeys.html")]
+pub struct PasskeysTemplate {
+    pub username: String,
+    pub passkeys: Vec<PasskeyView>,
+    // a recent login stands in for the password
+    pub fresh: bool,
+    pub has_password: bool,
+    pub csrf_token: String,
+}
+
+pub struct IdentityView {
+    pub provider: String,
+    pub provider_name: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: String,
+    pub last_login_at: Option<String>,
+    pub csrf_token: String,
+}
+
+pub struct ProviderLinkView {
+    pub id: String,
+    pub name: String,
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "identities.html")]
+pub struct IdentitiesTemplate {
+    pub username: String,
+    pub identities: Vec<IdentityView>,
+    // ones not linked yet
+    pub providers: Vec<ProviderLinkView>,
+    // a recent login stands in for the password
+    pub fresh: bool,
+    pub has_password: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "account_data.html")]
+pub struct AccountDataTemplate {
+    pub username: String,
+    pub grace_days: i64,
+    // a recent login stands in for the password
+    pub fresh: bool,
+    pub has_password: bool,
+    pub error: Option<String>,
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "restore.html")]
+pub struct RestoreTemplate {
+    pub username: String,
+    pub delete_after: String,
+    pub csrf_token: String,
+
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub state: String,
+    pub scope: String,
+    pub code_challenge: String,
+    pub code_challenge_method: String,
+}
+
+pub struct ActivityView {
+    pub at: String,
+    pub methods: String,
+    pub success: bool,
+    pub failure: Option<String>,
+    pub ip: String,
+    pub country: Option<String>,
+    pub client: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Template)]
+#[template(path = "activity.html")]
+pub struct ActivityTemplate {
+    pub username: String,
+    pub entries: Vec<ActivityView>,
+    pub page: u64,
+    pub more: bool,
+}
+
+#[derive(Template)]
+#[template(path = "portal.html")]
+pub struct PortalTemplate {
+    // the profile form, as saved or as typed
+    pub username: String,
+    pub avatar_url: String,
+    pub bio: String,
+    pub country: String,
+    pub email: String,
+    pub is_verified: bool,
+    pub member_since: String,
+    pub has_password: bool,
+    pub has_totp: bool,
+    pub passkeys: usize,
+    pub recovery_codes: u64,
+    pub identities: usize,
+    pub sessions: usize,
+    pub apps: usize,
+    pub errors: HashMap<String, String>,
+    pub csrf_token: String,
+}
+
+pub struct SessionView {
+    pub id: String,
+    pub current: bool,
+    pub device: String,
+    pub location: String,
+    pub signed_in_at: String,
+    pub last_seen_at: String,
+    // names of the apps signed in to through it
+    pub clients: String,
+    pub csrf_token: String,
+}
+
+pub struct GrantView {
+    pub id: String,
+    pub device: String,
+    pub location: String,
+    pub last_used_at: String,
+    pub csrf_token: String,
+}
+
+pub struct AppView {
+    pub client_id: String,
+    pub name: String,
+    pub grants: Vec<GrantView>,
+    // for revoking all of them
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "sessions.html")]
+pub struct SessionsTemplate {
+    pub username: String,
+    pub sessions: Vec<SessionView>,
+    pub apps: Vec<AppView>,
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "recovery_codes.html")]
+pub struct RecoveryCodesTemplate {
+    pub title: String,
+    pub message: String,
+    pub codes: Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "recovery.html")]
+pub struct RecoveryTemplate {
+    pub username: String,
+    pub has_second_factor: bool,
+    pub remaining: u64,
+    pub error: Option<String>,
+    pub csrf_token: String,
+}
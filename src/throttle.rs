@@ -0,0 +1,227 @@
+use crate::IS_PRODUCTION;
+use redis::AsyncCommands;
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// login throttling, on top of the per ip rate limit. failures are counted per account and per ip, so
+// neither guessing one password from many ips nor spraying many accounts from one ip gets far. wrong
+// second factors are counted per user on their own, a new challenge from the password doesn't reset them.
+// counts only go up atomically (redis INCR), so guesses sent side by side are all counted
+
+struct Policy {
+    // failures before any delay
+    free: u32,
+    // failures before a lockout for the whole window
+    lockout: u32,
+}
+
+const ACCOUNT: Policy = Policy { free: 3, lockout: 10 };
+const IP: Policy = Policy { free: 10, lockout: 50 };
+const SECOND_FACTOR: Policy = Policy { free: 3, lockout: 10 };
+
+// reset mails sent in the window, per address and per ip
+const MAILS_PER_ADDRESS: u32 = 3;
+const MAILS_PER_IP: u32 = 10;
+
+// failures are forgotten this long after the last one
+const WINDOW: u64 = 15 * 60;
+const MAX_DELAY: u64 = 5 * 60;
+
+#[derive(Clone, Copy, Default)]
+struct Failures {
+    count: u32,
+    last: u64,
+}
+
+impl Failures {
+    // seconds until the next attempt is allowed
+    fn wait(&self, policy: &Policy, now: u64) -> u64 {
+        let delay = if self.count >= policy.lockout {
+            WINDOW
+        } else if self.count > policy.free {
+            2u64.saturating_pow(self.count - policy.free).min(MAX_DELAY)
+        } else {
+            0
+        };
+        (self.last + delay).saturating_sub(now)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+// whose failures a login counts towards: the account it names, however it was named, or the name itself
+// when there's no such account
+pub fn account(user: Option<&crate::user::Model>, login: &str) -> String {
+    match user {
+        Some(user) => format!("user:{}", user.id),
+        None => format!("login:{}", crate::user::normalize(login)),
+    }
+}
+
+fn account_key(account: &str) -> String {
+    format!("throttle:account:{account}")
+}
+
+fn ip_key(ip: &str) -> String {
+    format!("throttle:ip:{ip}")
+}
+
+fn second_factor_key(user_id: &str) -> String {
+    format!("throttle:mfa:{user_id}")
+}
+
+fn pow_key(ip: &str) -> String {
+    format!("throttle:pow:{ip}")
+}
+
+fn mail_key(kind: &str, value: &str) -> String {
+    format!("throttle:mail:{kind}:{value}")
+}
+
+// in memory store, debug only
+static FAILURES: LazyLock<RwLock<HashMap<String, Failures>>> = LazyLock::new(|| RwLock::new(HashMap::new()));
+
+// in redis the count is the value, and the last failure is when its WINDOW long expiry was last reset
+async fn get(key: &str) -> Failures {
+    if *IS_PRODUCTION {
+        if let Ok(mut conn) = crate::get_redis_connection().await {
+            let value: Result<(Option<String>, i64), _> = redis::pipe().get(key).ttl(key).query_async(&mut conn).await;
+            if let Ok((Some(count), ttl)) = value
+                && ttl > 0
+            {
+                return Failures {
+                    count: count.parse().unwrap_or_default(),
+                    last: (now() + ttl as u64).saturating_sub(WINDOW),
+                };
+            }
+        }
+        Failures::default()
+    } else {
+        let Ok(mut failures) = FAILURES.write() else {
+            return Failures::default();
+        };
+        let now = now();
+        failures.retain(|_, f| f.last + WINDOW > now);
+        failures.get(key).copied().unwrap_or_default()
+    }
+}
+
+// one more failure, the count after it
+async fn increment(key: &str) -> u32 {
+    if *IS_PRODUCTION {
+        let Ok(mut conn) = crate::get_redis_connection().await else {
+            return 0;
+        };
+        let incr = async |conn: &mut redis::aio::ConnectionManager| -> redis::RedisResult<u32> {
+            let (count,): (u32,) = redis::pipe()
+                .atomic()
+                .incr(key, 1)
+                .expire(key, WINDOW as i64)
+                .ignore()
+                .query_async(conn)
+                .await?;
+            Ok(count)
+        };
+        match incr(&mut conn).await {
+            Ok(count) => count,
+            // a "count:last" value from before counts were bare numbers
+            Err(_) => {
+                let _: Result<(), _> = conn.del(key).await;
+                incr(&mut conn).await.unwrap_or_default()
+            }
+        }
+    } else {
+        let Ok(mut failures) = FAILURES.write() else {
+            return 0;
+        };
+        let failures = failures.entry(key.to_string()).or_default();
+        failures.count += 1;
+        failures.last = now();
+        failures.count
+    }
+}
+
+async fn clear(key: &str) {
+    if *IS_PRODUCTION {
+        if let Ok(mut conn) = crate::get_redis_connection().await {
+            let _: Result<(), _> = conn.del(key).await;
+        }
+    } else if let Ok(mut failures) = FAILURES.write() {
+        failures.remove(key);
+    }
+}
+
+// seconds to wait, if this account (see `account`) or ip is held back
+pub async fn check(account: &str, ip: &str) -> Option<u64> {
+    let now = now();
+    let wait = get(&account_key(account))
+        .await
+        .wait(&ACCOUNT, now)
+        .max(get(&ip_key(ip)).await.wait(&IP, now));
+    (wait > 0).then_some(wait)
+}
+
+pub async fn failed(account: &str, ip: &str) {
+    for (key, policy) in [(account_key(account), &ACCOUNT), (ip_key(ip), &IP)] {
+        if increment(&key).await == policy.lockout {
+            tracing::warn!(%key, "Login locked out");
+        }
+    }
+}
+
+// by an admin, or a password proven again in a signed in session
+pub async fn unlock_account(account: &str) {
+    clear(&account_key(account)).await;
+}
+
+// after every factor checked out, not just the password. the ip keeps its count, or signing in to an own
+// account would wipe a spray
+pub async fn signed_in(user: &crate::user::Model) {
+    clear(&account_key(&account(Some(user), &user.username))).await;
+    clear(&second_factor_key(&user.id)).await;
+}
+
+pub async fn unlock_ip(ip: &str) {
+    clear(&ip_key(ip)).await;
+}
+
+// seconds to wait, if this user's second factor is held back
+pub async fn check_second_factor(user_id: &str) -> Option<u64> {
+    let wait = get(&second_factor_key(user_id)).await.wait(&SECOND_FACTOR, now());
+    (wait > 0).then_some(wait)
+}
+
+// a wrong code, recovery code or passkey after the password
+pub async fn second_factor_failed(user_id: &str) {
+    let key = second_factor_key(user_id);
+    if increment(&key).await == SECOND_FACTOR.lockout {
+        tracing::warn!(%key, "Second factor locked out");
+    }
+}
+
+// whether this ip may ask for another reset mail. counts the ask either way
+pub async fn mail_ip_allowed(ip: &str) -> bool {
+    increment(&mail_key("ip", ip)).await <= MAILS_PER_IP
+}
+
+// whether another reset mail may go to this address, so one inbox can't be flooded from many ips
+pub async fn mail_address_allowed(address: &str) -> bool {
+    increment(&mail_key("address", &crate::user::normalize(address))).await <= MAILS_PER_ADDRESS
+}
+
+// failed logins from this ip in the window, see `pow`
+pub async fn ip_failures(ip: &str) -> u32 {
+    get(&ip_key(ip)).await.count
+}
+
+// failed proof of work, honeypot or timing checks. only raises the difficulty, never locks out
+pub async fn pow_failures(ip: &str) -> u32 {
+    get(&pow_key(ip)).await.count
+}
+
+pub async fn pow_failed(ip: &str) {
+    increment(&pow_key(ip)).await;
+}
@@ -1,8 +1,11 @@
 use crate::{
-    IS_PRODUCTION, client,
+    AppState, IS_PRODUCTION, client,
     error::{AppError, OptionExt},
 };
-use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use base64::{
+    Engine as _,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
 use redis::AsyncCommands;
 use ring::rand::{SecureRandom, SystemRandom};
 use sea_orm::*;
@@ -38,14 +41,56 @@ pub async fn get_client(client_id: &str, db: &DatabaseConnection) -> Result<clie
     Ok(client)
 }
 
-pub fn validate_redirect_uri(client: &client::Model, redirect_uri: &str) -> Result<(), AppError> {
-    let auth_base = if *crate::IS_PRODUCTION {
-        "https://auth.sjallabong.eu"
-    } else {
-        "http://localhost:3001"
+// rfc 6749 2.3.1, basic auth or form fields. public clients only have to say who they are
+pub async fn authenticate_client(
+    headers: &axum::http::HeaderMap,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+    app_state: &AppState,
+) -> Result<client::Model, AppError> {
+    let basic = headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Basic "))
+        .and_then(|b| STANDARD.decode(b).ok())
+        .and_then(|b| String::from_utf8(b).ok())
+        .and_then(|b| {
+            let (id, secret) = b.split_once(':')?;
+            Some((
+                urlencoding::decode(id).ok()?.into_owned(),
+                urlencoding::decode(secret).ok()?.into_owned(),
+            ))
+        });
+
+    let (client_id, client_secret) = match basic {
+        Some((id, _)) if client_id.is_some_and(|body_id| body_id != id) => {
+            return Err(AppError::unauthorized("client_id doesn't match the client credentials"));
+        }
+        Some((id, secret)) => (id, Some(secret)),
+        None => (
+            client_id.or_unauthorized("Client authentication required")?.to_string(),
+            client_secret.map(str::to_string),
+        ),
     };
 
-    if redirect_uri == format!("{}/success", auth_base) {
+    // invalid_client, a 401 like a wrong secret rather than a bad request
+    let client = client::Entity::find_by_id(&client_id)
+        .one(&app_state.db)
+        .await?
+        .or_unauthorized(format!("Invalid client_id: {client_id}"))?;
+
+    if let Some(hash) = &client.client_secret_hash {
+        let secret = client_secret.or_unauthorized("Client authentication required")?;
+        if !app_state.password.verify(&secret, hash)? {
+            return Err(AppError::unauthorized("Invalid client credentials"));
+        }
+    }
+
+    Ok(client)
+}
+
+pub fn validate_redirect_uri(client: &client::Model, redirect_uri: &str) -> Result<(), AppError> {
+    if redirect_uri == crate::handler::portal::uri() {
         return Ok(());
     }
 
@@ -96,3 +141,26 @@ pub async fn validate_csrf_token(token: &str) -> bool {
         tokens.remove(token).is_some()
     }
 }
+
+pub fn get_cookie(headers: &axum::http::HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(axum::http::header::COOKIE)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.to_string())
+}
+
+// max_age 0 clears it
+pub fn set_cookie(name: &str, value: &str, max_age_secs: i64, http_only: bool) -> String {
+    let mut cookie = format!("{name}={value}; Path=/; Max-Age={max_age_secs}; SameSite=Lax");
+    if http_only {
+        cookie.push_str("; HttpOnly");
+    }
+    if *IS_PRODUCTION {
+        cookie.push_str("; Secure");
+    }
+    cookie
+}
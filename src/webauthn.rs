@@ -0,0 +1,413 @@
+use anyhow::{Context, Result, bail, ensure};
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use ciborium::Value;
+use p256::ecdsa::{Signature, VerifyingKey, signature::Verifier};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::sync::LazyLock;
+
+// just enough of webauthn level 2 for passkeys and security keys: es256 credentials and no attestation.
+// we ask for "none", so whatever statement comes back is ignored rather than trusted
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+// cose algorithm and key parameters, rfc 9053
+pub const ES256: i64 = -7;
+const COSE_KTY: i64 = 1;
+const COSE_ALG: i64 = 3;
+const COSE_EC2_CRV: i64 = -1;
+const COSE_EC2_X: i64 = -2;
+const COSE_EC2_Y: i64 = -3;
+const COSE_KTY_EC2: i64 = 2;
+const COSE_CRV_P256: i64 = 1;
+
+// the host the credentials are scoped to, and the origin the browser has to report
+static RP_ID: LazyLock<String> = LazyLock::new(|| {
+    std::env::var("WEBAUTHN_RP_ID").unwrap_or_else(|_| {
+        url::Url::parse(crate::jwt::issuer())
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_else(|| "localhost".to_string())
+    })
+});
+
+pub fn rp_id() -> &'static str {
+    &RP_ID
+}
+
+pub fn origin() -> &'static str {
+    crate::jwt::issuer()
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+fn check_client_data(client_data_json: &[u8], kind: &str, challenge: &str) -> Result<()> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json).context("Invalid clientDataJSON")?;
+    ensure!(client_data.kind == kind, "Wrong ceremony type: {}", client_data.kind);
+    ensure!(client_data.challenge == challenge, "Challenge mismatch");
+    ensure!(
+        client_data.origin == origin(),
+        "Origin mismatch: {}",
+        client_data.origin
+    );
+    Ok(())
+}
+
+struct AuthenticatorData<'a> {
+    flags: u8,
+    sign_count: u32,
+    // attested credential data and extensions
+    rest: &'a [u8],
+}
+
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData<'_>> {
+    ensure!(data.len() >= 37, "Authenticator data too short");
+    ensure!(data[..32] == *Sha256::digest(rp_id().as_bytes()), "RP ID hash mismatch");
+
+    let flags = data[32];
+    ensure!(flags & FLAG_USER_PRESENT != 0, "User not present");
+
+    Ok(AuthenticatorData {
+        flags,
+        sign_count: u32::from_be_bytes(data[33..37].try_into()?),
+        rest: &data[37..],
+    })
+}
+
+fn map_get<'a>(map: &'a [(Value, Value)], key: &Value) -> Option<&'a Value> {
+    map.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+// cose_key to an uncompressed sec1 point, which is how we store it
+fn parse_cose_key(key: &Value) -> Result<Vec<u8>> {
+    let map = key.as_map().context("COSE key isn't a map")?;
+    let int = |label: i64| map_get(map, &Value::Integer(label.into())).and_then(Value::as_integer);
+    let bytes = |label: i64| map_get(map, &Value::Integer(label.into())).and_then(Value::as_bytes);
+
+    ensure!(int(COSE_KTY) == Some(COSE_KTY_EC2.into()), "Unsupported key type");
+    ensure!(int(COSE_ALG) == Some(ES256.into()), "Unsupported algorithm");
+    ensure!(int(COSE_EC2_CRV) == Some(COSE_CRV_P256.into()), "Unsupported curve");
+    let (Some(x), Some(y)) = (bytes(COSE_EC2_X), bytes(COSE_EC2_Y)) else {
+        bail!("Missing key coordinates");
+    };
+    ensure!(x.len() == 32 && y.len() == 32, "Bad key coordinates");
+
+    let mut point = vec![0x04];
+    point.extend_from_slice(x);
+    point.extend_from_slice(y);
+    VerifyingKey::from_sec1_bytes(&point).context("Invalid public key")?;
+    Ok(point)
+}
+
+pub struct NewCredential {
+    pub id: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+// navigator.credentials.create()
+pub fn verify_registration(
+    client_data_json: &[u8],
+    attestation_object: &[u8],
+    challenge: &str,
+) -> Result<NewCredential> {
+    check_client_data(client_data_json, "webauthn.create", challenge)?;
+
+    let attestation: Value = ciborium::from_reader(attestation_object).context("Invalid attestation object")?;
+    let attestation = attestation.as_map().context("Attestation object isn't a map")?;
+    let auth_data = map_get(attestation, &Value::Text("authData".to_string()))
+        .and_then(Value::as_bytes)
+        .context("Missing authData")?;
+
+    let auth_data = parse_authenticator_data(auth_data)?;
+    ensure!(
+        auth_data.flags & FLAG_ATTESTED_CREDENTIAL != 0,
+        "No attested credential"
+    );
+
+    // aaguid (16), credential id length (2), credential id, cose key
+    let rest = auth_data.rest;
+    ensure!(rest.len() >= 18, "Attested credential data too short");
+    let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+    ensure!(rest.len() >= 18 + id_len, "Credential id too short");
+    let id = &rest[18..18 + id_len];
+
+    let key: Value = ciborium::from_reader(&rest[18 + id_len..]).context("Invalid COSE key")?;
+
+    Ok(NewCredential {
+        id: URL_SAFE_NO_PAD.encode(id),
+        public_key: parse_cose_key(&key)?,
+        sign_count: auth_data.sign_count,
+    })
+}
+
+pub struct Assertion {
+    pub sign_count: u32,
+    // pin or biometric, needed for a passkey to stand in for the password
+    pub user_verified: bool,
+}
+
+// navigator.credentials.get()
+pub fn verify_assertion(
+    public_key: &[u8],
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+    signature: &[u8],
+    challenge: &str,
+) -> Result<Assertion> {
+    check_client_data(client_data_json, "webauthn.get", challenge)?;
+    let auth_data = parse_authenticator_data(authenticator_data)?;
+
+    let mut signed = authenticator_data.to_vec();
+    signed.extend_from_slice(&Sha256::digest(client_data_json));
+
+    let key = VerifyingKey::from_sec1_bytes(public_key).context("Invalid stored public key")?;
+    let signature = Signature::from_der(signature).context("Invalid signature encoding")?;
+    key.verify(&signed, &signature).context("Bad signature")?;
+
+    Ok(Assertion {
+        sign_count: auth_data.sign_count,
+        user_verified: auth_data.flags & FLAG_USER_VERIFIED != 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::{SigningKey, signature::Signer};
+    use serde_json::json;
+
+    const CHALLENGE: &str = "c2lnbiBtZSBpbg";
+
+    // a software authenticator: one p-256 key and its credential id
+    struct Authenticator {
+        key: SigningKey,
+        credential_id: Vec<u8>,
+    }
+
+    impl Authenticator {
+        fn new(seed: u8) -> Self {
+            Self {
+                key: SigningKey::from_bytes(&[seed; 32].into()).unwrap(),
+                credential_id: vec![seed; 16],
+            }
+        }
+
+        fn public_key(&self) -> Vec<u8> {
+            self.key.verifying_key().to_encoded_point(false).as_bytes().to_vec()
+        }
+
+        fn cose_key(&self) -> Vec<u8> {
+            let point = self.key.verifying_key().to_encoded_point(false);
+            let int = |i: i64| Value::Integer(i.into());
+            let key = Value::Map(vec![
+                (int(COSE_KTY), int(COSE_KTY_EC2)),
+                (int(COSE_ALG), int(ES256)),
+                (int(COSE_EC2_CRV), int(COSE_CRV_P256)),
+                (int(COSE_EC2_X), Value::Bytes(point.x().unwrap().to_vec())),
+                (int(COSE_EC2_Y), Value::Bytes(point.y().unwrap().to_vec())),
+            ]);
+            let mut out = Vec::new();
+            ciborium::into_writer(&key, &mut out).unwrap();
+            out
+        }
+
+        // rp id hash, flags, counter, then the attested credential when registering
+        fn authenticator_data(&self, rp_id: &str, flags: u8, sign_count: u32, attested: bool) -> Vec<u8> {
+            let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+            data.push(flags);
+            data.extend_from_slice(&sign_count.to_be_bytes());
+            if attested {
+                data.extend_from_slice(&[0; 16]);
+                data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+                data.extend_from_slice(&self.credential_id);
+                data.extend_from_slice(&self.cose_key());
+            }
+            data
+        }
+
+        fn attestation_object(&self, rp_id: &str) -> Vec<u8> {
+            let auth_data = self.authenticator_data(rp_id, FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL, 0, true);
+            let attestation = Value::Map(vec![
+                (Value::Text("fmt".to_string()), Value::Text("none".to_string())),
+                (Value::Text("attStmt".to_string()), Value::Map(Vec::new())),
+                (Value::Text("authData".to_string()), Value::Bytes(auth_data)),
+            ]);
+            let mut out = Vec::new();
+            ciborium::into_writer(&attestation, &mut out).unwrap();
+            out
+        }
+
+        fn sign(&self, authenticator_data: &[u8], client_data_json: &[u8]) -> Vec<u8> {
+            let mut signed = authenticator_data.to_vec();
+            signed.extend_from_slice(&Sha256::digest(client_data_json));
+            let signature: Signature = self.key.sign(&signed);
+            signature.to_der().as_bytes().to_vec()
+        }
+    }
+
+    fn client_data(kind: &str, challenge: &str, origin: &str) -> Vec<u8> {
+        serde_json::to_vec(&json!({ "type": kind, "challenge": challenge, "origin": origin, "crossOrigin": false }))
+            .unwrap()
+    }
+
+    fn assert_rejected<T>(result: Result<T>, reason: &str) {
+        match result {
+            Ok(_) => panic!("accepted, expected {reason}"),
+            Err(e) => assert!(format!("{e:#}").contains(reason), "{e:#}, expected {reason}"),
+        }
+    }
+
+    #[test]
+    fn registers_a_software_authenticator() {
+        let authenticator = Authenticator::new(7);
+        let credential = verify_registration(
+            &client_data("webauthn.create", CHALLENGE, origin()),
+            &authenticator.attestation_object(rp_id()),
+            CHALLENGE,
+        )
+        .unwrap();
+
+        assert_eq!(credential.id, URL_SAFE_NO_PAD.encode(&authenticator.credential_id));
+        assert_eq!(credential.public_key, authenticator.public_key());
+        assert_eq!(credential.sign_count, 0);
+    }
+
+    #[test]
+    fn rejects_a_registration_for_another_origin_rp_or_challenge() {
+        let authenticator = Authenticator::new(7);
+        let attestation = authenticator.attestation_object(rp_id());
+
+        assert_rejected(
+            verify_registration(
+                &client_data("webauthn.create", CHALLENGE, "https://evil.example"),
+                &attestation,
+                CHALLENGE,
+            ),
+            "Origin mismatch",
+        );
+        assert_rejected(
+            verify_registration(
+                &client_data("webauthn.create", CHALLENGE, origin()),
+                &authenticator.attestation_object("evil.example"),
+                CHALLENGE,
+            ),
+            "RP ID hash mismatch",
+        );
+        assert_rejected(
+            verify_registration(
+                &client_data("webauthn.create", "b3RoZXI", origin()),
+                &attestation,
+                CHALLENGE,
+            ),
+            "Challenge mismatch",
+        );
+        assert_rejected(
+            verify_registration(
+                &client_data("webauthn.get", CHALLENGE, origin()),
+                &attestation,
+                CHALLENGE,
+            ),
+            "Wrong ceremony type",
+        );
+    }
+
+    #[test]
+    fn verifies_an_assertion() {
+        let authenticator = Authenticator::new(7);
+        let client_data_json = client_data("webauthn.get", CHALLENGE, origin());
+        let authenticator_data =
+            authenticator.authenticator_data(rp_id(), FLAG_USER_PRESENT | FLAG_USER_VERIFIED, 5, false);
+        let signature = authenticator.sign(&authenticator_data, &client_data_json);
+
+        let assertion = verify_assertion(
+            &authenticator.public_key(),
+            &client_data_json,
+            &authenticator_data,
+            &signature,
+            CHALLENGE,
+        )
+        .unwrap();
+        assert_eq!(assertion.sign_count, 5);
+        assert!(assertion.user_verified);
+
+        // a touch without a pin still signs in as a second factor, just not on its own
+        let authenticator_data = authenticator.authenticator_data(rp_id(), FLAG_USER_PRESENT, 6, false);
+        let signature = authenticator.sign(&authenticator_data, &client_data_json);
+        let assertion = verify_assertion(
+            &authenticator.public_key(),
+            &client_data_json,
+            &authenticator_data,
+            &signature,
+            CHALLENGE,
+        )
+        .unwrap();
+        assert!(!assertion.user_verified);
+    }
+
+    #[test]
+    fn rejects_an_assertion_for_another_origin_rp_challenge_or_key() {
+        let authenticator = Authenticator::new(7);
+        let public_key = authenticator.public_key();
+        let flags = FLAG_USER_PRESENT | FLAG_USER_VERIFIED;
+        let assert = |client_data_json: Vec<u8>, authenticator_data: Vec<u8>, signer: &Authenticator| {
+            let signature = signer.sign(&authenticator_data, &client_data_json);
+            verify_assertion(
+                &public_key,
+                &client_data_json,
+                &authenticator_data,
+                &signature,
+                CHALLENGE,
+            )
+        };
+
+        assert_rejected(
+            assert(
+                client_data("webauthn.get", CHALLENGE, "https://evil.example"),
+                authenticator.authenticator_data(rp_id(), flags, 1, false),
+                &authenticator,
+            ),
+            "Origin mismatch",
+        );
+        assert_rejected(
+            assert(
+                client_data("webauthn.get", CHALLENGE, origin()),
+                authenticator.authenticator_data("evil.example", flags, 1, false),
+                &authenticator,
+            ),
+            "RP ID hash mismatch",
+        );
+        assert_rejected(
+            assert(
+                client_data("webauthn.get", "b3RoZXI", origin()),
+                authenticator.authenticator_data(rp_id(), flags, 1, false),
+                &authenticator,
+            ),
+            "Challenge mismatch",
+        );
+        assert_rejected(
+            assert(
+                client_data("webauthn.get", CHALLENGE, origin()),
+                authenticator.authenticator_data(rp_id(), 0, 1, false),
+                &authenticator,
+            ),
+            "User not present",
+        );
+        assert_rejected(
+            assert(
+                client_data("webauthn.get", CHALLENGE, origin()),
+                authenticator.authenticator_data(rp_id(), flags, 1, false),
+                &Authenticator::new(8),
+            ),
+            "Bad signature",
+        );
+    }
+}